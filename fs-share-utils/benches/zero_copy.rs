@@ -0,0 +1,148 @@
+//! Loopback throughput of the buffered copy loop vs `sendfile`/`splice`.
+//!
+//! Both ends run in this process, so the CPU time (user + system) spent per
+//! MiB is reported as well; on machines with few cores that is where the
+//! saved copies show up first.
+//!
+//! ```text
+//! cargo bench -p fs-share-utils --bench zero_copy
+//! ```
+//!
+//! The file size in MiB can be set with `FS_SHARE_BENCH_MB` (default 512).
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::run();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("zero-copy transfers are only available on Linux");
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        fs::File,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        path::Path,
+        time::{Duration, Instant},
+    };
+
+    use fs_share_utils::zerocopy;
+
+    const BUFFER_SIZE: usize = 256 * 1024;
+    const RUNS: usize = 3;
+
+    pub fn run() {
+        let mb: u64 = std::env::var("FS_SHARE_BENCH_MB")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(512);
+        let len = mb * 1024 * 1024;
+
+        let dir = std::env::temp_dir().join(format!("fs-share-bench-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("src.bin");
+        let dst = dir.join("dst.bin");
+        write_source(&src, len);
+
+        println!("{} MiB over loopback, best of {}:", mb, RUNS);
+        for (name, zero_copy) in [("buffered", false), ("zero-copy", true)] {
+            let (elapsed, cpu) = best(|| transfer(&src, &dst, len, zero_copy));
+            println!(
+                "  {:<10} {:>8.1} MiB/s  {:>6.2} ms CPU/MiB",
+                name,
+                mib_per_sec(len, elapsed),
+                cpu.as_secs_f64() * 1000.0 / mb as f64
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn write_source(path: &Path, len: u64) {
+        let mut file = File::create(path).unwrap();
+        let block: Vec<u8> = (0..BUFFER_SIZE).map(|i| (i % 251) as u8).collect();
+        let mut written = 0;
+        while written < len {
+            let n = std::cmp::min(len - written, BUFFER_SIZE as u64) as usize;
+            file.write_all(&block[..n]).unwrap();
+            written += n as u64;
+        }
+        file.sync_all().unwrap();
+    }
+
+    /// Fastest of [`RUNS`] runs, with the CPU time it took.
+    fn best(mut f: impl FnMut() -> Duration) -> (Duration, Duration) {
+        (0..RUNS)
+            .map(|_| {
+                let cpu = cpu_time();
+                let elapsed = f();
+                (elapsed, cpu_time() - cpu)
+            })
+            .min()
+            .unwrap()
+    }
+
+    /// User + system time of this process.
+    fn cpu_time() -> Duration {
+        // SAFETY: `usage` is a valid, zeroed rusage.
+        let usage = unsafe {
+            let mut usage = std::mem::zeroed::<libc::rusage>();
+            libc::getrusage(libc::RUSAGE_SELF, &mut usage);
+            usage
+        };
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+
+    fn mib_per_sec(len: u64, elapsed: Duration) -> f64 {
+        len as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    }
+
+    /// Send `src` to `dst` over a loopback connection and time it.
+    fn transfer(src: &Path, dst: &Path, len: u64, zero_copy: bool) -> Duration {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let src = src.to_path_buf();
+
+        let start = Instant::now();
+        let sender = std::thread::spawn(move || {
+            let mut socket = TcpStream::connect(addr).unwrap();
+            let mut file = File::open(src).unwrap();
+            if zero_copy {
+                assert!(zerocopy::send_file(&file, 0, len, &socket, &mut |_| {}).unwrap());
+            } else {
+                let mut buffer = vec![0u8; BUFFER_SIZE];
+                loop {
+                    let n = file.read(&mut buffer).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    socket.write_all(&buffer[..n]).unwrap();
+                }
+            }
+        });
+
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut file = File::create(dst).unwrap();
+        if zero_copy {
+            assert!(zerocopy::receive_file(&socket, &file, 0, len, &mut |_| {}).unwrap());
+        } else {
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            let mut received = 0;
+            while received < len {
+                let n = socket.read(&mut buffer).unwrap();
+                assert!(n > 0, "unexpected EOF");
+                file.write_all(&buffer[..n]).unwrap();
+                received += n as u64;
+            }
+        }
+        sender.join().unwrap();
+        start.elapsed()
+    }
+}
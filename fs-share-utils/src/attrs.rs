@@ -0,0 +1,229 @@
+//! # File Attributes
+//!
+//! Permissions, timestamps and ownership of a file, carried in its header
+//! when both peers negotiated
+//! [`Features::METADATA`](crate::protocol::Features::METADATA).
+//!
+//! ## Format
+//!
+//! The attributes follow the `mtime` field of a `:fff:`/`:ffr:` header:
+//! ```text
+//! mtime_nsec(u32) | atime(u64) | atime_nsec(u32) | mode(u32) | uid(u32) | gid(u32)
+//! ```
+//!
+//! Times are seconds since the Unix epoch plus nanoseconds (both 0 if
+//! unknown). `mode` holds Unix permission bits and is 0 when the sender
+//! has none (e.g. on Windows); `uid` and `gid` are [`UNKNOWN_ID`] there.
+//!
+//! ## Applying
+//!
+//! The receiver applies the attributes once the file is complete and
+//! verified. Only the `rwx` bits of `mode` are used, set-user-ID,
+//! set-group-ID and sticky bits are dropped, and the receiver's umask is
+//! applied to them as for any file it creates. Ownership is only changed
+//! when running as root. On Windows the times and the read-only flag are
+//! applied.
+
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Size of the attributes on the wire
+pub const LEN: usize = 28;
+
+/// `uid`/`gid` of a sender without Unix ownership
+pub const UNKNOWN_ID: u32 = u32::MAX;
+
+/// Permission bits that are applied
+const PERMISSION_BITS: u32 = 0o777;
+
+/// Attributes sent along with a file (the whole seconds of `mtime` are in
+/// the header itself).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub mtime_nsec: u32,
+    pub atime: u64,
+    pub atime_nsec: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Attributes {
+    /// Attributes of a local file.
+    pub fn of(metadata: &Metadata) -> Self {
+        let (_, mtime_nsec) = split_time(metadata.modified().ok());
+        let (atime, atime_nsec) = split_time(metadata.accessed().ok());
+        #[cfg(unix)]
+        let (mode, uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.mode() & 0o7777, metadata.uid(), metadata.gid())
+        };
+        #[cfg(not(unix))]
+        let (mode, uid, gid) = (0, UNKNOWN_ID, UNKNOWN_ID);
+        Self {
+            mtime_nsec,
+            atime,
+            atime_nsec,
+            mode,
+            uid,
+            gid,
+        }
+    }
+
+    pub fn write<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        let mut buf = [0u8; LEN];
+        buf[..4].copy_from_slice(&self.mtime_nsec.to_be_bytes());
+        buf[4..12].copy_from_slice(&self.atime.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.atime_nsec.to_be_bytes());
+        buf[16..20].copy_from_slice(&self.mode.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.uid.to_be_bytes());
+        buf[24..].copy_from_slice(&self.gid.to_be_bytes());
+        stream.write_all(&buf)
+    }
+
+    pub fn read<R: Read>(stream: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; LEN];
+        stream.read_exact(&mut buf)?;
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            mtime_nsec: u32_at(0),
+            atime: u64::from_be_bytes(buf[4..12].try_into().unwrap()),
+            atime_nsec: u32_at(12),
+            mode: u32_at(16),
+            uid: u32_at(20),
+            gid: u32_at(24),
+        })
+    }
+
+    /// Apply the attributes to the file at `path`; `mtime` are the whole
+    /// seconds from the header.
+    pub fn apply(&self, path: &Path, mtime: u64) -> io::Result<()> {
+        // Times first: a read-only mode would keep the file from being opened.
+        let mut times = std::fs::FileTimes::new();
+        if let Some(modified) = join_time(mtime, self.mtime_nsec) {
+            times = times.set_modified(modified);
+        }
+        if let Some(accessed) = join_time(self.atime, self.atime_nsec) {
+            times = times.set_accessed(accessed);
+        }
+        File::options().write(true).open(path)?.set_times(times)?;
+
+        // Ownership before the mode, chown clears the set-ID bits anyway.
+        #[cfg(all(unix, not(target_os = "android")))]
+        // SAFETY: geteuid has no preconditions.
+        if self.uid != UNKNOWN_ID && unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chown(path, Some(self.uid), Some(self.gid))?;
+        }
+
+        if self.mode == 0 {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        let permissions = {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::Permissions::from_mode(self.mode & PERMISSION_BITS & !umask())
+        };
+        #[cfg(not(unix))]
+        let permissions = {
+            let mut permissions = std::fs::metadata(path)?.permissions();
+            permissions.set_readonly(self.mode & PERMISSION_BITS & 0o222 == 0);
+            permissions
+        };
+        std::fs::set_permissions(path, permissions)
+    }
+}
+
+/// The process umask, read once.
+///
+/// It can only be read by setting it, so it is briefly the most restrictive
+/// one: a file created meanwhile by another thread isn't made more open.
+#[cfg(all(unix, not(target_os = "android")))]
+fn umask() -> u32 {
+    static UMASK: std::sync::OnceLock<u32> = std::sync::OnceLock::new();
+    *UMASK.get_or_init(|| {
+        // SAFETY: umask has no preconditions and can't fail.
+        let mask = unsafe { libc::umask(0o077) };
+        unsafe { libc::umask(mask) };
+        mask as u32
+    })
+}
+
+/// Without libc, the usual default.
+#[cfg(target_os = "android")]
+fn umask() -> u32 {
+    0o022
+}
+
+/// Seconds and nanoseconds since the Unix epoch, `(0, 0)` if unknown.
+fn split_time(time: Option<SystemTime>) -> (u64, u32) {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or((0, 0), |d| (d.as_secs(), d.subsec_nanos()))
+}
+
+fn join_time(secs: u64, nanos: u32) -> Option<SystemTime> {
+    if secs == 0 && nanos == 0 {
+        return None;
+    }
+    UNIX_EPOCH.checked_add(Duration::new(secs, nanos.min(999_999_999)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_round_trip() {
+        let attrs = Attributes {
+            mtime_nsec: 123_456_789,
+            atime: 1_700_000_000,
+            atime_nsec: 5,
+            mode: 0o4755,
+            uid: 1000,
+            gid: UNKNOWN_ID,
+        };
+        let mut wire = Vec::new();
+        attrs.write(&mut wire).unwrap();
+        assert_eq!(wire.len(), LEN);
+        assert_eq!(Attributes::read(&mut wire.as_slice()).unwrap(), attrs);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn set_id_bits_are_not_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("fs-share-attrs-{}", std::process::id()));
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let attrs = Attributes {
+            mtime_nsec: 500,
+            atime: 1_600_000_000,
+            atime_nsec: 0,
+            mode: 0o4751,
+            uid: UNKNOWN_ID,
+            gid: UNKNOWN_ID,
+        };
+        attrs.apply(&path, 1_500_000_000).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o751 & !umask());
+        assert_eq!(
+            metadata.modified().unwrap(),
+            UNIX_EPOCH + Duration::new(1_500_000_000, 500)
+        );
+
+        // Bits the umask removes are not given back by the sender.
+        let attrs = Attributes {
+            mode: 0o777,
+            ..attrs
+        };
+        attrs.apply(&path, 1_500_000_000).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o777 & !umask());
+        assert_eq!(mode & umask(), 0);
+        let _ = std::fs::remove_file(&path);
+    }
+}
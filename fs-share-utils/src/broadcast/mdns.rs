@@ -0,0 +1,1012 @@
+//! # mDNS / DNS-SD Discovery
+//!
+//! Discovery over multicast DNS, for networks that drop the limited
+//! broadcast address (managed Wi-Fi, VPNs).
+//!
+//! [`MdnsAdvertiser`] answers queries for [`SERVICE_TYPE`] with a `PTR`,
+//! `SRV` and `TXT` record (plus an `A` record for an IPv4 listener).
+//! [`MdnsBrowser`] sends one-shot queries from an ephemeral port, so it
+//! doesn't compete with a system responder for port 5353, and turns every
+//! `TXT` record it gets back into a payload for
+//! [`PayloadReader`]:
+//!
+//! ```text
+//! TXT "name=alice" "os=linux" "arch=x86_64" "addr=192.168.1.5:41235"
+//!  => :<len>alice:<len>linux:<len>x86_64:<len>192.168.1.5:41235
+//! ```
+//!
+//! so the same type decodes announcements from either backend.
+//!
+//! Only the parts of RFC 6762/6763 needed for that are implemented: no
+//! name probing, known-answer suppression or IPv6 records.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    num::NonZero,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use super::{
+    receiver::{Discovery, PayloadReader},
+    sender::encode_field,
+};
+
+/// DNS-SD service type advertised by receivers
+pub const SERVICE_TYPE: &str = "_fs-share._tcp.local";
+
+/// mDNS IPv4 multicast group and port
+pub const MDNS_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353));
+
+/// TTL of advertised records, in seconds
+const TTL: u32 = 120;
+
+/// How often the advertiser checks for the stop signal.
+///
+/// Short, because the receiver stops it while the sender waits for the
+/// connection to be upgraded.
+const STOP_POLL: Duration = Duration::from_millis(20);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: "unicast response" in questions, "cache flush"
+/// in records
+const CLASS_FLAG: u16 = 0x8000;
+
+/// QR (response) and AA (authoritative) bits
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// Longest DNS label
+const MAX_LABEL: usize = 63;
+
+/// Domain name as a list of labels.
+///
+/// Kept apart rather than joined with dots, since instance names may
+/// contain dots themselves.
+type Name = Vec<String>;
+
+fn name(dotted: &str) -> Name {
+    dotted.split('.').map(str::to_owned).collect()
+}
+
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// Cut `label` to [`MAX_LABEL`] bytes on a character boundary.
+fn truncate_label(label: &str) -> String {
+    let mut end = label.len().min(MAX_LABEL);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].to_owned()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Question {
+    name: Name,
+    qtype: u16,
+    /// Asks for a unicast response (QU bit)
+    unicast: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(Name),
+    Txt(Vec<Vec<u8>>),
+    Srv { port: u16, target: Name },
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    name: Name,
+    ttl: u32,
+    /// Cache flush bit, set on records only this host owns
+    unique: bool,
+    data: RecordData,
+}
+
+impl Record {
+    fn rtype(&self) -> u16 {
+        match self.data {
+            RecordData::A(_) => TYPE_A,
+            RecordData::Ptr(_) => TYPE_PTR,
+            RecordData::Txt(_) => TYPE_TXT,
+            RecordData::Srv { .. } => TYPE_SRV,
+            RecordData::Other => 0,
+        }
+    }
+}
+
+/// A DNS message, reduced to what discovery needs.
+///
+/// Answer, authority and additional records are all kept in `records`.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Message {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    records: Vec<Record>,
+}
+
+impl Message {
+    /// Encode without name compression.
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        let flags = if self.response { FLAGS_RESPONSE } else { 0 };
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.records.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[0, 0, 0, 0]); // NSCOUNT, ARCOUNT
+
+        for q in &self.questions {
+            write_name(&mut buf, &q.name);
+            buf.extend_from_slice(&q.qtype.to_be_bytes());
+            let class = if q.unicast {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            buf.extend_from_slice(&class.to_be_bytes());
+        }
+        for r in &self.records {
+            write_name(&mut buf, &r.name);
+            buf.extend_from_slice(&r.rtype().to_be_bytes());
+            let class = if r.unique {
+                CLASS_IN | CLASS_FLAG
+            } else {
+                CLASS_IN
+            };
+            buf.extend_from_slice(&class.to_be_bytes());
+            buf.extend_from_slice(&r.ttl.to_be_bytes());
+
+            let len_pos = buf.len();
+            buf.extend_from_slice(&[0, 0]);
+            match &r.data {
+                RecordData::A(ip) => buf.extend_from_slice(&ip.octets()),
+                RecordData::Ptr(target) => write_name(&mut buf, target),
+                RecordData::Txt(strings) => {
+                    for s in strings {
+                        buf.push(s.len() as u8);
+                        buf.extend_from_slice(s);
+                    }
+                }
+                RecordData::Srv { port, target } => {
+                    buf.extend_from_slice(&[0, 0, 0, 0]); // priority, weight
+                    buf.extend_from_slice(&port.to_be_bytes());
+                    write_name(&mut buf, target);
+                }
+                RecordData::Other => {}
+            }
+            let len = (buf.len() - len_pos - 2) as u16;
+            buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+        }
+        buf
+    }
+
+    /// Decode a message, `None` if it is malformed.
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader { buf, pos: 0 };
+        let id = r.u16()?;
+        let flags = r.u16()?;
+        let qdcount = r.u16()?;
+        let counts = [r.u16()?, r.u16()?, r.u16()?];
+
+        let mut questions = Vec::new();
+        for _ in 0..qdcount {
+            let name = r.name()?;
+            let qtype = r.u16()?;
+            let class = r.u16()?;
+            questions.push(Question {
+                name,
+                qtype,
+                unicast: class & CLASS_FLAG != 0,
+            });
+        }
+
+        let mut records = Vec::new();
+        for _ in 0..counts.iter().map(|&n| n as usize).sum::<usize>() {
+            let name = r.name()?;
+            let rtype = r.u16()?;
+            let class = r.u16()?;
+            let ttl = r.u32()?;
+            let len = r.u16()? as usize;
+            let end = r.pos.checked_add(len).filter(|&end| end <= buf.len())?;
+            let data = match rtype {
+                TYPE_A if len == 4 => {
+                    let b = r.take(4)?;
+                    RecordData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+                }
+                TYPE_PTR => RecordData::Ptr(r.name()?),
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    while r.pos < end {
+                        let n = r.take(1)?[0] as usize;
+                        strings.push(r.take(n)?.to_vec());
+                    }
+                    RecordData::Txt(strings)
+                }
+                TYPE_SRV => {
+                    r.take(4)?;
+                    let port = r.u16()?;
+                    RecordData::Srv {
+                        port,
+                        target: r.name()?,
+                    }
+                }
+                _ => RecordData::Other,
+            };
+            if r.pos > end {
+                return None;
+            }
+            r.pos = end;
+            records.push(Record {
+                name,
+                ttl,
+                unique: class & CLASS_FLAG != 0,
+                data,
+            });
+        }
+
+        Some(Self {
+            id,
+            response: flags & 0x8000 != 0,
+            questions,
+            records,
+        })
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &[String]) {
+    for label in name {
+        let label = &label.as_bytes()[..label.len().min(MAX_LABEL)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(n)?)?;
+        self.pos += n;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Read a possibly compressed name.
+    ///
+    /// Every compression pointer must point before the labels read since
+    /// the previous one (or since the start), which rules out loops.
+    fn name(&mut self) -> Option<Name> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        // Start of the labels read since the last pointer
+        let mut start = pos;
+        // Where reading continues after the first pointer
+        let mut resume = None;
+        loop {
+            let len = *self.buf.get(pos)? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                0xc0.. => {
+                    let low = *self.buf.get(pos + 1)? as usize;
+                    let target = ((len & 0x3f) << 8) | low;
+                    if target >= start {
+                        return None;
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = target;
+                    start = target;
+                }
+                1..=MAX_LABEL => {
+                    let label = self.buf.get(pos + 1..pos + 1 + len)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+        self.pos = resume.unwrap_or(pos);
+        Some(labels)
+    }
+}
+
+/// Set the interface multicast packets leave from.
+#[cfg(all(unix, not(target_os = "android")))]
+fn set_multicast_if(socket: &UdpSocket, interface: Ipv4Addr) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let addr = libc::in_addr {
+        s_addr: u32::from_ne_bytes(interface.octets()),
+    };
+    // SAFETY: `addr` outlives the call and its size is passed along.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_MULTICAST_IF,
+            &addr as *const libc::in_addr as *const libc::c_void,
+            std::mem::size_of::<libc::in_addr>() as libc::socklen_t,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Elsewhere the system picks the interface.
+#[cfg(not(all(unix, not(target_os = "android"))))]
+fn set_multicast_if(_socket: &UdpSocket, _interface: Ipv4Addr) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Join the mDNS group on `interface` and send multicast through it.
+fn join_group(socket: &UdpSocket, group: SocketAddr, interface: Ipv4Addr) -> anyhow::Result<()> {
+    let IpAddr::V4(group) = group.ip() else {
+        anyhow::bail!("mDNS group {} is not an IPv4 address", group);
+    };
+    socket
+        .join_multicast_v4(&group, &interface)
+        .with_context(|| format!("Failed to join multicast group {} on {}", group, interface))?;
+    socket
+        .set_multicast_loop_v4(true)
+        .context("Failed to enable multicast loopback")?;
+    if !interface.is_unspecified() {
+        set_multicast_if(socket, interface)
+            .with_context(|| format!("Failed to send multicast through {}", interface))?;
+    }
+    Ok(())
+}
+
+/// mDNS responder advertising one service instance.
+pub struct MdnsAdvertiser {
+    /// `<instance>._fs-share._tcp.local`
+    instance: Name,
+
+    /// `_fs-share._tcp.local`
+    service: Name,
+
+    /// `<host>.local`, target of the SRV record
+    host: Name,
+
+    /// Listener address
+    listener_addr: SocketAddr,
+
+    /// TXT record strings (`key=value`), in order
+    txt: Vec<Vec<u8>>,
+
+    bind_addr: SocketAddr,
+    group_addr: SocketAddr,
+    interface: Ipv4Addr,
+
+    /// Already bound socket, used instead of `bind_addr`
+    socket: Option<UdpSocket>,
+}
+
+impl MdnsAdvertiser {
+    /// Create a new builder for configuring [`MdnsAdvertiser`]
+    pub fn builder() -> MdnsAdvertiserBuilder {
+        MdnsAdvertiserBuilder::default()
+    }
+
+    /// Start answering queries in a background thread.
+    ///
+    /// Returns:
+    /// - Stop function
+    /// - Thread handle
+    ///
+    /// ## Behavior
+    ///
+    /// - Announces the service once when started
+    /// - Answers queries for the service type, instance or host name
+    /// - Sends a goodbye (TTL 0) when stopped
+    /// - Logs errors to stderr
+    pub fn start(self) -> (impl FnOnce(), thread::JoinHandle<()>) {
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            if let Err(e) = self.run(stop_rx) {
+                eprintln!("mDNS advertiser error: {}", e);
+            }
+        });
+
+        let stop = move || {
+            let _ = stop_tx.send(());
+        };
+
+        (stop, handle)
+    }
+
+    fn run(mut self, stop_rx: Receiver<()>) -> anyhow::Result<()> {
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => UdpSocket::bind(self.bind_addr)
+                .with_context(|| format!("Failed to bind UDP socket on {}", self.bind_addr))?,
+        };
+        join_group(&socket, self.group_addr, self.interface)?;
+        socket
+            .set_read_timeout(Some(STOP_POLL))
+            .context("Failed to set read timeout on mDNS socket")?;
+
+        let announcement = self.response(0, Vec::new(), TTL).encode();
+        socket
+            .send_to(&announcement, self.group_addr)
+            .with_context(|| format!("Failed to announce service to {}", self.group_addr))?;
+
+        let mut buffer = [0u8; 9000];
+        // Until stopped (or the stop function is dropped)
+        while let Err(mpsc::TryRecvError::Empty) = stop_rx.try_recv() {
+            let (size, src) = match socket.recv_from(&mut buffer) {
+                Ok(v) => v,
+                Err(ref e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => return Err(e).context("Failed to receive mDNS query"),
+            };
+            let Some(query) = Message::decode(&buffer[..size]) else {
+                continue;
+            };
+            if query.response || !query.questions.iter().any(|q| self.answers(q)) {
+                continue;
+            }
+
+            // One-shot queries come from another port and expect a plain
+            // DNS reply: same id, questions repeated, sent back directly.
+            let (reply, target) = if src.port() != self.group_addr.port() {
+                (self.response(query.id, query.questions, TTL), src)
+            } else if query.questions.iter().any(|q| q.unicast) {
+                (self.response(0, Vec::new(), TTL), src)
+            } else {
+                (self.response(0, Vec::new(), TTL), self.group_addr)
+            };
+            let _ = socket.send_to(&reply.encode(), target);
+        }
+
+        let goodbye = self.response(0, Vec::new(), 0).encode();
+        let _ = socket.send_to(&goodbye, self.group_addr);
+        Ok(())
+    }
+
+    fn answers(&self, question: &Question) -> bool {
+        match question.qtype {
+            TYPE_PTR => same_name(&question.name, &self.service),
+            TYPE_SRV | TYPE_TXT => same_name(&question.name, &self.instance),
+            TYPE_A => same_name(&question.name, &self.host),
+            TYPE_ANY => [&self.service, &self.instance, &self.host]
+                .iter()
+                .any(|name| same_name(&question.name, name)),
+            _ => false,
+        }
+    }
+
+    /// PTR, SRV and TXT records (and A for an IPv4 listener) with `ttl`.
+    fn response(&self, id: u16, questions: Vec<Question>, ttl: u32) -> Message {
+        let mut records = vec![
+            Record {
+                name: self.service.clone(),
+                ttl,
+                unique: false,
+                data: RecordData::Ptr(self.instance.clone()),
+            },
+            Record {
+                name: self.instance.clone(),
+                ttl,
+                unique: true,
+                data: RecordData::Srv {
+                    port: self.listener_addr.port(),
+                    target: self.host.clone(),
+                },
+            },
+            Record {
+                name: self.instance.clone(),
+                ttl,
+                unique: true,
+                data: RecordData::Txt(self.txt.clone()),
+            },
+        ];
+        if let IpAddr::V4(ip) = self.listener_addr.ip()
+            && !ip.is_unspecified()
+        {
+            records.push(Record {
+                name: self.host.clone(),
+                ttl,
+                unique: true,
+                data: RecordData::A(ip),
+            });
+        }
+        Message {
+            id,
+            response: true,
+            questions,
+            records,
+        }
+    }
+}
+
+/// Builder for [`MdnsAdvertiser`]
+///
+/// Provides configuration for:
+/// - Instance and host name
+/// - Listener address
+/// - TXT record fields
+/// - Bind address or socket, multicast group and interface
+pub struct MdnsAdvertiserBuilder {
+    instance: String,
+    host: String,
+    listener_addr: SocketAddr,
+    txt: Vec<Vec<u8>>,
+    bind_addr: SocketAddr,
+    group_addr: SocketAddr,
+    interface: Ipv4Addr,
+    socket: Option<UdpSocket>,
+}
+
+impl Default for MdnsAdvertiserBuilder {
+    fn default() -> Self {
+        Self {
+            instance: "fs-share".into(),
+            host: "fs-share".into(),
+            listener_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            txt: Vec::new(),
+            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), MDNS_ADDR.port()),
+            group_addr: MDNS_ADDR,
+            interface: Ipv4Addr::UNSPECIFIED,
+            socket: None,
+        }
+    }
+}
+
+impl MdnsAdvertiserBuilder {
+    /// Set the instance name shown by DNS-SD browsers
+    pub fn instance<T: AsRef<str>>(mut self, name: T) -> Self {
+        self.instance = truncate_label(name.as_ref());
+        self
+    }
+
+    /// Set the host label (without `.local`) the SRV record points to
+    pub fn host<T: AsRef<str>>(mut self, name: T) -> Self {
+        self.host = truncate_label(name.as_ref());
+        self
+    }
+
+    /// Set the advertised listener address
+    pub fn listener_addr(mut self, addr: SocketAddr) -> Self {
+        self.listener_addr = addr;
+        self
+    }
+
+    /// Add a TXT field.
+    ///
+    /// Encoded as:
+    /// ```text
+    /// <len:u8>key=value
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the field exceeds 255 bytes
+    pub fn add_txt<K: AsRef<str>, V: AsRef<[u8]>>(mut self, key: K, value: V) -> Self {
+        let mut field = format!("{}=", key.as_ref()).into_bytes();
+        field.extend_from_slice(value.as_ref());
+
+        assert!(field.len() <= u8::MAX as usize, "TXT field too large");
+
+        self.txt.push(field);
+        self
+    }
+
+    /// Set UDP bind address
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Use an already bound socket instead of binding `bind_addr`.
+    ///
+    /// Port 5353 is usually shared with the system responder, which
+    /// requires `SO_REUSEADDR` to be set before binding.
+    pub fn socket(mut self, socket: UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Set the multicast group (default `224.0.0.251:5353`)
+    pub fn group_addr(mut self, addr: SocketAddr) -> Self {
+        self.group_addr = addr;
+        self
+    }
+
+    /// Set the local interface address to join the group on
+    pub fn interface(mut self, addr: Ipv4Addr) -> Self {
+        self.interface = addr;
+        self
+    }
+
+    /// Build [`MdnsAdvertiser`]
+    pub fn build(self) -> MdnsAdvertiser {
+        let service = name(SERVICE_TYPE);
+        let mut instance = vec![self.instance];
+        instance.extend(service.iter().cloned());
+        MdnsAdvertiser {
+            instance,
+            service,
+            host: vec![self.host, "local".into()],
+            listener_addr: self.listener_addr,
+            txt: self.txt,
+            bind_addr: self.bind_addr,
+            group_addr: self.group_addr,
+            interface: self.interface,
+            socket: self.socket,
+        }
+    }
+}
+
+/// mDNS browser for [`SERVICE_TYPE`].
+///
+/// Sends queries periodically and emits the TXT fields of every instance
+/// that answers.
+pub struct MdnsBrowser {
+    socket: UdpSocket,
+    group_addr: SocketAddr,
+    query_interval: Duration,
+    buffer: Box<[u8]>,
+    deduplicate: bool,
+}
+
+impl MdnsBrowser {
+    /// Create a new builder for configuring [`MdnsBrowser`]
+    pub fn builder() -> MdnsBrowserBuilder {
+        MdnsBrowserBuilder::default()
+    }
+
+    /// Start browsing in a background thread.
+    ///
+    /// Returns the same handle as
+    /// [`BroadcastReceiver::start`](super::receiver::BroadcastReceiver::start),
+    /// and `U` is decoded the same way, from the values of the TXT fields
+    /// in their order.
+    ///
+    /// ## Behavior
+    ///
+    /// - Deduplicates data per service instance
+    /// - Sends only new or changed data, unless deduplication is turned
+    ///   off (see [`MdnsBrowserBuilder::deduplicate`])
+    /// - Ignores goodbyes and invalid records silently
+    pub fn start<U>(self) -> Discovery<U>
+    where
+        U: for<'a> TryFrom<(SocketAddr, PayloadReader<'a>)>,
+        U: Clone + PartialEq + Send + 'static,
+    {
+        let (data_tx, data_rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let mut this = self;
+            let query = Message {
+                questions: vec![Question {
+                    name: name(SERVICE_TYPE),
+                    qtype: TYPE_PTR,
+                    unicast: true,
+                }],
+                ..Default::default()
+            }
+            .encode();
+
+            // Track last seen data per instance
+            let mut seen: HashMap<String, U> = HashMap::new();
+            let mut last_query: Option<Instant> = None;
+
+            loop {
+                // Check stop signal
+                if stop_rx.try_recv().is_ok() {
+                    break;
+                }
+
+                if last_query.is_none_or(|t| t.elapsed() >= this.query_interval) {
+                    if let Err(e) = this.socket.send_to(&query, this.group_addr) {
+                        eprintln!("mDNS query error: {}", e);
+                        break;
+                    }
+                    last_query = Some(Instant::now());
+                }
+
+                let (size, addr) = match this.socket.recv_from(&mut this.buffer) {
+                    Ok(v) => v,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+                    Err(e) => {
+                        eprintln!("Receive error: {}", e);
+                        break;
+                    }
+                };
+                let Some(message) = Message::decode(&this.buffer[..size]) else {
+                    continue;
+                };
+                if !message.response {
+                    continue;
+                }
+
+                for (instance, payload) in txt_payloads(&message) {
+                    let Ok(data) = U::try_from((addr, PayloadReader::new(&payload))) else {
+                        continue; // Ignore invalid payload
+                    };
+                    if !this.deduplicate || seen.get(&instance) != Some(&data) {
+                        seen.insert(instance, data.clone());
+                        let _ = data_tx.send((addr, data));
+                    }
+                }
+            }
+        });
+
+        let stop = Box::new(move || {
+            let _ = stop_tx.send(());
+        });
+
+        (stop, data_rx, handle)
+    }
+}
+
+/// Instance label and [`PayloadReader`] payload of each live fs-share TXT
+/// record in `message`.
+fn txt_payloads(message: &Message) -> Vec<(String, Vec<u8>)> {
+    let service = name(SERVICE_TYPE);
+    message
+        .records
+        .iter()
+        .filter(|r| r.ttl > 0 && r.name.len() == service.len() + 1)
+        .filter(|r| same_name(&r.name[1..], &service))
+        .filter_map(|r| match &r.data {
+            RecordData::Txt(strings) => {
+                let mut payload = Vec::new();
+                for s in strings.iter().filter(|s| !s.is_empty()) {
+                    // `key=value`, or a bare key
+                    let value = match s.iter().position(|&b| b == b'=') {
+                        Some(i) => &s[i + 1..],
+                        None => &s[..],
+                    };
+                    encode_field(&mut payload, value);
+                }
+                Some((r.name[0].to_lowercase(), payload))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Builder for [`MdnsBrowser`]
+pub struct MdnsBrowserBuilder {
+    bind_addr: SocketAddr,
+    group_addr: SocketAddr,
+    interface: Ipv4Addr,
+    timeout: Option<Duration>,
+    query_interval: Duration,
+    buffer_size: Option<NonZero<usize>>,
+    deduplicate: bool,
+}
+
+impl Default for MdnsBrowserBuilder {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            group_addr: MDNS_ADDR,
+            interface: Ipv4Addr::UNSPECIFIED,
+            timeout: Some(Duration::from_millis(300)),
+            query_interval: Duration::from_secs(1),
+            buffer_size: NonZero::new(9000),
+            deduplicate: true,
+        }
+    }
+}
+
+impl MdnsBrowserBuilder {
+    /// Set socket bind address (use port 0: queries are one-shot)
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Set the multicast group (default `224.0.0.251:5353`)
+    pub fn group_addr(mut self, addr: SocketAddr) -> Self {
+        self.group_addr = addr;
+        self
+    }
+
+    /// Set the local interface address queries are sent from
+    pub fn interface(mut self, addr: Ipv4Addr) -> Self {
+        self.interface = addr;
+        self
+    }
+
+    /// Set how often to query
+    pub fn query_interval(mut self, interval: Duration) -> Self {
+        self.query_interval = interval;
+        self
+    }
+
+    /// Set internal buffer size
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = NonZero::new(size);
+        self
+    }
+
+    /// Emit only new or changed data (default), or every answer when
+    /// `false`, e.g. to tell when an instance was last heard from.
+    pub fn deduplicate(mut self, value: bool) -> Self {
+        self.deduplicate = value;
+        self
+    }
+
+    /// Build [`MdnsBrowser`]
+    pub fn build(self) -> anyhow::Result<MdnsBrowser> {
+        let buffer_size = self.buffer_size.context("Buffer size is not set")?.get();
+
+        let socket = UdpSocket::bind(self.bind_addr)
+            .with_context(|| format!("Failed to bind UDP socket on {}", self.bind_addr))?;
+
+        socket.set_read_timeout(self.timeout).with_context(|| {
+            format!(
+                "Failed to set read timeout {:?} on {}",
+                self.timeout, self.bind_addr
+            )
+        })?;
+
+        if !self.interface.is_unspecified() {
+            set_multicast_if(&socket, self.interface)
+                .with_context(|| format!("Failed to send multicast through {}", self.interface))?;
+        }
+
+        Ok(MdnsBrowser {
+            socket,
+            group_addr: self.group_addr,
+            query_interval: self.query_interval,
+            buffer: vec![0u8; buffer_size].into_boxed_slice(),
+            deduplicate: self.deduplicate,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Fields(Vec<String>);
+
+    impl<'a> TryFrom<(SocketAddr, PayloadReader<'a>)> for Fields {
+        type Error = ();
+        fn try_from((_, reader): (SocketAddr, PayloadReader<'a>)) -> Result<Self, ()> {
+            Ok(Self(
+                reader
+                    .map(|f| String::from_utf8_lossy(f).into_owned())
+                    .collect(),
+            ))
+        }
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let advertiser = MdnsAdvertiser::builder()
+            .instance("alice@laptop.home")
+            .host("laptop")
+            .listener_addr("192.168.1.5:41235".parse().unwrap())
+            .add_txt("name", "alice")
+            .add_txt("addr", "192.168.1.5:41235")
+            .build();
+        let message = advertiser.response(7, Vec::new(), TTL);
+        assert_eq!(message.records.len(), 4);
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded, message);
+
+        // The dotted instance label stays one label.
+        assert_eq!(
+            decoded.records[0].data,
+            RecordData::Ptr(advertiser.instance)
+        );
+        assert_eq!(
+            txt_payloads(&decoded),
+            vec![(
+                "alice@laptop.home".to_owned(),
+                b":\x00\x05alice:\x00\x11192.168.1.5:41235".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn compressed_names_are_followed() {
+        // Question for _fs-share._tcp.local, then a PTR record whose name
+        // points at it and whose target is "a" + pointer.
+        let mut buf = vec![0, 0, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+        write_name(&mut buf, &name(SERVICE_TYPE));
+        buf.extend_from_slice(&[0, 12, 0, 1]);
+        buf.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1, 0, 0, 0, 120, 0, 4]);
+        buf.extend_from_slice(&[1, b'a', 0xc0, 12]);
+
+        let message = Message::decode(&buf).unwrap();
+        assert_eq!(message.questions[0].name, name(SERVICE_TYPE));
+        let mut target = name(SERVICE_TYPE);
+        target.insert(0, "a".into());
+        assert_eq!(message.records[0].name, name(SERVICE_TYPE));
+        assert_eq!(message.records[0].data, RecordData::Ptr(target));
+
+        // A pointer to itself must not loop.
+        let mut buf = vec![0u8; 12];
+        buf[5] = 1;
+        buf.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+        assert!(Message::decode(&buf).is_none());
+
+        // Nor a label followed by a pointer back to it.
+        let mut buf = vec![0u8; 12];
+        buf[5] = 1;
+        buf.extend_from_slice(&[1, b'a', 0xc0, 12, 0, 12, 0, 1]);
+        assert!(Message::decode(&buf).is_none());
+        assert!(Message::decode(&[0, 0, 0]).is_none());
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "android")))]
+    fn browser_finds_advertiser_on_loopback() {
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let group = SocketAddr::new(MDNS_ADDR.ip(), socket.local_addr().unwrap().port());
+        let (stop_advertiser, advertiser) = MdnsAdvertiser::builder()
+            .instance("test receiver")
+            .listener_addr("127.0.0.1:41235".parse().unwrap())
+            .add_txt("name", "alice")
+            .add_txt("os", "linux")
+            .socket(socket)
+            .group_addr(group)
+            .interface(Ipv4Addr::LOCALHOST)
+            .build()
+            .start();
+
+        let (stop, rx, handle) = MdnsBrowser::builder()
+            .group_addr(group)
+            .interface(Ipv4Addr::LOCALHOST)
+            .query_interval(Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .start::<Fields>();
+        let (_, fields) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(fields, Fields(vec!["alice".into(), "linux".into()]));
+
+        stop();
+        stop_advertiser();
+        handle.join().unwrap();
+        advertiser.join().unwrap();
+    }
+}
@@ -1,2 +1,47 @@
+pub mod mdns;
 pub mod receiver;
 pub mod sender;
+
+use std::{net::Ipv6Addr, sync::mpsc, thread};
+
+use receiver::Discovery;
+
+/// IPv6 link-local multicast group used in place of broadcast
+pub const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7755);
+
+/// Combine several discoveries (e.g. UDP broadcast and mDNS) into one.
+///
+/// Data from all of them arrives on the returned channel; the stop
+/// function stops every one, and the thread handle finishes once they all
+/// have.
+pub fn merge<U: Send + 'static>(discoveries: Vec<Discovery<U>>) -> Discovery<U> {
+    let (data_tx, data_rx) = mpsc::channel();
+    let mut stops = Vec::new();
+    let mut handles = Vec::new();
+
+    for (stop, rx, handle) in discoveries {
+        let data_tx = data_tx.clone();
+        stops.push(stop);
+        handles.push(handle);
+        handles.push(thread::spawn(move || {
+            for data in rx {
+                if data_tx.send(data).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    let stop = Box::new(move || {
+        for stop in stops {
+            stop();
+        }
+    });
+    let handle = thread::spawn(move || {
+        for handle in handles {
+            let _ = handle.join();
+        }
+    });
+
+    (stop, data_rx, handle)
+}
@@ -3,10 +3,13 @@
 //! This module provides a lightweight UDP-based broadcast receiver
 //! used for service discovery in local networks.
 //!
+//! IPv6 has no broadcast; there the receiver joins a link-local multicast
+//! group instead (see [`BroadcastReceiverBuilder::multicast_group`]).
+//!
 use std::{
     collections::HashMap,
-    net::{IpAddr, Ipv4Addr},
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    net::{SocketAddr, SocketAddrV6, UdpSocket},
     num::NonZero,
     sync::mpsc::{self, Receiver},
     thread::{self, JoinHandle},
@@ -15,6 +18,18 @@ use std::{
 
 use anyhow::Context;
 
+/// Handle returned by [`BroadcastReceiver::start`].
+///
+/// Contains:
+/// - Stop function
+/// - Data receiver channel
+/// - Thread handle
+pub type Discovery<U> = (
+    Box<dyn FnOnce() + Send>,
+    Receiver<(SocketAddr, U)>,
+    JoinHandle<()>,
+);
+
 /// UDP broadcast receiver.
 ///
 /// Listens for UDP packets, filters them using a prefix,
@@ -28,6 +43,9 @@ pub struct BroadcastReceiver {
 
     /// UDP socket bound to a local address
     socket: UdpSocket,
+
+    /// Emit only new or changed data
+    deduplicate: bool,
 }
 
 impl BroadcastReceiver {
@@ -117,15 +135,10 @@ impl BroadcastReceiver {
     /// ## Behavior
     ///
     /// - Deduplicates data per sender (`SocketAddr`)
-    /// - Sends only new or changed data
+    /// - Sends only new or changed data, unless deduplication is turned
+    ///   off (see [`BroadcastReceiverBuilder::deduplicate`])
     /// - Ignores invalid payloads silently
-    pub fn start<U>(
-        self,
-    ) -> (
-        Box<dyn FnOnce() + Send>,
-        Receiver<(SocketAddr, U)>,
-        JoinHandle<()>,
-    )
+    pub fn start<U>(self) -> Discovery<U>
     where
         U: for<'a> TryFrom<(SocketAddr, PayloadReader<'a>)>,
         U: Clone + PartialEq + Send + 'static,
@@ -156,7 +169,7 @@ impl BroadcastReceiver {
                                 Ok(data) => {
                                     // Deduplicate
                                     let is_new_or_changed = match seen.get(&addr) {
-                                        Some(old) => old != &data,
+                                        Some(old) => !this.deduplicate || old != &data,
                                         None => true,
                                     };
 
@@ -194,6 +207,9 @@ pub struct BroadcastReceiverBuilder {
     timeout: Option<Duration>,
     buffer_size: Option<NonZero<usize>>,
     bind_addr: SocketAddr,
+    /// IPv6 group and interface index to join
+    multicast_group: Option<(Ipv6Addr, u32)>,
+    deduplicate: bool,
 }
 
 impl Default for BroadcastReceiverBuilder {
@@ -203,6 +219,8 @@ impl Default for BroadcastReceiverBuilder {
             timeout: Some(Duration::from_millis(300)),
             buffer_size: NonZero::new(8 * 1024), // 8 KB
             bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 7755),
+            multicast_group: None,
+            deduplicate: true,
         }
     }
 }
@@ -226,19 +244,60 @@ impl BroadcastReceiverBuilder {
         self
     }
 
+    /// Join an IPv6 multicast group (e.g. `ff02::7755`) on an interface
+    /// (by index) instead of receiving broadcasts.
+    ///
+    /// Only the port of the bind address is used then. Link-local groups
+    /// need a non-zero interface, and a receiver per interface.
+    pub fn multicast_group(mut self, group: Ipv6Addr, interface: u32) -> Self {
+        self.multicast_group = Some((group, interface));
+        self
+    }
+
+    /// Emit only new or changed data (default), or every announcement when
+    /// `false`, e.g. to tell when a sender was last heard from.
+    pub fn deduplicate(mut self, value: bool) -> Self {
+        self.deduplicate = value;
+        self
+    }
+
     /// Build [`BroadcastReceiver`]
     pub fn build(self) -> anyhow::Result<BroadcastReceiver> {
         let buffer_size = self.buffer_size.context("Buffer size is not set")?.get();
 
         let buffer = vec![0u8; buffer_size + self.prefix.len()].into_boxed_slice();
 
-        let socket = UdpSocket::bind(self.bind_addr)
-            .with_context(|| format!("Failed to bind UDP socket on {}", self.bind_addr))?;
+        let bind_addr = match self.multicast_group {
+            // Bound to the group itself: on Linux `[::]` would also take
+            // the port for IPv4, which an IPv4 receiver may already use.
+            Some((group, interface)) if cfg!(not(windows)) => SocketAddr::V6(SocketAddrV6::new(
+                group,
+                self.bind_addr.port(),
+                0,
+                interface,
+            )),
+            Some(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), self.bind_addr.port()),
+            None => self.bind_addr,
+        };
+
+        let socket = UdpSocket::bind(bind_addr)
+            .with_context(|| format!("Failed to bind UDP socket on {}", bind_addr))?;
+
+        if let Some((group, interface)) = self.multicast_group {
+            socket
+                .join_multicast_v6(&group, interface)
+                .with_context(|| {
+                    format!(
+                        "Failed to join multicast group {} on interface {}",
+                        group, interface
+                    )
+                })?;
+        }
 
         socket.set_read_timeout(self.timeout).with_context(|| {
             format!(
                 "Failed to set read timeout {:?} on {}",
-                self.timeout, self.bind_addr
+                self.timeout, bind_addr
             )
         })?;
 
@@ -246,6 +305,7 @@ impl BroadcastReceiverBuilder {
             prefix: self.prefix,
             buffer,
             socket,
+            deduplicate: self.deduplicate,
         })
     }
 }
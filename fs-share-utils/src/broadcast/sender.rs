@@ -29,14 +29,34 @@
 //!
 //! This matches the format used by [`crate::broadcast::receiver::PayloadReader`] on the receiver side.
 //!
+//! ## Directed Broadcast
+//!
+//! The limited broadcast address (`255.255.255.255`) only leaves through
+//! one interface. With [`BroadcasterBuilder::directed_broadcast`], each
+//! packet is sent to the directed broadcast address (e.g.
+//! `192.168.1.255`) of every active interface instead, so devices with
+//! several NICs are found on all of them. With a specific IPv4
+//! [`BroadcasterBuilder::bind_addr`], only the interface holding that
+//! address is used.
+//!
+//! A field added with [`BroadcasterBuilder::add_addr_field`] can then name
+//! the address of the interface each packet leaves through, so a device
+//! listening on all of them advertises the one its peers can reach.
+//!
+//! ## IPv6
+//!
+//! With an IPv6 target (a multicast group such as `ff02::7755`), packets
+//! go out on the interface set with [`BroadcasterBuilder::interface`], or
+//! the target's scope id.
+//!
 use std::{
-    net::{SocketAddr, UdpSocket},
+    net::{SocketAddr, SocketAddrV6, UdpSocket},
     sync::{
         Arc,
         atomic::{AtomicU64, Ordering},
         mpsc::{self, Receiver},
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
@@ -50,12 +70,22 @@ pub struct Broadcaster {
     /// Encoded payload (structured fields)
     payload: Vec<u8>,
 
-    /// Local address to bind UDP socket
-    bind_addr: SocketAddr,
+    /// Offset in `payload` and value of the address field, if any
+    addr_field: Option<(usize, SocketAddr)>,
+
+    /// Local address to bind UDP socket (default: unspecified, same
+    /// family as the target)
+    bind_addr: Option<SocketAddr>,
 
     /// Target broadcast address
     target_addr: SocketAddr,
 
+    /// Interface index for an IPv6 target without a scope id
+    interface: u32,
+
+    /// Send to each interface's directed broadcast address
+    directed: bool,
+
     /// Optional dynamic interval (milliseconds)
     interval_ms: Option<Arc<AtomicU64>>,
 }
@@ -93,21 +123,62 @@ impl Broadcaster {
         (stop, handle)
     }
 
+    /// Packet sent through the interface with address `local` (if known):
+    /// header + payload.
+    fn packet(&self, local: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.header.len() + self.payload.len() + 64);
+        packet.extend_from_slice(&self.header);
+        match self.addr_field {
+            Some((offset, mut addr)) => {
+                if let Some(local) = local
+                    && addr.ip().is_unspecified()
+                {
+                    addr.set_ip(IpAddr::V4(local));
+                }
+                packet.extend_from_slice(&self.payload[..offset]);
+                encode_field(&mut packet, addr.to_string().as_bytes());
+                packet.extend_from_slice(&self.payload[offset..]);
+            }
+            None => packet.extend_from_slice(&self.payload),
+        }
+        packet
+    }
+
     /// Internal run loop.
     ///
-    /// Builds the packet and continuously sends it until stopped.
+    /// Builds the packets and continuously sends them until stopped.
     fn run(self, stop_rx: Receiver<()>) -> anyhow::Result<()> {
-        // Build final packet = header + payload
-        let mut packet = Vec::with_capacity(self.header.len() + self.payload.len());
-        packet.extend_from_slice(&self.header);
-        packet.extend_from_slice(&self.payload);
+        let target_addr = match self.target_addr {
+            SocketAddr::V6(addr) if addr.scope_id() == 0 => SocketAddr::V6(SocketAddrV6::new(
+                *addr.ip(),
+                addr.port(),
+                addr.flowinfo(),
+                self.interface,
+            )),
+            addr => addr,
+        };
+        let bind_addr = self.bind_addr.unwrap_or(match target_addr {
+            SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+        });
 
-        let socket = UdpSocket::bind(self.bind_addr)
-            .with_context(|| format!("Failed to bind UDP socket on {}", self.bind_addr))?;
+        let socket = UdpSocket::bind(bind_addr)
+            .with_context(|| format!("Failed to bind UDP socket on {}", bind_addr))?;
 
-        socket
-            .set_broadcast(true)
-            .context("Failed to enable broadcast on UDP socket")?;
+        // IPv6 has multicast only
+        if target_addr.is_ipv4() {
+            socket
+                .set_broadcast(true)
+                .context("Failed to enable broadcast on UDP socket")?;
+        }
+
+        // Only the interface of a specific bind address is broadcast on.
+        let only = match bind_addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        };
+        let mut targets = vec![(target_addr, self.packet(only))];
+        let mut listed: Option<Instant> = None;
 
         loop {
             match stop_rx.recv_timeout(self.get_interval()) {
@@ -116,9 +187,37 @@ impl Broadcaster {
 
                 // Timeout → send packet
                 Err(mpsc::RecvTimeoutError::Timeout) => {
-                    socket.send_to(&packet, self.target_addr).with_context(|| {
-                        format!("Failed to send broadcast packet to {}", self.target_addr)
-                    })?;
+                    // Interfaces come and go, list them again now and then.
+                    if self.directed
+                        && target_addr.is_ipv4()
+                        && listed.is_none_or(|t| t.elapsed() >= RELIST_INTERFACES)
+                    {
+                        targets = directed_targets(target_addr.port(), only)
+                            .into_iter()
+                            .map(|(target, local)| (target, self.packet(Some(local))))
+                            .collect();
+                        if targets.is_empty() {
+                            targets.push((target_addr, self.packet(only)));
+                        }
+                        listed = Some(Instant::now());
+                    }
+
+                    // Fail only if no interface could be reached.
+                    let mut sent = false;
+                    let mut error = None;
+                    for (target, packet) in &targets {
+                        match socket.send_to(packet, target) {
+                            Ok(_) => sent = true,
+                            Err(e) => error = Some((target, e)),
+                        }
+                    }
+                    if let Some((target, e)) = error
+                        && !sent
+                    {
+                        return Err(e).with_context(|| {
+                            format!("Failed to send broadcast packet to {}", target)
+                        });
+                    }
                 }
 
                 // Channel disconnected → also stop
@@ -146,7 +245,52 @@ impl Broadcaster {
     }
 }
 
-use std::net::{IpAddr, Ipv4Addr};
+/// How often directed broadcast targets are listed again
+const RELIST_INTERFACES: Duration = Duration::from_secs(5);
+
+/// Directed broadcast address of every active IPv4 interface (or only of
+/// the one holding `only`), with the interface's own address.
+#[cfg(all(unix, not(target_os = "android")))]
+fn directed_targets(port: u16, only: Option<Ipv4Addr>) -> Vec<(SocketAddr, Ipv4Addr)> {
+    let Ok(interfaces) = crate::ip::IterIpAddr::new() else {
+        return Vec::new();
+    };
+    let mut targets: Vec<(SocketAddr, Ipv4Addr)> = interfaces
+        .filter(|i| i.up && !i.loopback)
+        .filter_map(|i| match (i.addr, i.broadcast) {
+            (IpAddr::V4(addr), Some(broadcast)) if only.is_none_or(|only| only == addr) => {
+                Some((SocketAddr::new(IpAddr::V4(broadcast), port), addr))
+            }
+            _ => None,
+        })
+        .collect();
+    // One packet per subnet, naming its lowest address
+    targets.sort();
+    targets.dedup_by_key(|(target, _)| *target);
+    targets
+}
+
+/// Interfaces can't be listed here; the target address is used as is.
+#[cfg(not(all(unix, not(target_os = "android"))))]
+fn directed_targets(_port: u16, _only: Option<Ipv4Addr>) -> Vec<(SocketAddr, Ipv4Addr)> {
+    Vec::new()
+}
+
+/// Append `:<len:u16><bytes>` to `buf`, a field as read by
+/// [`PayloadReader`](super::receiver::PayloadReader).
+///
+/// # Panics
+///
+/// Panics if field size exceeds `u16::MAX`
+pub fn encode_field(buf: &mut Vec<u8>, bytes: &[u8]) {
+    assert!(bytes.len() < u16::MAX as usize, "field too large");
+
+    buf.push(b':');
+    buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// Builder for [`Broadcaster`]
 ///
@@ -154,13 +298,17 @@ use std::net::{IpAddr, Ipv4Addr};
 /// - Header
 /// - Payload fields
 /// - Bind address
-/// - Broadcast target address
+/// - Broadcast target address (or IPv6 multicast group and interface)
+/// - Directed broadcast per interface
 /// - Interval
 pub struct BroadcasterBuilder {
     header: Vec<u8>,
     payload: Vec<u8>,
-    bind_addr: SocketAddr,
+    addr_field: Option<(usize, SocketAddr)>,
+    bind_addr: Option<SocketAddr>,
     target_addr: SocketAddr,
+    interface: u32,
+    directed: bool,
     interval_ms: Option<Arc<AtomicU64>>,
 }
 
@@ -169,8 +317,11 @@ impl Default for BroadcasterBuilder {
         Self {
             header: Vec::new(),
             payload: Vec::new(),
-            bind_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            addr_field: None,
+            bind_addr: None,
             target_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), 7755),
+            interface: 0,
+            directed: false,
             interval_ms: None,
         }
     }
@@ -194,15 +345,17 @@ impl BroadcasterBuilder {
     ///
     /// Panics if field size exceeds `u16::MAX`
     pub fn add_field<T: AsRef<[u8]>>(mut self, data: T) -> Self {
-        let bytes = data.as_ref();
-
-        assert!(bytes.len() < u16::MAX as usize, "field too large");
-
-        self.payload.push(b':');
-        self.payload
-            .extend_from_slice(&(bytes.len() as u16).to_be_bytes());
-        self.payload.extend_from_slice(bytes);
+        encode_field(&mut self.payload, data.as_ref());
+        self
+    }
 
+    /// Add a field holding `addr`.
+    ///
+    /// If `addr` is unspecified (`0.0.0.0:port`), a packet sent by directed
+    /// broadcast carries the address of the interface it is sent through
+    /// instead. Only one such field is kept.
+    pub fn add_addr_field(mut self, addr: SocketAddr) -> Self {
+        self.addr_field = Some((self.payload.len(), addr));
         self
     }
 
@@ -212,26 +365,79 @@ impl BroadcasterBuilder {
         self
     }
 
-    /// Set UDP bind address
+    /// Set UDP bind address; a specific IPv4 address also limits directed
+    /// broadcast to its interface
     pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
-        self.bind_addr = addr;
+        self.bind_addr = Some(addr);
         self
     }
 
-    /// Set broadcast target address
+    /// Set broadcast target address, or an IPv6 multicast group
     pub fn target_addr(mut self, addr: SocketAddr) -> Self {
         self.target_addr = addr;
         self
     }
 
+    /// Set the interface index an IPv6 target is sent through
+    pub fn interface(mut self, index: u32) -> Self {
+        self.interface = index;
+        self
+    }
+
+    /// Send to the directed broadcast address of every active interface
+    /// (IPv4 only), using the target's port.
+    ///
+    /// Falls back to the target address where none are found.
+    pub fn directed_broadcast(mut self, enable: bool) -> Self {
+        self.directed = enable;
+        self
+    }
+
     /// Build [`Broadcaster`]
     pub fn build(self) -> Broadcaster {
         Broadcaster {
             header: self.header,
             payload: self.payload,
+            addr_field: self.addr_field,
             bind_addr: self.bind_addr,
             target_addr: self.target_addr,
+            interface: self.interface,
+            directed: self.directed,
             interval_ms: self.interval_ms,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcast::receiver::PayloadReader;
+
+    #[test]
+    fn addr_field_names_the_interface() {
+        let broadcaster = Broadcaster::builder()
+            .header("v1.test")
+            .add_field("alice")
+            .add_addr_field("0.0.0.0:4000".parse().unwrap())
+            .add_field("http")
+            .build();
+        let fields = |packet: &[u8]| -> Vec<String> {
+            PayloadReader::new(&packet[b"v1.test".len()..])
+                .map(|f| String::from_utf8(f.to_vec()).unwrap())
+                .collect()
+        };
+
+        let packet = broadcaster.packet(Some(Ipv4Addr::new(192, 168, 1, 5)));
+        assert_eq!(fields(&packet), ["alice", "192.168.1.5:4000", "http"]);
+        let packet = broadcaster.packet(None);
+        assert_eq!(fields(&packet), ["alice", "0.0.0.0:4000", "http"]);
+
+        // A specific address is kept on every interface.
+        let broadcaster = Broadcaster::builder()
+            .header("v1.test")
+            .add_addr_field("10.0.0.2:4000".parse().unwrap())
+            .build();
+        let packet = broadcaster.packet(Some(Ipv4Addr::new(192, 168, 1, 5)));
+        assert_eq!(fields(&packet), ["10.0.0.2:4000"]);
+    }
+}
@@ -0,0 +1,30 @@
+//! # Collision Policy
+//!
+//! What to do when a received file already exists in the download
+//! directory and was not left behind by an interrupted transfer.
+//!
+//! The name checks and the renaming used by the runtimes are exported for
+//! other ways of receiving files, such as the upload form of `fs-share serve`.
+
+pub use crate::tf::{check_component, renamed_path};
+
+/// How to handle a received file whose target path already exists.
+///
+/// Whatever the choice, the sender is answered for every file, so the
+/// session continues with the next one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Keep the existing file and refuse the incoming one
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save the incoming file as `name (1).ext`, `name (2).ext`, ...
+    #[default]
+    Rename,
+    /// Replace the existing file only if the incoming one is newer
+    Newer,
+    /// Ask the app for every collision
+    ///
+    /// See `ask_collision` on the sender and receiver `App` traits.
+    Ask,
+}
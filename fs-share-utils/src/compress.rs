@@ -0,0 +1,327 @@
+//! # Compression
+//!
+//! Chunked LZ4 framing of file data, used when both peers negotiated
+//! [`Features::COMPRESSION`](crate::protocol::Features::COMPRESSION).
+//!
+//! ## Format
+//!
+//! With compression, the bytes of a file (after the `:off:` reply) are sent
+//! as a sequence of chunks instead of raw bytes:
+//! ```text
+//! kind(u8) | raw_len(u32) | wire_len(u32) | payload
+//! ```
+//!
+//! `kind` is [`STORED`] (payload is `raw_len` plain bytes) or [`LZ4`]
+//! (payload is an LZ4 block that expands to `raw_len` bytes). Chunks hold
+//! at most [`CHUNK_SIZE`] raw bytes and end exactly at the end of the file,
+//! so the framing never reaches into the next frame.
+//!
+//! The sender picks stored chunks for data that doesn't compress: files
+//! with a known compressed extension, files whose first bytes don't shrink
+//! (see [`should_compress`]) and single chunks that wouldn't get smaller.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Chunk kind: plain bytes
+pub const STORED: u8 = 0;
+
+/// Chunk kind: LZ4 block
+pub const LZ4: u8 = 1;
+
+/// Largest number of raw bytes in a chunk
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Bytes sampled from the start of a file to estimate its compression ratio
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// A sample must shrink below this fraction (in percent) to be worth compressing
+const MAX_RATIO_PERCENT: usize = 90;
+
+/// Extensions of formats that are already compressed
+const COMPRESSED_EXTENSIONS: [&str; 38] = [
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt",
+    "ogg", "opus", "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "xlsx", "xz", "zip",
+];
+
+/// Whether the data of `file` starting at `offset` is worth compressing.
+///
+/// Files with a known compressed extension are never compressed. Otherwise
+/// up to 64 KiB are sampled and compressed; the file is compressed if the
+/// sample shrinks by at least 10%. The cursor is left at `offset`.
+pub fn should_compress(path: &Path, file: &mut File, offset: u64) -> io::Result<bool> {
+    if has_compressed_extension(path) {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE);
+    (&mut *file)
+        .take(SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(sample_compresses(&sample))
+}
+
+fn has_compressed_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            COMPRESSED_EXTENSIONS
+                .iter()
+                .any(|known| known.eq_ignore_ascii_case(ext))
+        })
+}
+
+fn sample_compresses(sample: &[u8]) -> bool {
+    if sample.is_empty() {
+        return false;
+    }
+    let compressed = lz4_flex::block::compress(sample);
+    compressed.len() * 100 < sample.len() * MAX_RATIO_PERCENT
+}
+
+/// Writer for the bytes of one file.
+///
+/// Chunks are only used if `chunked` (compression was negotiated);
+/// `compress` then picks LZ4 or stored chunks.
+pub fn body_writer<'a, W: Write + 'a>(
+    inner: W,
+    chunked: bool,
+    compress: bool,
+) -> Box<dyn Write + 'a> {
+    match chunked {
+        true => Box::new(ChunkWriter::new(inner, compress)),
+        false => Box::new(inner),
+    }
+}
+
+/// Reader for the next `len` bytes of a file, see [`body_writer`].
+pub fn body_reader<'a, R: Read + 'a>(inner: R, chunked: bool, len: u64) -> Box<dyn Read + 'a> {
+    match chunked {
+        true => Box::new(ChunkReader::new(inner, len)),
+        false => Box::new(inner),
+    }
+}
+
+/// Writer that frames everything written to it as chunks.
+///
+/// Data is buffered until a chunk is full; [`Write::flush`] writes the
+/// pending partial chunk, so it must be called once at the end of a file.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+    compress: bool,
+    pending: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    /// `compress == false` writes only stored chunks.
+    pub fn new(inner: W, compress: bool) -> Self {
+        let output = match compress {
+            true => vec![0u8; lz4_flex::block::get_maximum_output_size(CHUNK_SIZE)],
+            false => Vec::new(),
+        };
+        Self {
+            inner,
+            compress,
+            pending: Vec::with_capacity(CHUNK_SIZE),
+            output,
+        }
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let raw_len = self.pending.len() as u32;
+        let compressed_len = match self.compress {
+            true => lz4_flex::block::compress_into(&self.pending, &mut self.output)
+                .map_err(io::Error::other)?,
+            false => usize::MAX,
+        };
+        let (kind, payload) = match compressed_len < self.pending.len() {
+            true => (LZ4, &self.output[..compressed_len]),
+            false => (STORED, &self.pending[..]),
+        };
+        self.inner.write_all(&[kind])?;
+        self.inner.write_all(&raw_len.to_be_bytes())?;
+        self.inner
+            .write_all(&(payload.len() as u32).to_be_bytes())?;
+        self.inner.write_all(payload)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), CHUNK_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+/// Reader that decodes the chunks of a single file.
+///
+/// It never reads past the chunk that completes `remaining` raw bytes.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    remaining: u64,
+    decoded: Vec<u8>,
+    pos: usize,
+    input: Vec<u8>,
+}
+
+impl<R: Read> ChunkReader<R> {
+    /// Decode the chunks of the next `remaining` raw bytes.
+    pub fn new(inner: R, remaining: u64) -> Self {
+        Self {
+            inner,
+            remaining,
+            decoded: Vec::new(),
+            pos: 0,
+            input: Vec::new(),
+        }
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut head = [0u8; 9];
+        self.inner.read_exact(&mut head)?;
+        let kind = head[0];
+        let raw_len = u32::from_be_bytes(head[1..5].try_into().unwrap()) as usize;
+        let wire_len = u32::from_be_bytes(head[5..9].try_into().unwrap()) as usize;
+
+        if raw_len == 0 || raw_len > CHUNK_SIZE || raw_len as u64 > self.remaining {
+            return Err(invalid(format!("invalid chunk length {}", raw_len)));
+        }
+        let max_wire = match kind {
+            STORED => raw_len,
+            LZ4 => lz4_flex::block::get_maximum_output_size(raw_len),
+            _ => return Err(invalid(format!("unknown chunk kind {}", kind))),
+        };
+        if wire_len > max_wire || (kind == STORED && wire_len != raw_len) {
+            return Err(invalid(format!("invalid chunk size {}", wire_len)));
+        }
+
+        self.pos = 0;
+        self.decoded.resize(raw_len, 0);
+        if kind == STORED {
+            self.inner.read_exact(&mut self.decoded)?;
+        } else {
+            self.input.resize(wire_len, 0);
+            self.inner.read_exact(&mut self.input)?;
+            let n = lz4_flex::block::decompress_into(&self.input, &mut self.decoded)
+                .map_err(invalid)?;
+            if n != raw_len {
+                return Err(invalid(format!(
+                    "chunk expanded to {} bytes instead of {}",
+                    n, raw_len
+                )));
+            }
+        }
+        self.remaining -= raw_len as u64;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.decoded.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let n = std::cmp::min(buf.len(), self.decoded.len() - self.pos);
+        buf[..n].copy_from_slice(&self.decoded[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8], compress: bool) -> Vec<u8> {
+        let mut wire = Vec::new();
+        let mut writer = ChunkWriter::new(&mut wire, compress);
+        writer.write_all(data).unwrap();
+        writer.flush().unwrap();
+        wire
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let text: Vec<u8> = b"fs-share compresses logs and source trees\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(CHUNK_SIZE * 2 + 100)
+            .collect();
+        for compress in [true, false] {
+            let mut wire = encode(&text, compress);
+            wire.extend_from_slice(b":eof:");
+
+            let mut input = wire.as_slice();
+            let mut decoded = Vec::new();
+            ChunkReader::new(&mut input, text.len() as u64)
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, text);
+            // The next frame is left untouched
+            assert_eq!(input, b":eof:");
+            assert_eq!(wire.len() < text.len(), compress);
+        }
+    }
+
+    #[test]
+    fn incompressible_chunks_are_stored() {
+        // xorshift noise doesn't compress
+        let mut x = 0x2545f491u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let wire = encode(&noise, true);
+        assert_eq!(wire[0], STORED);
+        assert_eq!(wire.len(), noise.len() + 9);
+        assert!(!sample_compresses(&noise));
+        assert!(sample_compresses(&[b'a'; 4096]));
+        assert!(has_compressed_extension(Path::new("photo.JPG")));
+        assert!(!has_compressed_extension(Path::new("server.log")));
+    }
+
+    #[test]
+    fn chunk_beyond_file_is_rejected() {
+        let wire = encode(&[b'a'; 100], true);
+        let mut decoded = Vec::new();
+        let err = ChunkReader::new(wire.as_slice(), 50)
+            .read_to_end(&mut decoded)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,251 @@
+//! # Errors
+//!
+//! Typed errors produced by the sender and receiver runtimes.
+//!
+//! The runtimes return [`anyhow::Error`]; use
+//! [`anyhow::Error::downcast_ref`] to inspect one of the errors below.
+
+use std::{fmt, path::PathBuf};
+
+use crate::manifest::human_size;
+
+/// The BLAKE3 hash of a received file does not match the one computed
+/// by the peer while sending it.
+///
+/// The corrupt file has already been removed when this error is returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// Path the file was being written to
+    pub path: PathBuf,
+    /// Hash sent by the peer
+    pub expected: [u8; 32],
+    /// Hash of the bytes written to disk
+    pub actual: [u8; 32],
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checksum mismatch for {}: expected {}, got {}",
+            self.path.display(),
+            blake3::Hash::from_bytes(self.expected).to_hex(),
+            blake3::Hash::from_bytes(self.actual).to_hex()
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+/// Code carried by an `:err:` frame.
+///
+/// Unknown codes from newer peers are kept as [`ErrorCode::Other`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Unspecified failure
+    Internal,
+    /// The peer sent something this side doesn't understand
+    Protocol,
+    /// The file was refused by the receiving side
+    Rejected,
+    /// No space left on the receiving device
+    DiskFull,
+    /// Any other I/O failure
+    Io,
+    /// A received file failed checksum verification
+    Checksum,
+    /// The file already exists on the receiving side
+    Exists,
+    /// A code this version doesn't know
+    Other(u16),
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => Self::Internal,
+            2 => Self::Protocol,
+            3 => Self::Rejected,
+            4 => Self::DiskFull,
+            5 => Self::Io,
+            6 => Self::Checksum,
+            7 => Self::Exists,
+            v => Self::Other(v),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Internal => 1,
+            ErrorCode::Protocol => 2,
+            ErrorCode::Rejected => 3,
+            ErrorCode::DiskFull => 4,
+            ErrorCode::Io => 5,
+            ErrorCode::Checksum => 6,
+            ErrorCode::Exists => 7,
+            ErrorCode::Other(v) => v,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// Pick the code that best describes `err`.
+    pub fn of(err: &anyhow::Error) -> Self {
+        if let Some(e) = err.downcast_ref::<PeerError>() {
+            return e.code;
+        }
+        if err.downcast_ref::<ProtocolError>().is_some() {
+            return Self::Protocol;
+        }
+        if err.downcast_ref::<ChecksumMismatch>().is_some() {
+            return Self::Checksum;
+        }
+        if err.downcast_ref::<InvalidName>().is_some() {
+            return Self::Rejected;
+        }
+        if err.downcast_ref::<InsufficientSpace>().is_some() {
+            return Self::DiskFull;
+        }
+        match err.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) {
+            Some(e) if e.kind() == std::io::ErrorKind::StorageFull => Self::DiskFull,
+            Some(_) => Self::Io,
+            None => Self::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Internal => write!(f, "internal error"),
+            Self::Protocol => write!(f, "protocol error"),
+            Self::Rejected => write!(f, "rejected"),
+            Self::DiskFull => write!(f, "disk full"),
+            Self::Io => write!(f, "I/O error"),
+            Self::Checksum => write!(f, "checksum mismatch"),
+            Self::Exists => write!(f, "file exists"),
+            Self::Other(v) => write!(f, "error {}", v),
+        }
+    }
+}
+
+/// The peer ended the session with an `:err:` frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerError {
+    pub code: ErrorCode,
+    /// Human readable description sent by the peer
+    pub message: String,
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer reported {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// The peer sent a frame that doesn't fit the protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// A frame started with a marker this version doesn't know
+    UnknownMarker([u8; 5]),
+    /// A reply to a file header was expected but another marker arrived
+    UnexpectedReply([u8; 5]),
+    /// A name was announced in an encoding this version doesn't know
+    UnknownEncoding(u8),
+    /// A name was announced with more bytes than are ever accepted
+    NameLength(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownMarker(m) => write!(f, "Unknown protocol marker \"{}\"", m.escape_ascii()),
+            Self::UnexpectedReply(m) => {
+                write!(
+                    f,
+                    "Unexpected reply to file header \"{}\"",
+                    m.escape_ascii()
+                )
+            }
+            Self::UnknownEncoding(v) => write!(f, "Unknown name encoding {}", v),
+            Self::NameLength(len) => write!(f, "Name of {} bytes is too long", len),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// A name received from the peer can't be used as a local path.
+///
+/// The file is refused and the peer is told why; the session goes on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidName {
+    /// Name as received from the peer
+    pub name: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for InvalidName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid name {:?}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidName {}
+
+/// A name is too long for the length field of the frame it is sent in.
+///
+/// Returned instead of truncating the length; the session ends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTooLong {
+    /// Name that was to be sent
+    pub name: String,
+    /// Its length in bytes
+    pub len: usize,
+    /// Longest name the frame can carry
+    pub max: usize,
+}
+
+impl fmt::Display for NameTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Name too long to send ({} bytes, at most {}): {}",
+            self.len, self.max, self.name
+        )
+    }
+}
+
+impl std::error::Error for NameTooLong {}
+
+/// The files announced in the peer's manifest don't fit on the filesystem
+/// of the download directory.
+///
+/// The transfer is refused before any file is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpace {
+    /// Download directory
+    pub path: PathBuf,
+    /// Bytes still to be written
+    pub needed: u64,
+    /// Bytes available on the filesystem
+    pub available: u64,
+}
+
+impl fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Not enough free space in {}: {} needed, {} available",
+            self.path.display(),
+            human_size(self.needed),
+            human_size(self.available)
+        )
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
@@ -2,4 +2,4 @@
 mod unix;
 
 #[cfg(all(unix, not(target_os = "android")))]
-pub use unix::IterIpAddr;
+pub use unix::{Interface, IterIpAddr, directed_broadcast, interface_index};
@@ -1,4 +1,4 @@
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
@@ -21,6 +21,56 @@ impl Drop for IterIpAddr {
     }
 }
 
+/// A network interface address, as listed by `getifaddrs`.
+///
+/// An interface with several addresses is listed once per address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub addr: IpAddr,
+    pub netmask: Option<IpAddr>,
+    /// Directed broadcast address (IPv4 interfaces that support broadcast)
+    pub broadcast: Option<Ipv4Addr>,
+    pub up: bool,
+    pub loopback: bool,
+    pub multicast: bool,
+}
+
+impl Interface {
+    /// Length of the network prefix, `None` without a (contiguous) netmask.
+    pub fn prefix_len(&self) -> Option<u8> {
+        let (len, rest) = match self.netmask? {
+            IpAddr::V4(mask) => {
+                let mask = u32::from(mask);
+                let len = mask.leading_ones();
+                (len, mask.checked_shl(len).unwrap_or(0) as u128)
+            }
+            IpAddr::V6(mask) => {
+                let mask = u128::from(mask);
+                let len = mask.leading_ones();
+                (len, mask.checked_shl(len).unwrap_or(0))
+            }
+        };
+        (rest == 0).then_some(len as u8)
+    }
+}
+
+impl std::fmt::Display for Interface {
+    /// `eth0 192.168.1.5/24`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.name, self.addr)?;
+        if let Some(len) = self.prefix_len() {
+            write!(f, "/{}", len)?;
+        }
+        Ok(())
+    }
+}
+
+/// `addr` with all host bits set.
+pub fn directed_broadcast(addr: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(addr) | !u32::from(netmask))
+}
+
 impl IterIpAddr {
     pub fn new() -> io::Result<Self> {
         let mut ifaddr: *mut ifaddrs = std::ptr::null_mut();
@@ -35,64 +85,137 @@ impl IterIpAddr {
             }
         }
     }
-    pub fn get_addr<U: AsRef<str>, T: AsRef<[U]>>(self, ifa_name: T) -> Option<IpAddr> {
-        self.filter(|(i, _)| {
-            ifa_name
-                .as_ref()
-                .iter()
-                .find(|&a| a.as_ref() == i)
-                .is_some()
-        })
-        .next()
-        .map(|(_, v)| v)
+    pub fn get_addr<U: AsRef<str>, T: AsRef<[U]>>(mut self, ifa_name: T) -> Option<IpAddr> {
+        self.find(|i| ifa_name.as_ref().iter().any(|a| a.as_ref() == i.name))
+            .map(|i| i.addr)
     }
     pub fn iter_ipv4(self) -> impl Iterator<Item = (String, Ipv4Addr)> {
-        self.filter_map(|(a, b)| match b {
-            IpAddr::V4(addr) => Some((a, addr)),
+        self.filter_map(|i| match i.addr {
+            IpAddr::V4(addr) => Some((i.name, addr)),
             IpAddr::V6(_) => None,
         })
     }
     pub fn iter_ipv6(self) -> impl Iterator<Item = (String, Ipv6Addr)> {
-        self.filter_map(|(a, b)| match b {
+        self.filter_map(|i| match i.addr {
             IpAddr::V4(_) => None,
-            IpAddr::V6(addr) => Some((a, addr)),
+            IpAddr::V6(addr) => Some((i.name, addr)),
         })
     }
 }
 
+/// IP address in `sa`, `None` for other families.
+///
+/// # Safety
+///
+/// `sa` must be null or point to a socket address of its family's size.
+unsafe fn sockaddr_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+    if sa.is_null() {
+        return None;
+    }
+    unsafe {
+        match (*sa).sa_family as i32 {
+            libc::AF_INET => {
+                let sa = sa as *const libc::sockaddr_in;
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    (*sa).sin_addr.s_addr,
+                ))))
+            }
+            libc::AF_INET6 => {
+                let sa6 = sa as *const libc::sockaddr_in6;
+                Some(IpAddr::V6(Ipv6Addr::from((*sa6).sin6_addr.s6_addr)))
+            }
+            _ => None,
+        }
+    }
+}
+
 impl Iterator for IterIpAddr {
-    type Item = (String, IpAddr);
+    type Item = Interface;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.curr.is_null() {
-            return None;
-        }
         unsafe {
             while !self.curr.is_null() {
                 let ifa = &*self.curr;
                 self.curr = ifa.ifa_next;
 
-                if ifa.ifa_addr.is_null() {
+                let Some(addr) = sockaddr_ip(ifa.ifa_addr) else {
                     continue;
-                }
+                };
+                let netmask = sockaddr_ip(ifa.ifa_netmask);
+                let flags = ifa.ifa_flags;
+                let has = |flag: libc::c_int| flags & flag as libc::c_uint != 0;
 
-                let name = CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned();
-                let family = (*ifa.ifa_addr).sa_family as i32;
-
-                match family {
-                    libc::AF_INET => {
-                        let sa = ifa.ifa_addr as *const libc::sockaddr_in;
-                        let ip = Ipv4Addr::from(u32::from_be((*sa).sin_addr.s_addr));
-                        return Some((name, IpAddr::V4(ip)));
-                    }
-                    libc::AF_INET6 => {
-                        let sa6 = ifa.ifa_addr as *const libc::sockaddr_in6;
-                        let ip = Ipv6Addr::from((*sa6).sin6_addr.s6_addr);
-                        return Some((name, IpAddr::V6(ip)));
+                let broadcast = match (addr, netmask) {
+                    (IpAddr::V4(addr), Some(IpAddr::V4(mask))) if has(libc::IFF_BROADCAST) => {
+                        Some(directed_broadcast(addr, mask))
                     }
-                    _ => continue,
-                }
+                    _ => None,
+                };
+
+                return Some(Interface {
+                    name: CStr::from_ptr(ifa.ifa_name).to_string_lossy().into_owned(),
+                    addr,
+                    netmask,
+                    broadcast,
+                    up: has(libc::IFF_UP),
+                    loopback: has(libc::IFF_LOOPBACK),
+                    multicast: has(libc::IFF_MULTICAST),
+                });
             }
         }
         None
     }
 }
+
+/// Index of the interface called `name`, as used in IPv6 scope ids.
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: `name` is a valid C string for the duration of the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_and_broadcast_come_from_the_netmask() {
+        let mut interface = Interface {
+            name: "eth0".into(),
+            addr: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5)),
+            netmask: Some(IpAddr::V4(Ipv4Addr::new(255, 255, 254, 0))),
+            broadcast: None,
+            up: true,
+            loopback: false,
+            multicast: true,
+        };
+        assert_eq!(interface.prefix_len(), Some(23));
+        assert_eq!(interface.to_string(), "eth0 192.168.1.5/23");
+        assert_eq!(
+            directed_broadcast(
+                Ipv4Addr::new(192, 168, 1, 5),
+                Ipv4Addr::new(255, 255, 254, 0)
+            ),
+            Ipv4Addr::new(192, 168, 1, 255)
+        );
+
+        interface.netmask = Some(IpAddr::V4(Ipv4Addr::new(255, 0, 255, 0)));
+        assert_eq!(interface.prefix_len(), None);
+        interface.addr = IpAddr::V6("fe80::1".parse().unwrap());
+        interface.netmask = Some(IpAddr::V6("ffff:ffff:ffff:ffff::".parse().unwrap()));
+        assert_eq!(interface.prefix_len(), Some(64));
+        interface.netmask = Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(interface.prefix_len(), Some(0));
+    }
+
+    #[test]
+    fn loopback_is_listed() {
+        let lo = IterIpAddr::new()
+            .unwrap()
+            .find(|i| i.addr == IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .unwrap();
+        assert!(lo.up && lo.loopback);
+        assert_eq!(lo.prefix_len(), Some(8));
+        assert_eq!(lo.broadcast, None);
+    }
+}
@@ -10,17 +10,34 @@
 //! ## Modules
 //!
 //! ### [`broadcast`]
-//! Provides UDP broadcast utilities for peer discovery.
+//! Provides UDP broadcast and mDNS (DNS-SD) utilities for peer discovery.
 //! Used to announce and detect available senders/receivers on the network.
 //!
+//! ### [`collision`]
+//! Policy for received files that already exist locally, and the checks
+//! applied to received names.
+//!
+//! ### [`error`]
+//! Typed errors returned by the runtimes (e.g. checksum mismatches).
+//!
 //! ### [`ip`]
 //! Utilities for working with network interfaces and IP addresses.
 //! Includes platform-specific implementations (Linux, Windows, Android).
 //!
+//! ### [`links`]
+//! Policy for symbolic links inside sent directory trees.
+//!
+//! ### [`manifest`]
+//! List of entries sent before any file data, used for the free-space
+//! check and overall progress.
+//!
 //! ### [`pb`]
 //! Progress bar utilities.
 //! Abstracts progress reporting (can be enabled/disabled depending on CLI flags).
 //!
+//! ### [`protocol`]
+//! Handshake, protocol version and feature negotiation.
+//!
 //! ### [`receiver`]
 //! Core logic for receiving files over TCP.
 //! Handles incoming streams, parsing metadata, and saving files.
@@ -29,9 +46,30 @@
 //! Core logic for sending files over TCP.
 //! Responsible for encoding metadata and streaming file contents.
 //!
+//! ### [`summary`]
+//! Per-file record (size, BLAKE3 hash) of a finished session.
+//!
+//! ### [`throttle`]
+//! Token bucket bandwidth limit shared by the connections of a session.
+//!
+//! ### [`zerocopy`]
+//! `sendfile`/`splice` fast path for plain TCP connections on Linux.
+//!
+pub(crate) mod attrs;
 pub mod broadcast;
+pub mod collision;
+pub(crate) mod compress;
+pub mod error;
 pub mod ip;
+pub mod links;
+pub mod manifest;
+pub(crate) mod name;
+pub(crate) mod parallel;
 pub mod pb;
+pub mod protocol;
 pub mod receiver;
 pub mod sender;
+pub mod summary;
 pub(crate) mod tf;
+pub mod throttle;
+pub mod zerocopy;
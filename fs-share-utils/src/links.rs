@@ -0,0 +1,20 @@
+//! # Link Policy
+//!
+//! What to do with symbolic links found while sending a directory tree.
+//! Paths given on the command line are always followed.
+
+/// How to send a symbolic link inside a directory tree.
+///
+/// Hard links are independent of this: with
+/// [`Features::LINKS`](crate::protocol::Features::LINKS), a file that was
+/// already sent from the same tree is recreated as a hard link by the peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkPolicy {
+    /// Send what the link points to, as if it was a regular file or directory
+    Follow,
+    /// Send the link itself with its target; the peer recreates it
+    #[default]
+    Preserve,
+    /// Leave links out and list them as skipped
+    Skip,
+}
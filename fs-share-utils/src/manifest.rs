@@ -0,0 +1,484 @@
+//! # Transfer Manifest
+//!
+//! List of everything a side is about to send, exchanged before any file
+//! data when both peers negotiated
+//! [`Features::MANIFEST`](crate::protocol::Features::MANIFEST).
+//!
+//! ## Format
+//!
+//! ```text
+//! sender   -> :man: | from_len(u16) | from | count(u32) | entry...
+//! entry       kind(u8) | size(u64) | path_len(u16) | path
+//! receiver -> :mok:                                      (go ahead)
+//!           | :rej: | code(u16) | msg_len(u16) | msg     (nothing is sent)
+//!           | :err: | code(u16) | msg_len(u16) | msg     (session ends)
+//! ```
+//!
+//! `from` is the name the sending device gives for itself. Paths use the
+//! same `/` separated form as the `:ffr:`, `:dir:` and `:lnk:` frames that
+//! follow; a file sent on its own is just its name. `size` is 0 for
+//! directories and symbolic links; a hard link carries the size of its
+//! file, which is sent in its place if the link is refused, but only files
+//! count towards the total. With
+//! [`Features::RAW_NAMES`](crate::protocol::Features::RAW_NAMES) `path_len`
+//! is a `u32`; paths are always the lossy UTF-8 form of the names that
+//! follow, so both sides can match them.
+//!
+//! ## Approval
+//!
+//! The receiving side decides on the whole manifest (see
+//! [`crate::receiver::App::approve_incoming`]). After `:mok:`, files and
+//! links it didn't pick, entries missing from the manifest and files
+//! larger than announced are refused one by one with `:rej:`; directories
+//! are only created if picked or holding a picked entry. Nothing is
+//! created for a rejected manifest. A peer that sends no manifest has
+//! each entry approved on its own.
+//!
+//! ## Free Space
+//!
+//! Before answering, the receiver compares the size of all files (minus
+//! what it already holds in partial files it can resume) with the space
+//! available on the download directory's filesystem. If it doesn't fit,
+//! the transfer is refused with [`ErrorCode::DiskFull`](crate::error::ErrorCode::DiskFull)
+//! before a single byte is written. The check is skipped where the free
+//! space can't be determined.
+
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use crate::error::{NameTooLong, ProtocolError};
+use crate::name::MAX_WIRE_LEN;
+
+/// What an entry of the manifest is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symbolic or hard link; carries no data
+    Link,
+}
+
+impl EntryKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::File => 0,
+            Self::Directory => 1,
+            Self::Link => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Self::File),
+            1 => Ok(Self::Directory),
+            2 => Ok(Self::Link),
+            v => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown manifest entry kind {}", v),
+            )),
+        }
+    }
+}
+
+/// A single entry of a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    /// `/` separated path as it will be sent
+    pub path: String,
+    /// File size in bytes, 0 for directories and links
+    pub size: u64,
+}
+
+/// Everything one side is about to send.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// Name of the sending device, as given by itself (may be empty)
+    pub from: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// Entries that carry data
+    pub fn files(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter().filter(|e| e.kind == EntryKind::File)
+    }
+
+    /// Sum of all file sizes
+    pub fn total_size(&self) -> u64 {
+        self.files().map(|e| e.size).sum()
+    }
+
+    /// Write the frame, marker included. Paths have 32-bit lengths if
+    /// the peer negotiated raw names.
+    pub(crate) fn write<W: Write>(&self, stream: &mut W, raw_names: bool) -> anyhow::Result<()> {
+        let count = u32::try_from(self.entries.len())
+            .map_err(|_| anyhow::anyhow!("Too many entries in manifest"))?;
+        let from = &self.from.as_bytes()[..std::cmp::min(self.from.len(), MAX_FROM_LEN)];
+        let max_path = match raw_names {
+            true => MAX_WIRE_LEN,
+            false => u16::MAX as usize,
+        };
+        stream.write_all(b":man:")?;
+        stream.write_all(&(from.len() as u16).to_be_bytes())?;
+        stream.write_all(from)?;
+        stream.write_all(&count.to_be_bytes())?;
+        for entry in &self.entries {
+            if entry.path.len() > max_path {
+                return Err(NameTooLong {
+                    name: entry.path.clone(),
+                    len: entry.path.len(),
+                    max: max_path,
+                }
+                .into());
+            }
+            stream.write_all(&[entry.kind.to_byte()])?;
+            stream.write_all(&entry.size.to_be_bytes())?;
+            match raw_names {
+                true => stream.write_all(&(entry.path.len() as u32).to_be_bytes())?,
+                false => stream.write_all(&(entry.path.len() as u16).to_be_bytes())?,
+            }
+            stream.write_all(entry.path.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Read a frame whose marker was already consumed.
+    pub(crate) fn read<R: Read>(stream: &mut R, raw_names: bool) -> anyhow::Result<Self> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut from = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut from)?;
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf)?;
+        let count = u32::from_be_bytes(buf);
+        // Don't trust the count for the allocation, the entries follow.
+        let mut entries = Vec::with_capacity(std::cmp::min(count, 4096) as usize);
+        for _ in 0..count {
+            let mut head = [0u8; 9];
+            stream.read_exact(&mut head)?;
+            let kind = EntryKind::from_byte(head[0])?;
+            let size = u64::from_be_bytes(head[1..9].try_into().unwrap());
+            let path_len = match raw_names {
+                true => {
+                    stream.read_exact(&mut buf)?;
+                    u32::from_be_bytes(buf) as usize
+                }
+                false => {
+                    stream.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as usize
+                }
+            };
+            if path_len > MAX_WIRE_LEN {
+                return Err(ProtocolError::NameLength(path_len).into());
+            }
+            let mut path = vec![0u8; path_len];
+            stream.read_exact(&mut path)?;
+            entries.push(Entry {
+                kind,
+                path: String::from_utf8_lossy(&path).into_owned(),
+                size,
+            });
+        }
+        Ok(Self {
+            from: String::from_utf8_lossy(&from).into_owned(),
+            entries,
+        })
+    }
+}
+
+/// Longest `from` name that is sent
+const MAX_FROM_LEN: usize = 255;
+
+/// Answer to an incoming [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Receive everything
+    AcceptAll,
+    /// Receive nothing
+    RejectAll,
+    /// Receive only the files with these paths
+    Pick(HashSet<String>),
+}
+
+impl Decision {
+    /// Whether the file at `path` is wanted
+    pub fn accepts(&self, path: &str) -> bool {
+        match self {
+            Self::AcceptAll => true,
+            Self::RejectAll => false,
+            Self::Pick(paths) => paths.contains(path),
+        }
+    }
+}
+
+/// Progress over all files of a manifest and, for incoming ones, which
+/// files were accepted.
+///
+/// Without a manifest there is nothing to report.
+#[derive(Debug, Default)]
+pub(crate) struct Overall {
+    total_files: u64,
+    total_bytes: u64,
+    files: Cell<u64>,
+    bytes: Cell<u64>,
+    /// Accepted paths with their announced size (incoming manifests only)
+    accepted: Option<HashMap<String, u64>>,
+    /// Directories that hold an accepted entry, or all of them
+    accepted_dirs: HashSet<String>,
+}
+
+impl Overall {
+    pub(crate) fn new(manifest: &Manifest) -> Self {
+        Self {
+            total_files: manifest.files().count() as u64,
+            total_bytes: manifest.total_size(),
+            ..Self::default()
+        }
+    }
+
+    /// Progress over the files of an incoming manifest that `decision`
+    /// accepts.
+    pub(crate) fn incoming(manifest: &Manifest, decision: &Decision) -> Self {
+        let accepted: HashMap<_, _> = manifest
+            .entries
+            .iter()
+            .filter(|e| e.kind != EntryKind::Directory && decision.accepts(&e.path))
+            .map(|e| (e.path.clone(), e.size))
+            .collect();
+        // A picked entry brings the directories above it along.
+        let accepted_dirs = manifest
+            .entries
+            .iter()
+            .filter(|e| e.kind == EntryKind::Directory)
+            .filter(|e| {
+                decision.accepts(&e.path)
+                    || accepted.keys().any(|path| {
+                        path.strip_prefix(e.path.as_str())
+                            .is_some_and(|rest| rest.starts_with('/'))
+                    })
+            })
+            .map(|e| e.path.clone())
+            .collect();
+        let files = manifest.files().filter(|e| accepted.contains_key(&e.path));
+        let (total_files, total_bytes) = files.fold((0, 0), |(n, b), e| (n + 1, b + e.size));
+        Self {
+            total_files,
+            total_bytes,
+            accepted: Some(accepted),
+            accepted_dirs,
+            ..Self::default()
+        }
+    }
+
+    /// Whether incoming files were announced by a manifest
+    pub(crate) fn announced(&self) -> bool {
+        self.accepted.is_some()
+    }
+
+    /// Check an incoming file against the accepted part of the manifest.
+    ///
+    /// A hard link is announced with the size of its file, which is sent
+    /// in its place if the link is refused; a symbolic link has no size.
+    pub(crate) fn check(&self, path: &str, size: u64) -> Result<(), &'static str> {
+        match self.accepted.as_ref().map(|a| a.get(path)) {
+            None => Ok(()),
+            Some(None) => Err("not accepted"),
+            Some(Some(&announced)) if size > announced => Err("larger than announced"),
+            Some(Some(_)) => Ok(()),
+        }
+    }
+
+    /// Check an incoming directory against the accepted part of the
+    /// manifest.
+    pub(crate) fn check_dir(&self, path: &str) -> Result<(), &'static str> {
+        match self.accepted.is_none() || self.accepted_dirs.contains(path) {
+            true => Ok(()),
+            false => Err("not accepted"),
+        }
+    }
+
+    /// Count the next file of `size` bytes.
+    ///
+    /// Returns e.g. `file 3/120, 1.2 GB/8.4 GB` (bytes done before this
+    /// file), or `None` without a manifest.
+    pub(crate) fn advance(&self, size: u64) -> Option<String> {
+        if self.total_files == 0 {
+            return None;
+        }
+        let file = std::cmp::min(self.files.get() + 1, self.total_files);
+        let done = std::cmp::min(self.bytes.get(), self.total_bytes);
+        self.files.set(file);
+        self.bytes.set(self.bytes.get().saturating_add(size));
+        Some(format!(
+            "file {}/{}, {}/{}",
+            file,
+            self.total_files,
+            human_size(done),
+            human_size(self.total_bytes)
+        ))
+    }
+}
+
+/// Size in B, KB, MB, GB or TB (powers of 1024), one decimal place.
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", size, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem of `dir`.
+///
+/// `dir` doesn't need to exist yet, its closest existing ancestor is used.
+/// Returns `None` where this can't be determined.
+pub fn available_space(dir: &Path) -> Option<u64> {
+    let dir = dir.ancestors().find(|p| p.exists())?;
+    statvfs_available(dir)
+}
+
+#[cfg(all(unix, not(target_os = "android")))]
+fn statvfs_available(dir: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL terminated and `stat` is only read on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(all(unix, not(target_os = "android"))))]
+fn statvfs_available(_dir: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = Manifest {
+            from: "alice@laptop".to_owned(),
+            entries: vec![
+                Entry {
+                    kind: EntryKind::Directory,
+                    path: "proj".to_owned(),
+                    size: 0,
+                },
+                Entry {
+                    kind: EntryKind::File,
+                    path: "proj/a.txt".to_owned(),
+                    size: 3,
+                },
+                Entry {
+                    kind: EntryKind::Link,
+                    path: "proj/b.txt".to_owned(),
+                    size: 0,
+                },
+            ],
+        };
+        for raw_names in [false, true] {
+            let mut wire = Vec::new();
+            manifest.write(&mut wire, raw_names).unwrap();
+            assert_eq!(&wire[..5], b":man:");
+            assert_eq!(
+                Manifest::read(&mut &wire[5..], raw_names).unwrap(),
+                manifest
+            );
+        }
+        assert_eq!(manifest.files().count(), 1);
+        assert_eq!(manifest.total_size(), 3);
+    }
+
+    #[test]
+    fn overall_progress_counts_files_and_bytes() {
+        let manifest = Manifest {
+            from: String::new(),
+            entries: vec![
+                Entry {
+                    kind: EntryKind::File,
+                    path: "a".to_owned(),
+                    size: 1024,
+                },
+                Entry {
+                    kind: EntryKind::File,
+                    path: "b".to_owned(),
+                    size: 3 * 1024,
+                },
+            ],
+        };
+        let overall = Overall::new(&manifest);
+        assert_eq!(overall.advance(1024).unwrap(), "file 1/2, 0 B/4.0 KB");
+        assert_eq!(
+            overall.advance(3 * 1024).unwrap(),
+            "file 2/2, 1.0 KB/4.0 KB"
+        );
+        assert_eq!(Overall::default().advance(10), None);
+    }
+
+    #[test]
+    fn incoming_files_are_checked_against_the_decision() {
+        let entry = |kind, path: &str, size| Entry {
+            kind,
+            path: path.to_owned(),
+            size,
+        };
+        let manifest = Manifest {
+            from: String::new(),
+            entries: vec![
+                entry(EntryKind::Directory, "d", 0),
+                entry(EntryKind::File, "d/a", 10),
+                entry(EntryKind::File, "d/b", 20),
+                entry(EntryKind::Link, "d/c", 0),
+                entry(EntryKind::Directory, "e", 0),
+            ],
+        };
+        let picked = Decision::Pick(HashSet::from(["d/b".to_owned(), "d/c".to_owned()]));
+        let overall = Overall::incoming(&manifest, &picked);
+        assert!(overall.announced());
+        assert_eq!(overall.check("d/a", 10), Err("not accepted"));
+        assert_eq!(overall.check("d/b", 20), Ok(()));
+        assert_eq!(overall.check("d/b", 21), Err("larger than announced"));
+        assert_eq!(overall.check("d/c", 1), Err("larger than announced"));
+        assert_eq!(overall.check("d/x", 0), Err("not accepted"));
+        assert_eq!(overall.advance(20).unwrap(), "file 1/1, 0 B/20 B");
+        // `d` holds a picked file, `e` is empty.
+        assert_eq!(overall.check_dir("d"), Ok(()));
+        assert_eq!(overall.check_dir("e"), Err("not accepted"));
+
+        let rejected = Overall::incoming(&manifest, &Decision::RejectAll);
+        assert_eq!(rejected.check("d/a", 10), Err("not accepted"));
+        assert_eq!(rejected.check("d/c", 0), Err("not accepted"));
+        assert_eq!(rejected.check_dir("d"), Err("not accepted"));
+        assert_eq!(Overall::default().check("d/a", 10), Ok(()));
+        assert_eq!(Overall::default().check_dir("e"), Ok(()));
+
+        let all = Overall::incoming(&manifest, &Decision::AcceptAll);
+        assert_eq!(all.check_dir("e"), Ok(()));
+    }
+
+    #[cfg(all(unix, not(target_os = "android")))]
+    #[test]
+    fn available_space_of_missing_dir_uses_parent() {
+        let dir = std::env::temp_dir().join("fs-share-no-such-dir/below");
+        assert!(available_space(&dir).is_some());
+    }
+}
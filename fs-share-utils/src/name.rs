@@ -0,0 +1,406 @@
+//! # Names on the Wire
+//!
+//! File names and `/` separated relative paths as carried by `:fff:`,
+//! `:ffr:`, `:dir:` and `:lnk:` frames.
+//!
+//! ## Format
+//!
+//! With [`Features::RAW_NAMES`](crate::protocol::Features::RAW_NAMES), a
+//! name is announced by its encoding and a 32-bit length:
+//! ```text
+//! encoding(u8) | name_len(u32)
+//! ```
+//!
+//! | encoding | name bytes                                         |
+//! |----------|----------------------------------------------------|
+//! | 0        | UTF-8                                              |
+//! | 1        | raw Unix bytes (`OsStrExt::as_bytes`)              |
+//! | 2        | UTF-16LE code units (`OsStrExt::encode_wide`)      |
+//!
+//! Names that are valid Unicode are always sent as UTF-8; the other
+//! encodings only carry names that aren't. Names longer than
+//! [`MAX_WIRE_LEN`] are neither sent nor accepted.
+//!
+//! The wider length doesn't let longer files through: receivers refuse
+//! components over 255 bytes and paths over 4096 bytes (the limits of
+//! common filesystems, see `tf`) one file at a time, and the sender reports
+//! the reason. It only keeps a path that grows when encoded, such as an
+//! escaped or UTF-16 one, from ending the session.
+//!
+//! Without the feature the length is a `name_len(u16)` and the name is
+//! UTF-8. A name that isn't valid Unicode is then sent lossily (with a
+//! note), and a name that doesn't fit is a [`NameTooLong`] error.
+//!
+//! ## Decoding
+//!
+//! A component is restored exactly when the receiving platform can hold
+//! it: raw bytes on Unix, UTF-16 on Windows. Otherwise it is escaped:
+//! bytes that aren't UTF-8 become `%XX` and unpaired surrogates `%uXXXX`.
+
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fmt,
+    io::{Read, Write},
+};
+
+use crate::error::{NameTooLong, ProtocolError};
+
+/// Longest name sent or accepted with 32-bit lengths, in bytes.
+pub(crate) const MAX_WIRE_LEN: usize = 1 << 20;
+
+/// Separator of relative paths, in every encoding.
+const SEPARATOR: u8 = b'/';
+
+/// How the bytes of a [`WireName`] are to be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Utf8,
+    /// Raw bytes of a Unix name
+    Unix,
+    /// UTF-16LE code units of a Windows name
+    Utf16,
+}
+
+impl Encoding {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Utf8 => 0,
+            Self::Unix => 1,
+            Self::Utf16 => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, ProtocolError> {
+        match byte {
+            0 => Ok(Self::Utf8),
+            1 => Ok(Self::Unix),
+            2 => Ok(Self::Utf16),
+            v => Err(ProtocolError::UnknownEncoding(v)),
+        }
+    }
+}
+
+/// A name or relative path as it is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct WireName {
+    encoding: Encoding,
+    bytes: Vec<u8>,
+}
+
+impl WireName {
+    pub(crate) fn utf8(name: &str) -> Self {
+        Self {
+            encoding: Encoding::Utf8,
+            bytes: name.as_bytes().to_vec(),
+        }
+    }
+
+    /// Join `parts` with `/`, in an encoding that keeps them intact.
+    pub(crate) fn join<'a>(parts: impl IntoIterator<Item = &'a OsStr>) -> Self {
+        let parts: Vec<&OsStr> = parts.into_iter().collect();
+        match parts.iter().map(|p| p.to_str()).collect::<Option<Vec<_>>>() {
+            Some(parts) => Self::utf8(&parts.join("/")),
+            None => encode(&parts),
+        }
+    }
+
+    /// Length of the name on the wire, in bytes
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// The name for display, with invalid parts replaced by `U+FFFD`.
+    ///
+    /// Both sides use it as the path of manifest entries.
+    pub(crate) fn lossy(&self) -> String {
+        match self.encoding {
+            Encoding::Utf8 | Encoding::Unix => String::from_utf8_lossy(&self.bytes).into_owned(),
+            Encoding::Utf16 => String::from_utf16_lossy(&units(&self.bytes)),
+        }
+    }
+
+    /// The `/` separated components, decoded for this platform.
+    pub(crate) fn components(&self) -> Vec<OsString> {
+        match self.encoding {
+            // A peer may claim UTF-8 for bytes that aren't.
+            Encoding::Utf8 | Encoding::Unix => self
+                .bytes
+                .split(|&b| b == SEPARATOR)
+                .map(decode_bytes)
+                .collect(),
+            Encoding::Utf16 => units(&self.bytes)
+                .split(|&u| u == u16::from(SEPARATOR))
+                .map(decode_wide)
+                .collect(),
+        }
+    }
+
+    /// The name as a peer without `RAW_NAMES` gets it.
+    pub(crate) fn for_peer(&self, raw: bool) -> Cow<'_, Self> {
+        if raw || self.encoding == Encoding::Utf8 {
+            return Cow::Borrowed(self);
+        }
+        let lossy = Self::utf8(&self.lossy());
+        println!(
+            "Peer can't receive names that aren't UTF-8, sending {} instead",
+            lossy
+        );
+        Cow::Owned(lossy)
+    }
+
+    /// Write the length prefix, `encoding(u8) | len(u32)` with `raw` names
+    /// and `len(u16)` without.
+    pub(crate) fn write_prefix<W: Write>(&self, stream: &mut W, raw: bool) -> anyhow::Result<()> {
+        let max = match raw {
+            true => MAX_WIRE_LEN,
+            false => u16::MAX as usize,
+        };
+        if self.len() > max {
+            return Err(NameTooLong {
+                name: self.lossy(),
+                len: self.len(),
+                max,
+            }
+            .into());
+        }
+        if raw {
+            stream.write_all(&[self.encoding.to_byte()])?;
+            stream.write_all(&(self.len() as u32).to_be_bytes())?;
+        } else {
+            stream.write_all(&(self.len() as u16).to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Write the name itself, after its prefix and any fields in between.
+    pub(crate) fn write_name<W: Write>(&self, stream: &mut W) -> anyhow::Result<()> {
+        stream.write_all(&self.bytes)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for WireName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.lossy())
+    }
+}
+
+/// Length prefix of a name whose bytes come later in the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Prefix {
+    encoding: Encoding,
+    len: usize,
+}
+
+impl Prefix {
+    /// Read a prefix written by [`WireName::write_prefix`].
+    pub(crate) fn read<R: Read>(stream: &mut R, raw: bool) -> anyhow::Result<Self> {
+        if !raw {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len)?;
+            return Ok(Self {
+                encoding: Encoding::Utf8,
+                len: u16::from_be_bytes(len) as usize,
+            });
+        }
+        let mut head = [0u8; 5];
+        stream.read_exact(&mut head)?;
+        let len = u32::from_be_bytes(head[1..].try_into().unwrap()) as usize;
+        if len > MAX_WIRE_LEN {
+            return Err(ProtocolError::NameLength(len).into());
+        }
+        Ok(Self {
+            encoding: Encoding::from_byte(head[0])?,
+            len,
+        })
+    }
+
+    /// Read the name this prefix announced.
+    pub(crate) fn read_name<R: Read>(self, stream: &mut R) -> anyhow::Result<WireName> {
+        let mut bytes = vec![0u8; self.len];
+        stream.read_exact(&mut bytes)?;
+        Ok(WireName {
+            encoding: self.encoding,
+            bytes,
+        })
+    }
+}
+
+/// UTF-16LE code units, a trailing odd byte is dropped.
+fn units(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect()
+}
+
+#[cfg(unix)]
+fn encode(parts: &[&OsStr]) -> WireName {
+    use std::os::unix::ffi::OsStrExt;
+    WireName {
+        encoding: Encoding::Unix,
+        bytes: parts
+            .iter()
+            .map(|p| p.as_bytes())
+            .collect::<Vec<_>>()
+            .join(&SEPARATOR),
+    }
+}
+
+#[cfg(windows)]
+fn encode(parts: &[&OsStr]) -> WireName {
+    use std::os::windows::ffi::OsStrExt;
+    let mut bytes = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            bytes.extend_from_slice(&u16::from(SEPARATOR).to_le_bytes());
+        }
+        bytes.extend(part.encode_wide().flat_map(u16::to_le_bytes));
+    }
+    WireName {
+        encoding: Encoding::Utf16,
+        bytes,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn encode(parts: &[&OsStr]) -> WireName {
+    WireName::utf8(
+        &parts
+            .iter()
+            .map(|p| p.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+    )
+}
+
+#[cfg(unix)]
+fn decode_bytes(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes).to_owned()
+}
+
+#[cfg(not(unix))]
+fn decode_bytes(bytes: &[u8]) -> OsString {
+    escape_bytes(bytes).into()
+}
+
+#[cfg(windows)]
+fn decode_wide(units: &[u16]) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    OsString::from_wide(units)
+}
+
+#[cfg(not(windows))]
+fn decode_wide(units: &[u16]) -> OsString {
+    escape_wide(units).into()
+}
+
+/// `bytes` as UTF-8, every invalid byte written as `%XX`.
+#[cfg_attr(unix, allow(dead_code))]
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        escaped.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+/// UTF-16 `units` as a string, every unpaired surrogate written as `%uXXXX`.
+#[cfg_attr(windows, allow(dead_code))]
+fn escape_wide(units: &[u16]) -> String {
+    char::decode_utf16(units.iter().copied())
+        .map(|c| match c {
+            Ok(c) => c.to_string(),
+            Err(err) => format!("%u{:04X}", err.unpaired_surrogate()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(name: &WireName, raw: bool) -> WireName {
+        let mut wire = Vec::new();
+        name.write_prefix(&mut wire, raw).unwrap();
+        name.write_name(&mut wire).unwrap();
+        let mut wire = wire.as_slice();
+        let prefix = Prefix::read(&mut wire, raw).unwrap();
+        let read = prefix.read_name(&mut wire).unwrap();
+        assert!(wire.is_empty());
+        read
+    }
+
+    #[test]
+    fn unicode_names_are_sent_as_utf8() {
+        let name = WireName::join([OsStr::new("dir"), OsStr::new("ünï.txt")]);
+        assert_eq!(name, WireName::utf8("dir/ünï.txt"));
+        assert_eq!(round_trip(&name, true), name);
+        assert_eq!(round_trip(&name, false), name);
+        assert_eq!(
+            name.components(),
+            [OsString::from("dir"), OsString::from("ünï.txt")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_unix_names_are_restored_exactly() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let part = OsStr::from_bytes(b"caf\xe9.txt");
+        let name = WireName::join([OsStr::new("dir"), part]);
+        assert_eq!(name.encoding, Encoding::Unix);
+
+        let read = round_trip(&name, true);
+        assert_eq!(read.components(), [OsStr::new("dir"), part]);
+        assert_eq!(read.lossy(), "dir/caf\u{fffd}.txt");
+
+        // Without raw names the peer gets the lossy form.
+        let lossy = name.for_peer(false);
+        assert_eq!(lossy.lossy(), "dir/caf\u{fffd}.txt");
+        assert_eq!(round_trip(&lossy, false), *lossy);
+    }
+
+    #[test]
+    fn unrepresentable_names_are_escaped() {
+        assert_eq!(escape_bytes(b"caf\xe9/\xff.txt"), "caf%E9/%FF.txt");
+        assert_eq!(escape_wide(&[0x61, 0xd800, 0x62]), "a%uD800b");
+
+        let wide = WireName {
+            encoding: Encoding::Utf16,
+            bytes: [0x61u16, 0xdc00, 0x2f, 0x62]
+                .iter()
+                .flat_map(|u| u.to_le_bytes())
+                .collect(),
+        };
+        assert_eq!(wide.components().len(), 2);
+        #[cfg(not(windows))]
+        assert_eq!(wide.components()[0], "a%uDC00");
+    }
+
+    #[test]
+    fn lengths_that_dont_fit_are_errors() {
+        let name = WireName::utf8(&"x/".repeat(40_000));
+        let err = name.write_prefix(&mut Vec::new(), false).unwrap_err();
+        assert!(err.downcast_ref::<NameTooLong>().is_some());
+        assert_eq!(round_trip(&name, true), name);
+
+        let too_long = WireName::utf8(&"x".repeat(MAX_WIRE_LEN + 1));
+        assert!(too_long.write_prefix(&mut Vec::new(), true).is_err());
+
+        let mut wire = vec![0u8];
+        wire.extend_from_slice(&u32::MAX.to_be_bytes());
+        let err = Prefix::read(&mut wire.as_slice(), true).unwrap_err();
+        assert!(err.downcast_ref::<ProtocolError>().is_some());
+    }
+}
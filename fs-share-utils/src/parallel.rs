@@ -0,0 +1,335 @@
+//! # Parallel Transfers
+//!
+//! Splitting a large file into byte ranges that are sent concurrently over
+//! the control connection and the session's data connections (see
+//! [`Features::PARALLEL`](crate::protocol::Features::PARALLEL)).
+//!
+//! ## Format
+//!
+//! Range `0` goes over the control connection, range `i` over data
+//! connection `i - 1`. Each range starts with its position, followed by
+//! its bytes (framed as chunks if compression was negotiated):
+//! ```text
+//! :rng: | start(u64) | len(u64) | range_bytes...
+//! ```
+//!
+//! Both sides compute the ranges with [`split_ranges`], so the receiver
+//! checks every `:rng:` header against the range it expects there. Ranges
+//! are written at their offsets with positioned writes.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    thread::ScopedJoinHandle,
+    time::Duration,
+};
+
+use anyhow::Context;
+
+use crate::{
+    compress::{body_reader, body_writer},
+    error::ProtocolError,
+    zerocopy::{self, PlainSocket},
+};
+
+/// Files smaller than this are sent over the control connection only
+pub const PARALLEL_MIN_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Smallest range worth a connection of its own
+const MIN_RANGE_SIZE: u64 = 4 * 1024 * 1024;
+
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// How often the progress bar is refreshed while waiting for other ranges
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A `(start, len)` byte range of a file.
+pub type Range = (u64, u64);
+
+/// Number of ranges to split `remaining` bytes into, given `data_streams`
+/// data connections. `0` means "send inline, without ranges".
+pub fn range_count(remaining: u64, data_streams: usize) -> u8 {
+    if data_streams == 0 || remaining < PARALLEL_MIN_SIZE {
+        return 0;
+    }
+    let parts = std::cmp::min((data_streams + 1) as u64, remaining / MIN_RANGE_SIZE);
+    std::cmp::min(parts, u8::MAX as u64) as u8
+}
+
+/// Split `offset..total` into `parts` contiguous ranges.
+///
+/// The last range takes the remainder.
+pub fn split_ranges(offset: u64, total: u64, parts: u8) -> Vec<Range> {
+    let parts = parts as u64;
+    let len = (total - offset) / parts;
+    (0..parts)
+        .map(|i| {
+            let start = offset + i * len;
+            match i + 1 == parts {
+                true => (start, total - start),
+                false => (start, len),
+            }
+        })
+        .collect()
+}
+
+/// End of the part of the file that is known to be written without gaps.
+///
+/// `written[i]` is the number of bytes of `ranges[i]` written so far.
+pub fn contiguous_end(offset: u64, ranges: &[Range], written: &[AtomicU64]) -> u64 {
+    let mut end = offset;
+    for (&(start, len), written) in ranges.iter().zip(written) {
+        let written = written.load(Ordering::Relaxed);
+        if start != end {
+            break;
+        }
+        end += written;
+        if written < len {
+            break;
+        }
+    }
+    end
+}
+
+/// Send `ranges` of the file at `path`, the first over `stream` and the
+/// others over `data`.
+///
+/// `progress` is called with the total number of bytes sent so far.
+pub fn send_ranges<S: Write + Send + PlainSocket>(
+    path: &Path,
+    stream: &mut S,
+    data: &mut [S],
+    ranges: &[Range],
+    chunked: bool,
+    compress: bool,
+    progress: &dyn Fn(u64),
+) -> anyhow::Result<()> {
+    let sent = AtomicU64::new(0);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = ranges[1..]
+            .iter()
+            .zip(data.iter_mut())
+            .map(|(&range, data)| {
+                let sent = &sent;
+                scope.spawn(move || send_range(path, data, range, chunked, compress, sent, &|| {}))
+            })
+            .collect();
+
+        let update = || progress(sent.load(Ordering::Relaxed));
+        let own = send_range(path, stream, ranges[0], chunked, compress, &sent, &update);
+        join_ranges(own, handles, &update)
+    })
+}
+
+/// Receive `ranges` into `file`, the first from `stream` and the others
+/// from `data`.
+///
+/// `written[i]` tracks the bytes of `ranges[i]` written so far, so a failed
+/// transfer knows what can be resumed (see [`contiguous_end`]).
+pub fn receive_ranges<S: Read + Send + PlainSocket>(
+    file: &File,
+    stream: &mut S,
+    data: &mut [S],
+    ranges: &[Range],
+    chunked: bool,
+    written: &[AtomicU64],
+    progress: &dyn Fn(u64),
+) -> anyhow::Result<()> {
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = ranges[1..]
+            .iter()
+            .zip(data.iter_mut())
+            .zip(&written[1..])
+            .map(|((&range, data), written)| {
+                scope.spawn(move || receive_range(file, data, range, chunked, written, &|| {}))
+            })
+            .collect();
+
+        let update = || progress(written.iter().map(|n| n.load(Ordering::Relaxed)).sum());
+        let own = receive_range(file, stream, ranges[0], chunked, &written[0], &update);
+        join_ranges(own, handles, &update)
+    })
+}
+
+/// Wait for the other ranges while refreshing the progress, then return the
+/// first error.
+fn join_ranges(
+    own: anyhow::Result<()>,
+    handles: Vec<ScopedJoinHandle<'_, anyhow::Result<()>>>,
+    update: &dyn Fn(),
+) -> anyhow::Result<()> {
+    while handles.iter().any(|h| !h.is_finished()) {
+        std::thread::sleep(PROGRESS_INTERVAL);
+        update();
+    }
+    update();
+    own?;
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("Transfer thread panicked"))??;
+    }
+    Ok(())
+}
+
+fn send_range<S: Write + PlainSocket>(
+    path: &Path,
+    stream: &mut S,
+    (start, len): Range,
+    chunked: bool,
+    compress: bool,
+    sent: &AtomicU64,
+    update: &dyn Fn(),
+) -> anyhow::Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    file.seek(SeekFrom::Start(start))?;
+
+    stream.write_all(b":rng:")?;
+    stream.write_all(&start.to_be_bytes())?;
+    stream.write_all(&len.to_be_bytes())?;
+    stream.flush()?;
+
+    let mut reported = 0;
+    if !chunked
+        && let Some(socket) = stream.plain_socket()
+        && zerocopy::send_file(&file, start, len, socket, &mut |n| {
+            sent.fetch_add(n - reported, Ordering::Relaxed);
+            reported = n;
+            update();
+        })?
+    {
+        return Ok(());
+    }
+
+    let mut body = body_writer(&mut *stream, chunked, compress);
+    let mut reader = file.take(len);
+    let mut buffer = vec![0u8; std::cmp::min(len as usize, BUFFER_SIZE)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            anyhow::bail!("File shrank while sending: {}", path.display());
+        }
+        body.write_all(&buffer[..n])?;
+        remaining -= n as u64;
+        sent.fetch_add(n as u64, Ordering::Relaxed);
+        update();
+    }
+    body.flush()?;
+    Ok(())
+}
+
+fn receive_range<S: Read + PlainSocket>(
+    file: &File,
+    stream: &mut S,
+    expected: Range,
+    chunked: bool,
+    written: &AtomicU64,
+    update: &dyn Fn(),
+) -> anyhow::Result<()> {
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    if &marker != b":rng:" {
+        return Err(ProtocolError::UnknownMarker(marker).into());
+    }
+    let mut buf = [0u8; 16];
+    stream.read_exact(&mut buf)?;
+    let start = u64::from_be_bytes(buf[..8].try_into().unwrap());
+    let len = u64::from_be_bytes(buf[8..].try_into().unwrap());
+    if (start, len) != expected {
+        anyhow::bail!(
+            "Peer sent range {}+{}, expected {}+{}",
+            start,
+            len,
+            expected.0,
+            expected.1
+        );
+    }
+
+    if !chunked
+        && let Some(socket) = stream.plain_socket()
+        && zerocopy::receive_file(socket, file, start, len, &mut |n| {
+            written.store(n, Ordering::Relaxed);
+            update();
+        })?
+    {
+        return Ok(());
+    }
+
+    let mut body = body_reader(&mut *stream, chunked, len);
+    let mut buffer = vec![0u8; std::cmp::min(len as usize, BUFFER_SIZE)];
+    let mut pos = start;
+    let mut remaining = len;
+    while remaining > 0 {
+        let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+        let n = body.read(&mut buffer[..to_read])?;
+        if n == 0 {
+            anyhow::bail!("Unexpected EOF");
+        }
+        write_all_at(file, &buffer[..n], pos)?;
+        pos += n as u64;
+        remaining -= n as u64;
+        written.fetch_add(n as u64, Ordering::Relaxed);
+        update();
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], pos: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &File, mut buf: &[u8], mut pos: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, pos)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_cover_the_remaining_bytes() {
+        assert_eq!(range_count(PARALLEL_MIN_SIZE - 1, 4), 0);
+        assert_eq!(range_count(1 << 30, 0), 0);
+        assert_eq!(range_count(1 << 30, 4), 5);
+        // Never split into ranges smaller than MIN_RANGE_SIZE
+        assert_eq!(range_count(3 * MIN_RANGE_SIZE, 8), 3);
+
+        let ranges = split_ranges(10, 10 + 3 * MIN_RANGE_SIZE + 2, 3);
+        assert_eq!(
+            ranges,
+            vec![
+                (10, MIN_RANGE_SIZE),
+                (10 + MIN_RANGE_SIZE, MIN_RANGE_SIZE),
+                (10 + 2 * MIN_RANGE_SIZE, MIN_RANGE_SIZE + 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn contiguous_end_stops_at_first_gap() {
+        let ranges = split_ranges(0, 300, 3);
+        let written = [AtomicU64::new(100), AtomicU64::new(40), AtomicU64::new(100)];
+        assert_eq!(contiguous_end(0, &ranges, &written), 140);
+
+        written[1].store(100, Ordering::Relaxed);
+        assert_eq!(contiguous_end(0, &ranges, &written), 300);
+
+        written[0].store(0, Ordering::Relaxed);
+        assert_eq!(contiguous_end(0, &ranges, &written), 0);
+    }
+}
@@ -0,0 +1,560 @@
+//! # Protocol Negotiation
+//!
+//! Version and feature negotiation performed right after the handshake line.
+//!
+//! ## Handshake
+//!
+//! ```text
+//! sender   -> fs-share:v1.1\n
+//! receiver -> :accept:
+//! sender   -> :hlo: | min_version(u16) | max_version(u16) | features(u64)
+//! receiver -> :hlo: | min_version(u16) | max_version(u16) | features(u64)
+//! ```
+//!
+//! Both sides then use the highest version in both ranges and the features
+//! both of them advertise. Versions are encoded as `major << 8 | minor`.
+//!
+//! ## Confirmation
+//!
+//! The hellos are exchanged before the stream is upgraded (see `--secure`
+//! in the CLI), so anyone on the path could change them, e.g. to turn off
+//! checksums. Once upgraded, both sides send what they negotiated and end
+//! the session if the peer's differs:
+//! ```text
+//! sender   -> :cap: | version(u16) | features(u64)
+//! receiver -> :cap: | version(u16) | features(u64)
+//! ```
+//!
+//! A 1.0 session has no hello and nothing to confirm.
+//!
+//! ## Sessions
+//!
+//! With [`Features::PARALLEL`], the sender asks for extra data connections
+//! once the control connection is upgraded, and the receiver answers with
+//! how many it accepts and a random session id:
+//! ```text
+//! sender   -> :par: | count(u8)
+//! receiver -> :sid: | count(u8) | session_id(16)
+//! ```
+//!
+//! Every data connection then starts like a new one, but sends `:att:`
+//! instead of a hello so the receiver attaches it to the session:
+//! ```text
+//! sender   -> fs-share:v1.1\n
+//! receiver -> :accept:
+//! sender   -> :att: | session_id(16)
+//! ```
+//!
+//! A 1.0 receiver answers `fs-share:v1.1\n` with `:reject:`. The sender then
+//! reconnects with `fs-share:v1.0\n` and both sides use
+//! [`Capabilities::LEGACY`]: bare `:fff:` frames without replies, checksums,
+//! directories or error frames. A 1.0 sender is accepted the same way.
+//! Neither side falls back when the stream is to be secured (see
+//! [`Confirmation`](#confirmation)): a 1.0 peer can't secure it, so the
+//! `:reject:` came from someone else.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+    ops::{BitAnd, BitOr},
+};
+
+use crate::error::ProtocolError;
+
+/// Handshake line of the original protocol
+pub(crate) const HANDSHAKE_V1_0: &[u8; 14] = b"fs-share:v1.0\n";
+
+/// Handshake line of peers that negotiate with a hello
+pub(crate) const HANDSHAKE_V1_1: &[u8; 14] = b"fs-share:v1.1\n";
+
+/// Protocol 1.0, without hello
+pub const V1_0: u16 = 0x0100;
+
+/// Protocol 1.1: per-file replies, error frames and mtime in file headers
+pub const V1_1: u16 = 0x0101;
+
+/// Lowest version this build negotiates in a hello
+const MIN_VERSION: u16 = V1_1;
+
+/// Highest version this build negotiates in a hello
+const MAX_VERSION: u16 = V1_1;
+
+/// Optional protocol features, advertised as a bit set.
+///
+/// Unknown bits from newer peers are dropped when intersecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// `:dir:` and `:ffr:` frames
+    pub const DIRECTORIES: Self = Self(1 << 0);
+    /// Non-zero offsets in `:off:` replies
+    pub const RESUME: Self = Self(1 << 1);
+    /// BLAKE3 trailer after every file
+    pub const CHECKSUM: Self = Self(1 << 2);
+    /// File data sent as LZ4 chunks (see `compress`)
+    pub const COMPRESSION: Self = Self(1 << 3);
+    /// Extra data connections attached to the session
+    pub const PARALLEL: Self = Self(1 << 4);
+    /// Permissions, timestamps and ownership in file headers (see `attrs`)
+    pub const METADATA: Self = Self(1 << 5);
+    /// `:lnk:` frames for symbolic and hard links
+    pub const LINKS: Self = Self(1 << 6);
+    /// `:man:` frame before the files (see `manifest`)
+    pub const MANIFEST: Self = Self(1 << 7);
+    /// Names in their OS encoding with 32-bit lengths (see `name`)
+    pub const RAW_NAMES: Self = Self(1 << 8);
+    /// Every feature this build supports
+    pub const ALL: Self = Self(
+        Self::DIRECTORIES.0
+            | Self::RESUME.0
+            | Self::CHECKSUM.0
+            | Self::COMPRESSION.0
+            | Self::PARALLEL.0
+            | Self::METADATA.0
+            | Self::LINKS.0
+            | Self::MANIFEST.0
+            | Self::RAW_NAMES.0,
+    );
+
+    const NAMES: [(Self, &'static str); 9] = [
+        (Self::DIRECTORIES, "directories"),
+        (Self::RESUME, "resume"),
+        (Self::CHECKSUM, "checksum"),
+        (Self::COMPRESSION, "compression"),
+        (Self::PARALLEL, "parallel"),
+        (Self::METADATA, "metadata"),
+        (Self::LINKS, "links"),
+        (Self::MANIFEST, "manifest"),
+        (Self::RAW_NAMES, "raw-names"),
+    ];
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Keep only the bits this build knows.
+    pub const fn from_bits_truncate(bits: u64) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These features without the ones in `other`.
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Features {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut names = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .peekable();
+        if names.peek().is_none() {
+            return write!(f, "none");
+        }
+        for (i, name) in names.enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// What both peers agreed on for a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Capabilities {
+    /// A 1.0 peer: no hello, no features
+    pub const LEGACY: Self = Self {
+        version: V1_0,
+        features: Features::NONE,
+    };
+
+    /// Everything this build supports
+    pub const LATEST: Self = Self {
+        version: MAX_VERSION,
+        features: Features::ALL,
+    };
+
+    /// Whether the session uses the original 1.0 frames
+    pub fn is_legacy(&self) -> bool {
+        self.version < V1_1
+    }
+
+    pub fn has(&self, feature: Features) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "v{}.{} (features: {})",
+            self.version >> 8,
+            self.version & 0xff,
+            self.features
+        )
+    }
+}
+
+/// Confirm `caps` over the upgraded stream (see the module docs).
+pub(crate) fn confirm_capabilities<S: Read + Write>(
+    stream: &mut S,
+    caps: Capabilities,
+) -> anyhow::Result<()> {
+    if caps.is_legacy() {
+        return Ok(());
+    }
+    stream.write_all(b":cap:")?;
+    stream.write_all(&caps.version.to_be_bytes())?;
+    stream.write_all(&caps.features.bits().to_be_bytes())?;
+    stream.flush()?;
+
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    if &marker != b":cap:" {
+        return Err(ProtocolError::UnknownMarker(marker).into());
+    }
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf)?;
+    let peer = Capabilities {
+        version: u16::from_be_bytes([buf[0], buf[1]]),
+        features: Features(u64::from_be_bytes(buf[2..].try_into().unwrap())),
+    };
+    if peer != caps {
+        anyhow::bail!(
+            "Negotiation was tampered with: negotiated {}, peer negotiated {}",
+            caps,
+            peer
+        );
+    }
+    Ok(())
+}
+
+/// Most data connections a session accepts
+pub const MAX_DATA_STREAMS: u8 = 16;
+
+/// Random identifier that data connections present to join a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SessionId([u8; 16]);
+
+impl SessionId {
+    fn random() -> anyhow::Result<Self> {
+        let mut id = [0u8; 16];
+        getrandom::fill(&mut id)
+            .map_err(|e| anyhow::anyhow!("Failed to generate session id: {}", e))?;
+        Ok(Self(id))
+    }
+}
+
+/// First frame of a connection after `:accept:`.
+pub(crate) enum Opening {
+    /// A new session with the negotiated capabilities
+    Hello(Capabilities),
+    /// A data connection for an existing session
+    Attach(SessionId),
+}
+
+/// One side's `:hlo:` frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Hello {
+    min_version: u16,
+    max_version: u16,
+    features: Features,
+}
+
+impl Hello {
+    fn local(features: Features) -> Self {
+        Self {
+            min_version: MIN_VERSION,
+            max_version: MAX_VERSION,
+            features,
+        }
+    }
+}
+
+fn write_hello<S: Write>(stream: &mut S, hello: Hello) -> anyhow::Result<()> {
+    stream.write_all(b":hlo:")?;
+    stream.write_all(&hello.min_version.to_be_bytes())?;
+    stream.write_all(&hello.max_version.to_be_bytes())?;
+    stream.write_all(&hello.features.bits().to_be_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_hello<S: Read>(stream: &mut S) -> anyhow::Result<Hello> {
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    if &marker != b":hlo:" {
+        return Err(ProtocolError::UnknownMarker(marker).into());
+    }
+    read_hello_body(stream)
+}
+
+/// Read a hello whose marker was already consumed.
+fn read_hello_body<S: Read>(stream: &mut S) -> anyhow::Result<Hello> {
+    let mut buf = [0u8; 12];
+    stream.read_exact(&mut buf)?;
+    Ok(Hello {
+        min_version: u16::from_be_bytes([buf[0], buf[1]]),
+        max_version: u16::from_be_bytes([buf[2], buf[3]]),
+        features: Features::from_bits_truncate(u64::from_be_bytes(buf[4..].try_into().unwrap())),
+    })
+}
+
+/// Highest common version and shared features of two hellos.
+fn negotiate(local: Hello, remote: Hello) -> anyhow::Result<Capabilities> {
+    let version = std::cmp::min(local.max_version, remote.max_version);
+    if version < std::cmp::max(local.min_version, remote.min_version) {
+        anyhow::bail!(
+            "No common protocol version (local {:#06x}-{:#06x}, peer {:#06x}-{:#06x})",
+            local.min_version,
+            local.max_version,
+            remote.min_version,
+            remote.max_version
+        );
+    }
+    Ok(Capabilities {
+        version,
+        features: local.features & remote.features,
+    })
+}
+
+/// Sender side of the hello exchange (after `:accept:`).
+pub(crate) fn sender_hello<S: Read + Write>(
+    stream: &mut S,
+    features: Features,
+) -> anyhow::Result<Capabilities> {
+    let local = Hello::local(features);
+    write_hello(stream, local)?;
+    negotiate(local, read_hello(stream)?)
+}
+
+/// Receiver side of the first frame (after `:accept:`).
+///
+/// Answers a hello with ours; an `:att:` frame is returned as is.
+pub(crate) fn receiver_opening<S: Read + Write>(
+    stream: &mut S,
+    features: Features,
+) -> anyhow::Result<Opening> {
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    match &marker {
+        b":hlo:" => {
+            let remote = read_hello_body(stream)?;
+            let local = Hello::local(features);
+            write_hello(stream, local)?;
+            Ok(Opening::Hello(negotiate(local, remote)?))
+        }
+        b":att:" => {
+            let mut id = [0u8; 16];
+            stream.read_exact(&mut id)?;
+            Ok(Opening::Attach(SessionId(id)))
+        }
+        _ => Err(ProtocolError::UnknownMarker(marker).into()),
+    }
+}
+
+/// Join the session `id` with a data connection (after `:accept:`).
+pub(crate) fn sender_attach<S: Write>(stream: &mut S, id: SessionId) -> anyhow::Result<()> {
+    stream.write_all(b":att:")?;
+    stream.write_all(&id.0)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Ask for `count` data connections.
+///
+/// Returns how many the receiver accepted and the session they join.
+pub(crate) fn sender_session<S: Read + Write>(
+    stream: &mut S,
+    count: u8,
+) -> anyhow::Result<(u8, SessionId)> {
+    stream.write_all(b":par:")?;
+    stream.write_all(&[count])?;
+    stream.flush()?;
+
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    if &marker != b":sid:" {
+        return Err(ProtocolError::UnexpectedReply(marker).into());
+    }
+    let mut buf = [0u8; 17];
+    stream.read_exact(&mut buf)?;
+    let accepted = buf[0];
+    if accepted > count {
+        anyhow::bail!(
+            "Peer accepted {} data connections, {} were requested",
+            accepted,
+            count
+        );
+    }
+    Ok((accepted, SessionId(buf[1..].try_into().unwrap())))
+}
+
+/// Answer the sender's request for data connections.
+///
+/// Returns how many data connections to accept and the session they join.
+pub(crate) fn receiver_session<S: Read + Write>(stream: &mut S) -> anyhow::Result<(u8, SessionId)> {
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    if &marker != b":par:" {
+        return Err(ProtocolError::UnknownMarker(marker).into());
+    }
+    let mut count = [0u8; 1];
+    stream.read_exact(&mut count)?;
+    let count = std::cmp::min(count[0], MAX_DATA_STREAMS);
+
+    let id = SessionId::random()?;
+    stream.write_all(b":sid:")?;
+    stream.write_all(&[count])?;
+    stream.write_all(&id.0)?;
+    stream.flush()?;
+    Ok((count, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_uses_common_features() {
+        let local = Hello::local(Features::ALL);
+        let remote = Hello {
+            min_version: V1_1,
+            max_version: 0x0105,
+            features: Features::DIRECTORIES | Features::CHECKSUM,
+        };
+        let caps = negotiate(local, remote).unwrap();
+        assert_eq!(caps.version, V1_1);
+        assert!(caps.has(Features::DIRECTORIES | Features::CHECKSUM));
+        assert!(!caps.has(Features::RESUME));
+    }
+
+    #[test]
+    fn negotiate_fails_without_common_version() {
+        let local = Hello::local(Features::ALL);
+        let remote = Hello {
+            min_version: 0x0200,
+            max_version: 0x0201,
+            features: Features::ALL,
+        };
+        assert!(negotiate(local, remote).is_err());
+    }
+
+    #[test]
+    fn hello_round_trip_drops_unknown_features() {
+        let mut wire = Vec::new();
+        write_hello(&mut wire, Hello::local(Features::ALL)).unwrap();
+        // A newer peer with a feature bit this build doesn't know
+        wire[16] |= 0x80;
+
+        let hello = read_hello(&mut wire.as_slice()).unwrap();
+        assert_eq!(hello, Hello::local(Features::ALL));
+    }
+
+    #[test]
+    fn session_is_joined_with_its_id() {
+        // The sender asks for 20 data connections, the receiver caps them.
+        let mut wire = b":par:".to_vec();
+        wire.push(20);
+        let mut receiver = Duplex(wire.as_slice(), Vec::new());
+        let (count, id) = receiver_session(&mut receiver).unwrap();
+        assert_eq!(count, MAX_DATA_STREAMS);
+
+        let mut sender = Duplex(receiver.1.as_slice(), Vec::new());
+        assert_eq!(sender_session(&mut sender, 20).unwrap(), (count, id));
+
+        let mut attach = Vec::new();
+        sender_attach(&mut attach, id).unwrap();
+        let mut data = Duplex(attach.as_slice(), Vec::new());
+        match receiver_opening(&mut data, Features::ALL).unwrap() {
+            Opening::Attach(got) => assert_eq!(got, id),
+            Opening::Hello(_) => panic!("expected an attach frame"),
+        }
+    }
+
+    /// Reads from the slice, writes into the vector.
+    struct Duplex<'a>(&'a [u8], Vec<u8>);
+
+    impl Read for Duplex<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Duplex<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tampered_negotiation_is_detected() {
+        let caps = Capabilities::LATEST;
+        // The peer's frame; it then finds nothing to read.
+        let mut peer = Duplex(&[], Vec::new());
+        assert!(confirm_capabilities(&mut peer, caps).is_err());
+        let wire = peer.1;
+
+        // Same capabilities on both sides
+        let mut local = Duplex(wire.as_slice(), Vec::new());
+        confirm_capabilities(&mut local, caps).unwrap();
+        assert_eq!(local.1, wire);
+
+        // The peer's hello lost the checksum feature on the way
+        let stripped = Capabilities {
+            features: caps.features.without(Features::CHECKSUM),
+            ..caps
+        };
+        let mut local = Duplex(wire.as_slice(), Vec::new());
+        let error = confirm_capabilities(&mut local, stripped).unwrap_err();
+        assert!(error.to_string().contains("tampered"), "{}", error);
+
+        // Nothing is confirmed with a 1.0 peer
+        let mut legacy = Duplex(&[], Vec::new());
+        confirm_capabilities(&mut legacy, Capabilities::LEGACY).unwrap();
+        assert!(legacy.1.is_empty());
+    }
+
+    #[test]
+    fn features_display() {
+        assert_eq!(Features::NONE.to_string(), "none");
+        assert_eq!(
+            (Features::DIRECTORIES | Features::CHECKSUM).to_string(),
+            "directories, checksum"
+        );
+        assert_eq!(Capabilities::LEGACY.to_string(), "v1.0 (features: none)");
+    }
+}
@@ -5,12 +5,14 @@
 //!
 //! ## Flow
 //!
-//! 1. Create listener (TCP)
-//! 2. Optionally start UDP broadcaster (for discovery)
-//! 3. Accept and authenticate connection
-//! 4. Upgrade stream (e.g., encryption/handshake)
-//! 5. Receive files from peer
-//! 6. Send files to peer
+//! 1. Clean up partial files left in the download directory
+//! 2. Create listener (TCP)
+//! 3. Optionally start UDP broadcaster (for discovery)
+//! 4. Accept and authenticate connection
+//! 5. Upgrade stream (e.g., encryption/handshake)
+//! 6. Accept the data connections the sender asks for (if any)
+//! 7. Receive files from peer
+//! 8. Send files (and directory trees) to peer
 //!
 //!
 //! ## Design
@@ -20,6 +22,7 @@
 //! - Broadcast discovery
 //! - Progress bar
 //! - Stream upgrade (e.g., encryption)
+//! - Approval of incoming files
 
 use std::{
     borrow::Cow,
@@ -31,8 +34,22 @@ use std::{
 use anyhow::Context;
 
 use crate::{
+    collision::CollisionPolicy,
+    error::ProtocolError,
+    links::LinkPolicy,
+    manifest::{Decision, Manifest, Overall},
     pb::ProgressBar,
-    tf::{receiver_receive_file, receiver_send_file},
+    protocol::{
+        Capabilities, Features, HANDSHAKE_V1_0, HANDSHAKE_V1_1, Opening, SessionId,
+        confirm_capabilities, receiver_opening, receiver_session,
+    },
+    summary::Summary,
+    tf::{
+        clean_partial_files, read_error, receiver_receive_dir, receiver_receive_file,
+        receiver_receive_link, receiver_receive_manifest, receiver_receive_tree_file,
+        receiver_send_files, report_error,
+    },
+    zerocopy::PlainSocket,
 };
 
 /// Application abstraction for receiver runtime.
@@ -43,7 +60,9 @@ pub trait App {
     type Stream: Read + Write;
 
     /// Upgraded stream (e.g., encrypted stream)
-    type UpgradeStream: Read + Write;
+    ///
+    /// `Send` because data connections are served from separate threads.
+    type UpgradeStream: Read + Write + Send + PlainSocket;
 
     /// Prefix used for broadcast discovery
     fn prefix(&self) -> &str;
@@ -82,6 +101,14 @@ pub trait App {
     /// Provide stream upgrade function (e.g., handshake, encryption)
     fn upgrade_stream(&self, stream: Self::Stream) -> anyhow::Result<Self::UpgradeStream>;
 
+    /// Whether [`App::upgrade_stream`] secures the connection (default: no).
+    ///
+    /// A secured session never uses protocol 1.0: its negotiation can't be
+    /// confirmed afterwards, and a 1.0 peer can't secure a stream anyway.
+    fn secure(&self) -> bool {
+        false
+    }
+
     /// Post-process upgraded connection
     ///
     /// Called after stream upgrade (e.g., encryption established).
@@ -93,6 +120,43 @@ pub trait App {
     /// Create progress bar
     fn create_progress_bar(&self, total: u64) -> Box<dyn ProgressBar>;
 
+    /// What to do when a received file already exists
+    fn collision_policy(&self) -> CollisionPolicy {
+        CollisionPolicy::default()
+    }
+
+    /// Decide a single collision when the policy is [`CollisionPolicy::Ask`].
+    ///
+    /// Returning `Ask` again is treated as `Skip`.
+    fn ask_collision(&self, path: &Path) -> CollisionPolicy {
+        let _ = path;
+        CollisionPolicy::Skip
+    }
+
+    /// How to send symbolic links inside directory trees
+    fn link_policy(&self) -> LinkPolicy {
+        LinkPolicy::default()
+    }
+
+    /// Protocol features to offer to the sender
+    fn features(&self) -> Features {
+        Features::ALL
+    }
+
+    /// Name of this device, sent along with the files it sends
+    fn device_name(&self) -> String {
+        String::new()
+    }
+
+    /// Decide which of the files the peer announced to receive.
+    ///
+    /// Files sent without a manifest are offered one at a time. The
+    /// default accepts everything.
+    fn approve_incoming(&self, manifest: &Manifest) -> Decision {
+        let _ = manifest;
+        Decision::AcceptAll
+    }
+
     /// Start UDP broadcaster
     ///
     /// Returns:
@@ -110,19 +174,23 @@ pub trait App {
 /// - accept connection
 /// - receive files
 /// - send files
+///
+/// Returns a [`Summary`] of every file received and sent.
 pub fn run_v1_0<A, P, I, F>(
     app: A,
     files_to_send: impl Iterator<Item = P>,
     create_listener: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<Summary>
 where
     A: App,
     P: AsRef<Path>,
     I: Iterator<Item = io::Result<A::Stream>> + Send + 'static,
     F: Fn(&A) -> anyhow::Result<(SocketAddr, I)>,
 {
+    clean_partial_files(&app.download_dir());
+
     // Create TCP listener
-    let (listen_addr, incoming_streams) = create_listener(&app)?;
+    let (listen_addr, mut incoming_streams) = create_listener(&app)?;
 
     // Start broadcaster (optional)
     let broadcaster = if !app.disable_broadcaster() {
@@ -132,12 +200,13 @@ where
     };
 
     // Accept authenticated connection
-    let stream = accept_authenticated_stream(&app, incoming_streams).with_context(|| {
-        format!(
-            "Failed to accept authenticated connection on {}",
-            listen_addr
-        )
-    })?;
+    let (stream, caps) = accept_authenticated_stream(&app, &mut incoming_streams, None)
+        .with_context(|| {
+            format!(
+                "Failed to accept authenticated connection on {}",
+                listen_addr
+            )
+        })?;
 
     // Stop broadcaster after connection is established
     if let Some((stop, handle)) = broadcaster {
@@ -147,30 +216,87 @@ where
             .map_err(|_| anyhow::anyhow!("Broadcaster thread panicked"))?;
     }
 
+    println!("Protocol {}", caps);
+
     // Upgrade stream (e.g., encryption)
     let mut stream = app.upgrade_stream(stream)?;
 
     app.postprocess_connection(&mut stream)
         .context("postprocess faild")?;
+    confirm_capabilities(&mut stream, caps)?;
 
+    // Accept the data connections of this session
+    let mut data = Vec::new();
+    if caps.has(Features::PARALLEL) {
+        let (count, id) = receiver_session(&mut stream)?;
+        let session = Session { id, caps };
+        for _ in 0..count {
+            let (data_stream, _) =
+                accept_authenticated_stream(&app, &mut incoming_streams, Some(session))
+                    .context("Failed to accept data connection")?;
+            let mut data_stream = app.upgrade_stream(data_stream)?;
+            app.postprocess_connection(&mut data_stream)
+                .context("postprocess faild")?;
+            data.push(data_stream);
+        }
+        if count > 0 {
+            println!("Using {} data connections", count);
+        }
+    }
+
+    let mut summary = Summary::default();
+    exchange_files(
+        &app,
+        caps,
+        files_to_send,
+        &mut stream,
+        &mut data,
+        &mut summary,
+    )
+    .map_err(|e| report_error(&mut stream, caps, e))?;
+    Ok(summary)
+}
+
+/// Receive the peer's files until `:eof:`, then send ours.
+fn exchange_files<A: App, P: AsRef<Path>>(
+    app: &A,
+    caps: Capabilities,
+    files_to_send: impl Iterator<Item = P>,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
     // Receive loop
+    let mut overall = Overall::default();
     loop {
         let mut marker = [0u8; 5];
         stream.read_exact(&mut marker)?;
 
         match &marker {
             b":fff:" => {
-                receiver_receive_file(&app, &mut stream)?;
+                receiver_receive_file(app, caps, &overall, stream, data, summary)?;
+            }
+            b":ffr:" => {
+                receiver_receive_tree_file(app, caps, &overall, stream, data, summary)?;
+            }
+            b":dir:" => {
+                receiver_receive_dir(app, caps, &overall, stream, summary)?;
+            }
+            b":lnk:" => {
+                receiver_receive_link(app, caps, &overall, stream, summary)?;
+            }
+            b":man:" => {
+                overall = receiver_receive_manifest(app, caps, stream)?;
             }
             b":eof:" => break,
-            _ => unreachable!("Invalid protocol marker"),
+            b":err:" => return Err(read_error(stream)?.into()),
+            _ => return Err(ProtocolError::UnknownMarker(marker).into()),
         }
     }
 
     // Send files
-    for path in files_to_send {
-        receiver_send_file(&app, path, &mut stream)?;
-    }
+    let files_to_send: Vec<P> = files_to_send.collect();
+    receiver_send_files(app, caps, &files_to_send, stream, data, summary)?;
 
     // End session
     stream.write_all(b":eof:")?;
@@ -178,11 +304,26 @@ where
     Ok(())
 }
 
+/// Session that data connections attach to.
+#[derive(Clone, Copy)]
+struct Session {
+    id: SessionId,
+    caps: Capabilities,
+}
+
 /// Accept first authenticated stream from incoming connections.
 ///
-/// Iterates over incoming streams and returns the first one
-/// that passes authentication.
-fn accept_authenticated_stream<A: App, L>(app: &A, incoming: L) -> anyhow::Result<A::Stream>
+/// Iterates over incoming streams and returns the first one that passes
+/// the handshake and authentication, along with the negotiated protocol.
+///
+/// Without a `session`, only new sessions (a hello, or a 1.0 handshake)
+/// are accepted. With one, only data connections presenting its id are
+/// accepted; they share the session's capabilities.
+fn accept_authenticated_stream<A: App, L>(
+    app: &A,
+    incoming: &mut L,
+    session: Option<Session>,
+) -> anyhow::Result<(A::Stream, Capabilities)>
 where
     L: Iterator<Item = io::Result<A::Stream>>,
 {
@@ -196,36 +337,36 @@ where
             _ => {}
         }
 
-        match match_bytes("fs-share:v1.0\n", &mut stream) {
-            Ok(true) => {
-                stream.write_all(b":accept:")?;
-                stream.flush()?;
-            }
-            Ok(false) => {
-                let _ = stream.write_all(b":reject:");
-                let _ = stream.flush();
-                continue;
-            }
-            Err(_) => continue,
+        let mut line = [0u8; HANDSHAKE_V1_1.len()];
+        if stream.read_exact(&mut line).is_err() {
+            continue;
         }
+        let accepted = &line == HANDSHAKE_V1_1
+            // Data connections and secured sessions need a hello.
+            || (&line == HANDSHAKE_V1_0 && session.is_none() && !app.secure());
+        if !accepted {
+            let _ = stream.write_all(b":reject:");
+            let _ = stream.flush();
+            continue;
+        }
+        stream.write_all(b":accept:")?;
+        stream.flush()?;
+
+        let caps = if &line == HANDSHAKE_V1_1 {
+            match (receiver_opening(&mut stream, app.features()), session) {
+                (Ok(Opening::Hello(caps)), None) => caps,
+                (Ok(Opening::Attach(id)), Some(session)) if id == session.id => session.caps,
+                _ => continue,
+            }
+        } else {
+            Capabilities::LEGACY
+        };
 
         match app.auth(&mut stream) {
-            Ok(true) => return Ok(stream),
+            Ok(true) => return Ok((stream, caps)),
             _ => continue,
         }
     }
 
     anyhow::bail!("No authenticated connection found")
 }
-
-fn match_bytes<B: AsRef<[u8]>, R: Read>(bytes: B, mut reader: R) -> anyhow::Result<bool> {
-    let expected = bytes.as_ref();
-
-    let mut buf = vec![0u8; expected.len()].into_boxed_slice();
-
-    reader
-        .read_exact(&mut buf)
-        .context("Failed to read bytes from reader")?;
-
-    Ok(buf.as_ref() == expected)
-}
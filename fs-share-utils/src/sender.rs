@@ -6,28 +6,45 @@
 //!
 //! 1. Resolve receiver address:
 //!    - Use CLI-provided address OR
-//!    - Discover via UDP broadcast
+//!    - Discover via UDP broadcast (or IPv6 multicast) and mDNS
 //! 2. Establish TCP connection
 //! 3. Upgrade stream (e.g., encryption/handshake)
-//! 4. Send files to peer
-//! 5. Receive files from peer
+//! 4. Open extra data connections (if asked for and supported)
+//! 5. Send files (and directory trees) to peer
+//! 6. Receive files from peer (cleaning up stale partial files in the
+//!    download directory first)
 //!
 use std::{
     borrow::Cow,
     fmt::Display,
     io::{self, Read, Write},
-    net::SocketAddr,
+    net::{SocketAddr, SocketAddrV6},
     path::Path,
-    sync::mpsc::Receiver,
-    thread::JoinHandle,
 };
 
 use anyhow::Context;
 
 use crate::{
-    broadcast::receiver::{BroadcastReceiver, PayloadReader},
+    broadcast::{
+        self,
+        mdns::MdnsBrowser,
+        receiver::{BroadcastReceiver, Discovery, PayloadReader},
+    },
+    collision::CollisionPolicy,
+    error::ProtocolError,
+    links::LinkPolicy,
+    manifest::Overall,
     pb::ProgressBar,
-    tf::{sender_receive_file, sender_send_file},
+    protocol::{
+        Capabilities, Features, HANDSHAKE_V1_0, HANDSHAKE_V1_1, confirm_capabilities,
+        sender_attach, sender_hello, sender_session,
+    },
+    summary::Summary,
+    tf::{
+        clean_partial_files, read_error, report_error, sender_receive_dir, sender_receive_file,
+        sender_receive_link, sender_receive_manifest, sender_receive_tree_file, sender_send_files,
+    },
+    zerocopy::PlainSocket,
 };
 
 /// Trait for data received from broadcast discovery.
@@ -36,6 +53,19 @@ use crate::{
 pub trait ReceiverData {
     /// Returns the receiver's socket address
     fn addr(&self) -> SocketAddr;
+
+    /// Whether the advertised service accepts fs-share transfers.
+    ///
+    /// Other services (e.g. an HTTP server) may share the discovery
+    /// channel so they can be listed, but can't be connected to.
+    fn accepts_transfers(&self) -> bool {
+        true
+    }
+
+    /// Name the receiver advertised (e.g. its user name)
+    fn name(&self) -> &str {
+        ""
+    }
 }
 
 /// Application abstraction for sender runtime.
@@ -50,7 +80,9 @@ pub trait App {
     type Stream: Read + Write;
 
     /// Upgraded stream (e.g., encrypted stream)
-    type UpgradeStream: Read + Write;
+    ///
+    /// `Send` because data connections are served from separate threads.
+    type UpgradeStream: Read + Write + Send + PlainSocket;
 
     /// Broadcast prefix used for discovery filtering
     fn prefix(&self) -> &str;
@@ -58,6 +90,13 @@ pub trait App {
     /// Broadcast address (UDP)
     fn broadcast_addr(&self) -> SocketAddr;
 
+    /// IPv6 multicast groups to listen on as well (default: none)
+    ///
+    /// The scope id of each is the interface index to join it on.
+    fn multicast_addrs(&self) -> Vec<SocketAddrV6> {
+        Vec::new()
+    }
+
     /// Optional receiver address
     fn receiver_addr(&self) -> Option<SocketAddr>;
 
@@ -81,6 +120,14 @@ pub trait App {
     /// Upgrade stream (e.g., encryption/handshake)
     fn upgrade_stream(&self, stream: Self::Stream) -> anyhow::Result<Self::UpgradeStream>;
 
+    /// Whether [`App::upgrade_stream`] secures the connection (default: no).
+    ///
+    /// A secured session never uses protocol 1.0: its negotiation can't be
+    /// confirmed afterwards, and a 1.0 peer can't secure a stream anyway.
+    fn secure(&self) -> bool {
+        false
+    }
+
     /// Post-process upgraded connection
     ///
     /// Called after stream upgrade (e.g., encryption established).
@@ -92,30 +139,110 @@ pub trait App {
     /// Create progress bar
     fn create_progress_bar(&self, total: u64) -> Box<dyn ProgressBar>;
 
-    /// Select receiver address from discovered broadcast data
-    fn select_receiver_addr<U>(
-        &self,
-        discovery: (
-            Box<dyn FnOnce() + Send>,
-            Receiver<(SocketAddr, U)>,
-            JoinHandle<()>,
-        ),
-    ) -> Option<SocketAddr>
+    /// What to do when a received file already exists
+    fn collision_policy(&self) -> CollisionPolicy {
+        CollisionPolicy::default()
+    }
+
+    /// Decide a single collision when the policy is [`CollisionPolicy::Ask`].
+    ///
+    /// Returning `Ask` again is treated as `Skip`.
+    fn ask_collision(&self, path: &Path) -> CollisionPolicy {
+        let _ = path;
+        CollisionPolicy::Skip
+    }
+
+    /// How to send symbolic links inside directory trees
+    fn link_policy(&self) -> LinkPolicy {
+        LinkPolicy::default()
+    }
+
+    /// Protocol features to offer to the receiver
+    fn features(&self) -> Features {
+        Features::ALL
+    }
+
+    /// Name of this device, sent along with the files it sends
+    fn device_name(&self) -> String {
+        String::new()
+    }
+
+    /// Extra data connections to open for large files (default: none)
+    ///
+    /// The receiver may accept fewer, at most
+    /// [`MAX_DATA_STREAMS`](crate::protocol::MAX_DATA_STREAMS).
+    fn data_streams(&self) -> u8 {
+        0
+    }
+
+    /// Select receiver address from data discovered via broadcast or mDNS
+    ///
+    /// Fails if no receiver was chosen.
+    fn select_receiver_addr<U>(&self, discovery: Discovery<U>) -> anyhow::Result<SocketAddr>
     where
         U: Clone + Display + PartialEq + ReceiverData + Send + 'static;
 }
 
+/// Start discovering receivers that announce themselves with `prefix`.
+///
+/// Listens for UDP broadcasts on `broadcast_addr`, on each IPv6 multicast
+/// group in `multicast_addrs`, and browses mDNS. Only broadcast is
+/// required: the others are skipped with a warning where unavailable.
+///
+/// With `deduplicate`, data is emitted only when new or changed; without
+/// it, on every announcement (to tell when a receiver was last seen).
+pub fn discover<R>(
+    prefix: &str,
+    broadcast_addr: SocketAddr,
+    multicast_addrs: &[SocketAddrV6],
+    deduplicate: bool,
+) -> anyhow::Result<Discovery<R>>
+where
+    R: for<'a> TryFrom<(SocketAddr, PayloadReader<'a>)> + Clone + PartialEq + Send + 'static,
+{
+    let receiver = BroadcastReceiver::builder()
+        .prefix(prefix)
+        .bind_addr(broadcast_addr)
+        .buffer_size(4 * 1024)
+        .deduplicate(deduplicate)
+        .build()
+        .context("Failed to build BroadcastReceiver")?;
+
+    let mut discoveries = vec![receiver.start::<R>()];
+    for group in multicast_addrs {
+        let receiver = BroadcastReceiver::builder()
+            .prefix(prefix)
+            .bind_addr(SocketAddr::V6(*group))
+            .multicast_group(*group.ip(), group.scope_id())
+            .buffer_size(4 * 1024)
+            .deduplicate(deduplicate)
+            .build();
+        match receiver {
+            Ok(receiver) => discoveries.push(receiver.start::<R>()),
+            Err(e) => eprintln!("IPv6 discovery unavailable: {:#}", e),
+        }
+    }
+    // Broadcast alone still works where multicast doesn't.
+    match MdnsBrowser::builder().deduplicate(deduplicate).build() {
+        Ok(browser) => discoveries.push(browser.start::<R>()),
+        Err(e) => eprintln!("mDNS discovery unavailable: {:#}", e),
+    }
+    Ok(broadcast::merge(discoveries))
+}
+
 /// Run sender runtime.
 ///
 /// Handles:
 /// - receiver discovery
 /// - connection
 /// - file transfer (send + receive)
+///
+/// Returns a [`Summary`] of every file sent and received.
 pub fn run_v1_0<A, P, ConnectFn, R>(
     app: A,
     files_to_send: impl Iterator<Item = P>,
     connect: ConnectFn,
-) -> anyhow::Result<()>
+) -> anyhow::Result<Summary>
 where
     A: App,
     P: AsRef<Path>,
@@ -130,25 +257,91 @@ where
 {
     // Resolve receiver address
     let receiver_addr = match app.receiver_addr() {
-        Some(addr) => Some(addr),
+        Some(addr) => addr,
         None => {
-            let receiver = BroadcastReceiver::builder()
-                .prefix(app.prefix())
-                .bind_addr(app.broadcast_addr())
-                .buffer_size(4 * 1024)
-                .build()
-                .context("Failed to build BroadcastReceiver")?;
-
-            let discovery = receiver.start::<R>();
-            app.select_receiver_addr(discovery)
+            let discovery = discover::<R>(
+                app.prefix(),
+                app.broadcast_addr(),
+                &app.multicast_addrs(),
+                true,
+            )?;
+            app.select_receiver_addr(discovery)?
         }
     };
 
-    let receiver_addr = receiver_addr.context("No valid receiver address found via broadcast")?;
+    // Establish connection and negotiate the protocol
+    let (stream, caps) = match open_connection(&app, &connect, receiver_addr, HANDSHAKE_V1_1)? {
+        Some(mut stream) => {
+            let caps = sender_hello(&mut stream, app.features())?;
+            (stream, caps)
+        }
+        None => {
+            // Refused by the receiver, or by someone on the way to it.
+            if app.secure() {
+                anyhow::bail!(
+                    "The receiver refused protocol 1.1, and a secured session can't use 1.0"
+                );
+            }
+            // The receiver only speaks 1.0; reconnect with the old handshake.
+            let stream = open_connection(&app, &connect, receiver_addr, HANDSHAKE_V1_0)?
+                .context("faild to connect, version not match")?;
+            (stream, Capabilities::LEGACY)
+        }
+    };
+    println!("Protocol {}", caps);
 
-    // Establish connection
-    let mut stream = connect(receiver_addr)
-        .with_context(|| format!("Failed to connect to {}", receiver_addr))?;
+    // Upgrade stream
+    let mut stream = app.upgrade_stream(stream)?;
+
+    app.postprocess_connection(&mut stream)
+        .context("postprocess failed")?;
+    confirm_capabilities(&mut stream, caps)?;
+
+    // Open the data connections of this session
+    let mut data = Vec::new();
+    if caps.has(Features::PARALLEL) {
+        let (count, id) = sender_session(&mut stream, app.data_streams())?;
+        for _ in 0..count {
+            let mut data_stream = open_connection(&app, &connect, receiver_addr, HANDSHAKE_V1_1)?
+                .context("Receiver rejected a data connection")?;
+            sender_attach(&mut data_stream, id)?;
+            let mut data_stream = app.upgrade_stream(data_stream)?;
+            app.postprocess_connection(&mut data_stream)
+                .context("postprocess failed")?;
+            data.push(data_stream);
+        }
+        if count > 0 {
+            println!("Using {} data connections", count);
+        }
+    }
+
+    let mut summary = Summary::default();
+    exchange_files(
+        &app,
+        caps,
+        files_to_send,
+        &mut stream,
+        &mut data,
+        &mut summary,
+    )
+    .map_err(|e| report_error(&mut stream, caps, e))?;
+    Ok(summary)
+}
+
+/// Connect to `addr` and send the handshake `line`.
+///
+/// Returns `None` if the receiver rejected the version.
+fn open_connection<A, ConnectFn>(
+    app: &A,
+    connect: &ConnectFn,
+    addr: SocketAddr,
+    line: &[u8],
+) -> anyhow::Result<Option<A::Stream>>
+where
+    A: App,
+    ConnectFn: Fn(SocketAddr) -> io::Result<A::Stream>,
+{
+    let mut stream = connect(addr).with_context(|| format!("Failed to connect to {}", addr))?;
 
     app.preprocess_connection(&mut stream)
         .context("Pre-processing faild")?;
@@ -157,44 +350,64 @@ where
         anyhow::bail!("authentication failed");
     };
 
-    stream.write_all(b"fs-share:v1.0\n")?;
+    stream.write_all(line)?;
     stream.flush()?;
     let mut buf = [0u8; 8];
     stream.read_exact(&mut buf)?;
     match &buf {
-        b":reject:" => {
-            anyhow::bail!("faild to connect, version not match");
-        }
-        b":accept:" => {}
+        b":reject:" => Ok(None),
+        b":accept:" => Ok(Some(stream)),
         _ => anyhow::bail!("invalid connection"),
     }
+}
 
-    // Upgrade stream
-    let mut stream = app.upgrade_stream(stream)?;
-
-    app.postprocess_connection(&mut stream)
-        .context("postprocess failed")?;
-
+/// Send our files, then receive the peer's until `:eof:`.
+fn exchange_files<A: App, P: AsRef<Path>>(
+    app: &A,
+    caps: Capabilities,
+    files_to_send: impl Iterator<Item = P>,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
     // Send files
-    for path in files_to_send {
-        sender_send_file(&app, path, &mut stream)?;
-    }
+    let files_to_send: Vec<P> = files_to_send.collect();
+    sender_send_files(app, caps, &files_to_send, stream, data, summary)?;
 
     // Signal end of sending
     stream.write_all(b":eof:")?;
     stream.flush()?;
 
-    // Receive files
+    // Receive files, after cleaning up earlier sessions if any arrive
+    let mut overall = Overall::default();
+    let mut cleaned = false;
     loop {
         let mut marker = [0u8; 5];
         stream.read_exact(&mut marker)?;
+        if !cleaned && &marker != b":eof:" {
+            clean_partial_files(&app.download_dir());
+            cleaned = true;
+        }
 
         match &marker {
             b":fff:" => {
-                sender_receive_file(&app, &mut stream)?;
+                sender_receive_file(app, caps, &overall, stream, data, summary)?;
+            }
+            b":ffr:" => {
+                sender_receive_tree_file(app, caps, &overall, stream, data, summary)?;
+            }
+            b":dir:" => {
+                sender_receive_dir(app, caps, &overall, stream, summary)?;
+            }
+            b":lnk:" => {
+                sender_receive_link(app, caps, &overall, stream, summary)?;
+            }
+            b":man:" => {
+                overall = sender_receive_manifest(app, caps, stream)?;
             }
             b":eof:" => break,
-            _ => unreachable!("Invalid protocol marker"),
+            b":err:" => return Err(read_error(stream)?.into()),
+            _ => return Err(ProtocolError::UnknownMarker(marker).into()),
         }
     }
     Ok(())
@@ -0,0 +1,63 @@
+//! # Transfer Summary
+//!
+//! Record of every file moved during a session, returned by
+//! [`crate::sender::run_v1_0`] and [`crate::receiver::run_v1_0`].
+
+use std::path::PathBuf;
+
+/// Direction of a transferred file, seen from the local side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A single transferred file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRecord {
+    pub direction: Direction,
+    /// Local path the file was read from or written to
+    pub path: PathBuf,
+    /// File size in bytes
+    pub size: u64,
+    /// BLAKE3 hash of the file content
+    pub hash: [u8; 32],
+}
+
+impl FileRecord {
+    /// Hash as a lowercase hex string
+    pub fn hash_hex(&self) -> String {
+        blake3::Hash::from_bytes(self.hash).to_hex().to_string()
+    }
+}
+
+/// A file that was offered but not transferred.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub direction: Direction,
+    /// Local path for sent files, name as sent by the peer for received ones
+    pub path: PathBuf,
+    /// Why the file was skipped
+    pub reason: String,
+}
+
+/// All files transferred during a session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub files: Vec<FileRecord>,
+    pub skipped: Vec<Skipped>,
+}
+
+impl Summary {
+    /// Files sent to the peer
+    pub fn sent(&self) -> impl Iterator<Item = &FileRecord> {
+        self.files.iter().filter(|f| f.direction == Direction::Sent)
+    }
+
+    /// Files received from the peer
+    pub fn received(&self) -> impl Iterator<Item = &FileRecord> {
+        self.files
+            .iter()
+            .filter(|f| f.direction == Direction::Received)
+    }
+}
@@ -6,235 +6,3299 @@
 //!
 //! Header format:
 //! ```text
+//! :fff: | name_len(u16) | file_size(u64) | mtime(u64) | filename | file_bytes... | blake3(32)
+//! :ffr: | path_len(u16) | file_size(u64) | mtime(u64) | rel_path | file_bytes... | blake3(32)
+//! :dir: | path_len(u16) | rel_path
+//! :lnk: | kind(u8) | path_len(u16) | target_len(u16) | rel_path | target
+//! ```
+//!
+//! `:fff:` carries a bare file name and is used for files given directly
+//! on the command line. `:ffr:` and `:dir:` are used while walking a
+//! directory tree; `rel_path` is relative to the download directory and
+//! its components are joined with `/` regardless of platform. `mtime` is
+//! the modification time in seconds since the Unix epoch (0 if unknown).
+//! With [`Features::RAW_NAMES`], every `*_len(u16)` above is an
+//! `encoding(u8) | len(u32)` pair and names keep their OS bytes (see
+//! `name`). With [`Features::METADATA`], permissions, precise timestamps and
+//! ownership follow `mtime` (see `attrs`) and are applied to the received
+//! file.
+//!
+//! After every file header the receiver answers with the offset it wants
+//! the data to start at, and the sender only sends the remaining bytes:
+//! ```text
+//! :off: | offset(u64)
+//! ```
+//!
+//! ## Manifest
+//!
+//! With [`Features::MANIFEST`], a side that has files to send first sends
+//! a [`Manifest`] of every entry it is about to send and waits for the
+//! peer to accept it (see [`crate::manifest`]). The receiver may reject
+//! the whole transfer or pick files from it, and refuses it with an error
+//! frame if the accepted files don't fit on its disk; otherwise both sides
+//! report overall progress (`file 3/120, ...`) from it.
+//!
+//! ## Links
+//!
+//! With [`Features::LINKS`], symbolic links inside a tree are sent as
+//! `:lnk:` frames of kind [`SYMLINK`] (see [`LinkPolicy`]), with their
+//! target as a `/` separated relative path. A file that has another hard
+//! link already sent from the same tree is sent as kind [`HARD_LINK`],
+//! whose target is the `rel_path` of that earlier file. The receiver
+//! answers with `:lnd:` once the link exists, or with `:rej:` (see below);
+//! a refused hard link is then sent as a regular `:ffr:` file.
+//!
+//! A symbolic link target must be relative and must not leave the download
+//! directory, and a hard link must point to a file received in the same
+//! session. Nothing received is ever written through a symbolic link below
+//! the download directory, so a link can't redirect later files.
+//!
+//! ## Errors
+//!
+//! Either side may end the session at any frame boundary, including in
+//! place of an `:off:` reply, with an error frame. The message is UTF-8:
+//! ```text
+//! :err: | code(u16) | msg_len(u16) | message
+//! ```
+//!
+//! The receiving runtime returns it as [`PeerError`]. Unknown markers are
+//! answered with an error frame and returned as [`ProtocolError`].
+//!
+//! A receiver that refuses a single file answers its header with a frame of
+//! the same layout instead of `:off:`. No data is sent for that file and the
+//! session continues with the next frame:
+//! ```text
+//! :rej: | code(u16) | msg_len(u16) | message
+//! ```
+//!
+//! ## Names
+//!
+//! Names and relative paths come from the peer and are never trusted. Every
+//! component must be a plain, non-empty name of at most 255 bytes without
+//! NUL, control characters or `\`, must not be `.` or `..` and must not be a
+//! device name reserved on Windows (`CON`, `NUL`, `COM1`, ...) or end like
+//! the files used for partial downloads, and a whole path must be at most
+//! 4096 bytes, with or without [`Features::RAW_NAMES`]. Anything else is
+//! refused with [`InvalidName`] and reported to the sender. Names that
+//! aren't valid Unicode are kept as they are where the platform allows it,
+//! and escaped otherwise.
+//!
+//! ## Partial Files and Resume
+//!
+//! A file is received into a hidden `.<name>.fs-share-part` file next to
+//! its final path, described by a sidecar `.<name>.fs-share-resume` that
+//! holds the expected size. Only once the whole file is written and its
+//! size and checksum are verified is the part synced to disk and renamed
+//! over the final path, so a crash never leaves a truncated file under the
+//! real name.
+//!
+//! If the connection drops, the part and the sidecar stay on disk and the
+//! next transfer of the same file (same name and size) continues from the
+//! end of the part. Parts untouched for [`PART_MAX_AGE`] are removed with
+//! their sidecar from the download directory when the receiver starts (or
+//! the sender gets files back), and from each received directory (see
+//! [`clean_partial_files`]). Only parts with a sidecar are ever removed:
+//! the sidecar is written first, so anything else isn't ours.
+//!
+//! ## Collisions
+//!
+//! A file that exists without a resume sidecar is handled according to the
+//! app's [`CollisionPolicy`]. Skipped files are refused with `:rej:`, so no
+//! data is sent for them.
+//!
+//! ## Negotiated Features
+//!
+//! `:dir:`/`:ffr:` frames, non-zero offsets and the BLAKE3 trailer are only
+//! used when negotiated (see [`crate::protocol`]). Directories are skipped
+//! if the peer can't receive them.
+//!
+//! With a 1.0 peer only the original frame is used and nothing is sent back
+//! per file; files the receiver doesn't want are read and discarded:
+//! ```text
 //! :fff: | name_len(u16) | file_size(u64) | filename | file_bytes...
 //! ```
+//!
+//! When the session has data connections ([`Features::PARALLEL`]), the
+//! sender follows every `:off:` reply with a byte: `0` if `file_bytes` come
+//! inline as above, or the number of ranges they are split into (see
+//! `parallel`). The BLAKE3 trailer always comes over the control connection.
+//!
+//! With [`Features::COMPRESSION`], `file_bytes` are sent as LZ4 chunks (see
+//! `compress`); sizes, offsets, progress and the checksum still count the
+//! uncompressed bytes.
+//!
+//! Over a plain TCP connection without compression, Linux moves the bytes
+//! with `sendfile(2)` and `splice(2)` (see [`crate::zerocopy`]).
+//!
+//! ## Integrity
+//!
+//! With [`Features::CHECKSUM`], every file frame ends with the BLAKE3 hash
+//! of the whole file, computed by the sender while streaming. The receiver
+//! hashes what it writes (including a resumed prefix) and removes the file
+//! on mismatch, returning [`ChecksumMismatch`].
 
 use std::io::Read;
 use std::{
-    fs::File,
-    io::Write,
-    path::{Path, PathBuf},
+    borrow::Cow,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::atomic::AtomicU64,
 };
 
-use anyhow::Context;
+use anyhow::Context as _;
 
+use crate::attrs::Attributes;
+use crate::collision::CollisionPolicy;
+use crate::compress::{body_reader, body_writer, should_compress};
+use crate::error::{
+    ChecksumMismatch, ErrorCode, InsufficientSpace, InvalidName, PeerError, ProtocolError,
+};
+use crate::links::LinkPolicy;
+use crate::manifest::{Decision, Entry, EntryKind, Manifest, Overall, available_space, human_size};
+use crate::name::{Prefix, WireName};
+use crate::parallel::{contiguous_end, range_count, receive_ranges, send_ranges, split_ranges};
+use crate::pb::ProgressBar;
+use crate::protocol::{Capabilities, Features};
 use crate::receiver::App as ReceiverApp;
 use crate::sender::App as SenderApp;
+use crate::summary::{Direction, FileRecord, Skipped, Summary};
+use crate::zerocopy::{self, PlainSocket};
 
 const BUFFER_SIZE: usize = 256 * 1024;
 
+/// Suffix of the sidecar file that marks a partially received file.
+const RESUME_SUFFIX: &str = ".fs-share-resume";
+
+/// Suffix of the file an incoming file is written to until it is complete.
+const PART_SUFFIX: &str = ".fs-share-part";
+
+/// Partial files not written to for this long are removed at startup.
+pub(crate) const PART_MAX_AGE: std::time::Duration =
+    std::time::Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Longest message written into an `:err:` frame.
+const MAX_ERROR_MESSAGE: usize = 1024;
+
+/// Longest accepted name component, in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// Longest accepted relative path, in bytes.
+const MAX_PATH_LEN: usize = 4096;
+
+/// Most `name (n).ext` candidates tried before a renamed copy is given up.
+const MAX_RENAMES: u32 = 10_000;
+
+/// `:lnk:` kind of a symbolic link
+const SYMLINK: u8 = 0;
+
+/// `:lnk:` kind of a hard link to a file sent earlier
+const HARD_LINK: u8 = 1;
+
+/// Device names reserved on Windows, with or without an extension.
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
 fn create_buffer(size: usize) -> Box<[u8]> {
     let v = Box::new_zeroed_slice(size);
     unsafe { v.assume_init() }
 }
 
-pub(crate) fn sender_send_file<A: SenderApp + ?Sized>(
+/// Runtime independent view of an app, used by the transfer functions.
+struct Context<'a> {
+    download_dir: Cow<'a, Path>,
+    progress: Box<dyn Fn(u64) -> Box<dyn ProgressBar> + 'a>,
+    collision: CollisionPolicy,
+    ask_collision: Box<dyn Fn(&Path) -> CollisionPolicy + 'a>,
+    links: LinkPolicy,
+    caps: Capabilities,
+    /// Progress over the files of the current manifest
+    overall: Option<&'a Overall>,
+    /// Decide on incoming files
+    approve: Box<dyn Fn(&Manifest) -> Decision + 'a>,
+}
+
+impl<'a> Context<'a> {
+    fn sender<A: SenderApp + ?Sized>(
+        app: &'a A,
+        caps: Capabilities,
+        overall: Option<&'a Overall>,
+    ) -> Self {
+        Self {
+            download_dir: app.download_dir(),
+            progress: Box::new(|total| app.create_progress_bar(total)),
+            collision: app.collision_policy(),
+            ask_collision: Box::new(|path| app.ask_collision(path)),
+            links: app.link_policy(),
+            caps,
+            overall,
+            approve: Box::new(|_| Decision::AcceptAll),
+        }
+    }
+    fn receiver<A: ReceiverApp + ?Sized>(
+        app: &'a A,
+        caps: Capabilities,
+        overall: Option<&'a Overall>,
+    ) -> Self {
+        Self {
+            download_dir: app.download_dir(),
+            progress: Box::new(|total| app.create_progress_bar(total)),
+            collision: app.collision_policy(),
+            ask_collision: Box::new(|path| app.ask_collision(path)),
+            links: app.link_policy(),
+            caps,
+            overall,
+            approve: Box::new(|manifest| app.approve_incoming(manifest)),
+        }
+    }
+
+    /// Whether names are sent with their encoding and 32-bit lengths
+    fn raw_names(&self) -> bool {
+        self.caps.has(Features::RAW_NAMES)
+    }
+
+    /// Check an incoming entry against the accepted part of the manifest,
+    /// or ask the app about it alone if no manifest announced it.
+    fn approve_entry(&self, kind: EntryKind, path: &str, size: u64) -> Result<(), &'static str> {
+        match self.overall {
+            Some(overall) if overall.announced() => match kind {
+                EntryKind::Directory => overall.check_dir(path),
+                _ => overall.check(path, size),
+            },
+            _ => {
+                let manifest = Manifest {
+                    from: String::new(),
+                    entries: vec![Entry {
+                        kind,
+                        path: path.to_owned(),
+                        size,
+                    }],
+                };
+                match (self.approve)(&manifest).accepts(path) {
+                    true => Ok(()),
+                    false => Err("rejected by the receiver"),
+                }
+            }
+        }
+    }
+
+    /// Print the overall progress before a file of `size` bytes.
+    fn count_file(&self, size: u64) {
+        if let Some(progress) = self.overall.and_then(|o| o.advance(size)) {
+            println!("[{}]", progress);
+        }
+    }
+}
+
+/// Send every path in `paths`, announced by a manifest if supported.
+pub(crate) fn sender_send_files<A: SenderApp + ?Sized, P: AsRef<Path>>(
     app: &A,
-    path: impl AsRef<Path>,
+    caps: Capabilities,
+    paths: &[P],
     stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
 ) -> anyhow::Result<()> {
-    let path = path.as_ref();
-    if path.is_dir() {
-        anyhow::bail!("Faild to send");
-    }
-    let file_name = path
-        .file_name()
-        .with_context(|| format!("Invalid file name: {}", path.display()))?
-        .to_string_lossy();
-    let name_bytes = file_name.as_bytes();
+    let ctx = Context::sender(app, caps, None);
+    let Some(overall) = announce(&ctx, app.device_name(), paths, stream, summary)? else {
+        return Ok(());
+    };
+    let ctx = Context::sender(app, caps, Some(&overall));
+    paths
+        .iter()
+        .try_for_each(|path| send_path(&ctx, path.as_ref(), stream, data, summary))
+}
 
-    let mut file = std::fs::File::open(path)
-        .with_context(|| format!("Failed to open file: {}", path.display()))?;
-    let metadata = file.metadata()?;
-    let total = metadata.len();
+/// Send every path in `paths`, announced by a manifest if supported.
+pub(crate) fn receiver_send_files<A: ReceiverApp + ?Sized, P: AsRef<Path>>(
+    app: &A,
+    caps: Capabilities,
+    paths: &[P],
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let ctx = Context::receiver(app, caps, None);
+    let Some(overall) = announce(&ctx, app.device_name(), paths, stream, summary)? else {
+        return Ok(());
+    };
+    let ctx = Context::receiver(app, caps, Some(&overall));
+    paths
+        .iter()
+        .try_for_each(|path| send_path(&ctx, path.as_ref(), stream, data, summary))
+}
 
-    let pb = app.create_progress_bar(total);
+/// Read a `:fff:` frame (marker already consumed).
+pub(crate) fn sender_receive_file<A: SenderApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_file(
+        &Context::sender(app, caps, Some(overall)),
+        stream,
+        data,
+        false,
+        summary,
+    )
+}
 
-    println!("Sending file: {}, size: {} bytes", path.display(), total);
-    stream.write_all(b":fff:")?;
-    stream.write_all(&(name_bytes.len() as u16).to_be_bytes())?;
-    stream.write_all(&total.to_be_bytes())?;
-    stream.write_all(name_bytes)?;
-    stream.flush()?;
+/// Read a `:fff:` frame (marker already consumed).
+pub(crate) fn receiver_receive_file<A: ReceiverApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_file(
+        &Context::receiver(app, caps, Some(overall)),
+        stream,
+        data,
+        false,
+        summary,
+    )
+}
 
-    let mut buffer = create_buffer(std::cmp::min(total as usize, BUFFER_SIZE));
+/// Read a `:ffr:` frame (marker already consumed).
+pub(crate) fn sender_receive_tree_file<A: SenderApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_file(
+        &Context::sender(app, caps, Some(overall)),
+        stream,
+        data,
+        true,
+        summary,
+    )
+}
 
-    let mut i = 0;
-    loop {
-        let read_count = file.read(&mut buffer)?;
-        if read_count == 0 {
-            break;
-        }
-        stream.write_all(&buffer[..read_count])?;
+/// Read a `:ffr:` frame (marker already consumed).
+pub(crate) fn receiver_receive_tree_file<A: ReceiverApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    data: &mut [A::UpgradeStream],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_file(
+        &Context::receiver(app, caps, Some(overall)),
+        stream,
+        data,
+        true,
+        summary,
+    )
+}
 
-        i += read_count as u64;
-        pb.update(i);
-    }
-    pb.finish();
-    Ok(())
+/// Read a `:lnk:` frame (marker already consumed).
+pub(crate) fn sender_receive_link<A: SenderApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_link(&Context::sender(app, caps, Some(overall)), stream, summary)
+}
+
+/// Read a `:lnk:` frame (marker already consumed).
+pub(crate) fn receiver_receive_link<A: ReceiverApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_link(
+        &Context::receiver(app, caps, Some(overall)),
+        stream,
+        summary,
+    )
+}
+
+/// Read a `:dir:` frame (marker already consumed).
+pub(crate) fn sender_receive_dir<A: SenderApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_dir(&Context::sender(app, caps, Some(overall)), stream, summary)
+}
+
+/// Read a `:dir:` frame (marker already consumed).
+pub(crate) fn receiver_receive_dir<A: ReceiverApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    overall: &Overall,
+    stream: &mut A::UpgradeStream,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    receive_dir(
+        &Context::receiver(app, caps, Some(overall)),
+        stream,
+        summary,
+    )
+}
+
+/// Read a `:man:` frame (marker already consumed) and answer it.
+pub(crate) fn sender_receive_manifest<A: SenderApp + ?Sized>(
+    app: &A,
+    caps: Capabilities,
+    stream: &mut A::UpgradeStream,
+) -> anyhow::Result<Overall> {
+    receive_manifest(&Context::sender(app, caps, None), stream)
 }
-pub(crate) fn receiver_send_file<A: ReceiverApp + ?Sized>(
+
+/// Read a `:man:` frame (marker already consumed) and answer it.
+pub(crate) fn receiver_receive_manifest<A: ReceiverApp + ?Sized>(
     app: &A,
-    path: impl AsRef<Path>,
+    caps: Capabilities,
     stream: &mut A::UpgradeStream,
+) -> anyhow::Result<Overall> {
+    receive_manifest(&Context::receiver(app, caps, None), stream)
+}
+
+fn send_path<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    path: &Path,
+    stream: &mut S,
+    data: &mut [S],
+    summary: &mut Summary,
 ) -> anyhow::Result<()> {
-    let path = path.as_ref();
     if path.is_dir() {
-        anyhow::bail!("Faild to send");
+        if !ctx.caps.has(Features::DIRECTORIES) {
+            println!(
+                "Peer can't receive directories, skipping {}",
+                path.display()
+            );
+            summary.skipped.push(Skipped {
+                direction: Direction::Sent,
+                path: path.to_path_buf(),
+                reason: "Peer doesn't support directories".to_owned(),
+            });
+            return Ok(());
+        }
+        let root = path
+            .file_name()
+            .with_context(|| format!("Invalid directory name: {}", path.display()))?;
+        let mut walk = Walk {
+            rel: vec![root.to_os_string()],
+            ancestors: Vec::new(),
+            inodes: HashMap::new(),
+        };
+        return send_dir(ctx, path, &mut walk, stream, data, summary);
     }
     let file_name = path
         .file_name()
-        .with_context(|| format!("Invalid file name: {}", path.display()))?
-        .to_string_lossy();
-    let name_bytes = file_name.as_bytes();
+        .with_context(|| format!("Invalid file name: {}", path.display()))?;
+    let name = WireName::join([file_name]);
+    send_file(ctx, path, b":fff:", &name, stream, data, summary)
+}
 
-    let mut file = std::fs::File::open(path)
-        .with_context(|| format!("Failed to open file: {}", path.display()))?;
-    let metadata = file.metadata()?;
-    let total = metadata.len();
+/// State of the directory walk in [`send_dir`].
+struct Walk {
+    /// Components of the current entry relative to the directory that was
+    /// given on the command line (including its own name)
+    rel: Vec<OsString>,
+    /// Canonical paths of the directories being walked, to stop at links
+    /// that lead back into one of them
+    ancestors: Vec<PathBuf>,
+    /// Sent files that have more hard links: their relative path and
+    /// index in the summary
+    inodes: HashMap<(u64, u64), (WireName, usize)>,
+}
+
+impl Walk {
+    fn rel_path(&self) -> WireName {
+        WireName::join(self.rel.iter().map(OsString::as_os_str))
+    }
+}
 
-    let pb = app.create_progress_bar(total);
+/// Recursively send a directory.
+fn send_dir<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    dir: &Path,
+    walk: &mut Walk,
+    stream: &mut S,
+    data: &mut [S],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let canonical = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    if walk.ancestors.contains(&canonical) {
+        skip_sent(summary, dir, "Symbolic link loop");
+        return Ok(());
+    }
 
-    println!("Sending file: {}, size: {} bytes", path.display(), total);
-    stream.write_all(b":fff:")?;
-    stream.write_all(&(name_bytes.len() as u16).to_be_bytes())?;
-    stream.write_all(&total.to_be_bytes())?;
-    stream.write_all(name_bytes)?;
+    println!("Sending directory: {}", dir.display());
+    write_header(stream, b":dir:", &walk.rel_path(), None, ctx.raw_names())?;
     stream.flush()?;
 
-    let mut buffer = create_buffer(std::cmp::min(total as usize, BUFFER_SIZE));
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    entries.sort_by_key(|e| e.file_name());
 
-    let mut i = 0;
-    loop {
-        let read_count = file.read(&mut buffer)?;
-        if read_count == 0 {
-            break;
+    walk.ancestors.push(canonical);
+    for entry in entries {
+        let path = entry.path();
+        walk.rel.push(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            send_symlink(ctx, &path, walk, stream, data, summary)?;
+        } else if file_type.is_dir() {
+            send_dir(ctx, &path, walk, stream, data, summary)?;
+        } else {
+            send_tree_file(ctx, &path, walk, stream, data, summary)?;
         }
-        stream.write_all(&buffer[..read_count])?;
+        walk.rel.pop();
+    }
+    walk.ancestors.pop();
+    Ok(())
+}
 
-        i += read_count as u64;
-        pb.update(i);
+/// Send a symbolic link found in a tree according to the [`LinkPolicy`].
+fn send_symlink<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    path: &Path,
+    walk: &mut Walk,
+    stream: &mut S,
+    data: &mut [S],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    match ctx.links {
+        LinkPolicy::Skip => skip_sent(summary, path, "Symbolic link"),
+        LinkPolicy::Follow => match std::fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => send_dir(ctx, path, walk, stream, data, summary)?,
+            Ok(_) => send_tree_file(ctx, path, walk, stream, data, summary)?,
+            Err(_) => skip_sent(summary, path, "Broken symbolic link"),
+        },
+        LinkPolicy::Preserve if !ctx.caps.has(Features::LINKS) => {
+            skip_sent(summary, path, "Peer doesn't support links")
+        }
+        LinkPolicy::Preserve => {
+            let target = std::fs::read_link(path)
+                .with_context(|| format!("Failed to read link: {}", path.display()))?;
+            let Some(target) = wire_link_target(&target) else {
+                skip_sent(summary, path, "Absolute link target");
+                return Ok(());
+            };
+            println!("Sending link: {} -> {}", path.display(), target);
+            let rel_path = walk.rel_path();
+            if let Some(err) = send_link(ctx, stream, SYMLINK, &rel_path, &target)? {
+                println!("Peer refused {}: {}", path.display(), err.message);
+                summary.skipped.push(Skipped {
+                    direction: Direction::Sent,
+                    path: path.to_path_buf(),
+                    reason: err.message,
+                });
+            }
+        }
     }
-    pb.finish();
     Ok(())
 }
 
-pub(crate) fn sender_receive_file<A: SenderApp + ?Sized>(
-    app: &A,
-    stream: &mut A::UpgradeStream,
+/// Send a file found in a tree, as a hard link if the peer already got
+/// another link to it.
+fn send_tree_file<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    path: &Path,
+    walk: &mut Walk,
+    stream: &mut S,
+    data: &mut [S],
+    summary: &mut Summary,
 ) -> anyhow::Result<()> {
-    // Read name length
-    let mut len_buf = [0u8; 2];
-    stream.read_exact(&mut len_buf)?;
-    let name_len = u16::from_be_bytes(len_buf) as usize;
+    let rel_path = walk.rel_path();
+    let inode = match ctx.caps.has(Features::LINKS) {
+        true => std::fs::metadata(path).ok().and_then(|m| hard_link_id(&m)),
+        false => None,
+    };
+    if let Some(inode) = inode
+        && let Some((target, index)) = walk.inodes.get(&inode)
+    {
+        println!("Sending hard link: {} -> {}", rel_path, target);
+        match send_link(ctx, stream, HARD_LINK, &rel_path, target)? {
+            None => {
+                let record = FileRecord {
+                    path: path.to_path_buf(),
+                    ..summary.files[*index].clone()
+                };
+                summary.files.push(record);
+                return Ok(());
+            }
+            Some(err) => println!("Peer refused hard link {}: {}", rel_path, err.message),
+        }
+    }
 
-    // Read file size
-    let mut size_buf = [0u8; 8];
-    stream.read_exact(&mut size_buf)?;
-    let total = u64::from_be_bytes(size_buf);
+    let index = summary.files.len();
+    send_file(ctx, path, b":ffr:", &rel_path, stream, data, summary)?;
+    if let Some(inode) = inode
+        && summary.files.len() > index
+    {
+        walk.inodes.entry(inode).or_insert((rel_path, index));
+    }
+    Ok(())
+}
 
-    // Read filename
-    let mut name_buf = vec![0u8; name_len];
-    stream.read_exact(&mut name_buf)?;
+/// Identity of a file with more than one hard link.
+#[cfg(unix)]
+fn hard_link_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// A link target as sent on the wire, `None` if it is absolute.
+fn wire_link_target(target: &Path) -> Option<WireName> {
+    let parts = target
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Some(name),
+            Component::CurDir => Some(OsStr::new(".")),
+            Component::ParentDir => Some(OsStr::new("..")),
+            Component::RootDir | Component::Prefix(_) => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(WireName::join(parts))
+}
+
+/// Write a `:lnk:` frame and wait for the reply.
+///
+/// Returns the peer's reason if it refused the link.
+fn send_link<S: Read + Write>(
+    ctx: &Context,
+    stream: &mut S,
+    kind: u8,
+    rel_path: &WireName,
+    target: &WireName,
+) -> anyhow::Result<Option<PeerError>> {
+    write_link(stream, kind, rel_path, target, ctx.raw_names())?;
+    stream.flush()?;
+
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    match &marker {
+        b":lnd:" => Ok(None),
+        b":rej:" => Ok(Some(read_error(stream)?)),
+        b":err:" => Err(read_error(stream)?.into()),
+        _ => Err(ProtocolError::UnexpectedReply(marker).into()),
+    }
+}
+
+/// Write `:lnk: | kind(u8) | path_len | target_len | rel_path | target`.
+fn write_link<S: Write>(
+    stream: &mut S,
+    kind: u8,
+    rel_path: &WireName,
+    target: &WireName,
+    raw: bool,
+) -> anyhow::Result<()> {
+    let rel_path = rel_path.for_peer(raw);
+    let target = target.for_peer(raw);
+    stream.write_all(b":lnk:")?;
+    stream.write_all(&[kind])?;
+    rel_path.write_prefix(stream, raw)?;
+    target.write_prefix(stream, raw)?;
+    rel_path.write_name(stream)?;
+    target.write_name(stream)?;
+    Ok(())
+}
 
-    let file_name = String::from_utf8(name_buf).context("Invalid UTF-8 in file name")?;
+/// List what [`send_path`] sends for each of `paths`, without sending.
+///
+/// Entries that can't be read are left out; sending them fails later.
+fn list_paths<P: AsRef<Path>>(ctx: &Context, paths: &[P]) -> Manifest {
+    let mut manifest = Manifest::default();
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_dir() {
+            let Some(root) = path.file_name() else {
+                continue;
+            };
+            if ctx.caps.has(Features::DIRECTORIES) {
+                let mut walk = Walk {
+                    rel: vec![root.to_os_string()],
+                    ancestors: Vec::new(),
+                    inodes: HashMap::new(),
+                };
+                list_dir(ctx, path, &mut walk, &mut manifest);
+            }
+        } else if let Ok(metadata) = std::fs::metadata(path)
+            && let Some(name) = path.file_name()
+        {
+            manifest.entries.push(Entry {
+                kind: EntryKind::File,
+                path: WireName::join([name]).lossy(),
+                size: metadata.len(),
+            });
+        }
+    }
+    manifest
+}
 
-    let mut save_path = PathBuf::from(app.download_dir());
-    if !save_path.is_dir() {
-        std::fs::create_dir_all(&save_path)
-            .with_context(|| format!("Faild to create directoy: {}", save_path.display()))?;
+/// Directory part of [`list_paths`], mirroring [`send_dir`].
+fn list_dir(ctx: &Context, dir: &Path, walk: &mut Walk, manifest: &mut Manifest) {
+    let Ok(canonical) = dir.canonicalize() else {
+        return;
+    };
+    if walk.ancestors.contains(&canonical) {
+        return;
     }
-    save_path.push(&file_name);
-    if save_path.exists() {
-        anyhow::bail!("File already exists: {}", save_path.display());
+    let Ok(Ok(mut entries)) = std::fs::read_dir(dir).map(|r| r.collect::<Result<Vec<_>, _>>())
+    else {
+        return;
+    };
+    entries.sort_by_key(|e| e.file_name());
+    manifest.entries.push(Entry {
+        kind: EntryKind::Directory,
+        path: walk.rel_path().lossy(),
+        size: 0,
+    });
+
+    walk.ancestors.push(canonical);
+    for entry in entries {
+        let path = entry.path();
+        walk.rel.push(entry.file_name());
+        let link = |manifest: &mut Manifest, walk: &Walk| {
+            manifest.entries.push(Entry {
+                kind: EntryKind::Link,
+                path: walk.rel_path().lossy(),
+                size: 0,
+            })
+        };
+        match entry.file_type() {
+            Ok(t) if t.is_symlink() => match ctx.links {
+                LinkPolicy::Skip => {}
+                LinkPolicy::Follow => match std::fs::metadata(&path) {
+                    Ok(metadata) if metadata.is_dir() => list_dir(ctx, &path, walk, manifest),
+                    Ok(metadata) => list_tree_file(ctx, &metadata, walk, manifest),
+                    Err(_) => {}
+                },
+                LinkPolicy::Preserve if !ctx.caps.has(Features::LINKS) => {}
+                LinkPolicy::Preserve => {
+                    if let Ok(target) = std::fs::read_link(&path)
+                        && wire_link_target(&target).is_some()
+                    {
+                        link(manifest, walk);
+                    }
+                }
+            },
+            Ok(t) if t.is_dir() => list_dir(ctx, &path, walk, manifest),
+            Ok(_) => {
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    list_tree_file(ctx, &metadata, walk, manifest);
+                }
+            }
+            Err(_) => {}
+        }
+        walk.rel.pop();
     }
+    walk.ancestors.pop();
+}
 
-    let mut file = File::create(&save_path)?;
+/// A tree file for [`list_dir`]: a link if another hard link was listed.
+fn list_tree_file(
+    ctx: &Context,
+    metadata: &std::fs::Metadata,
+    walk: &mut Walk,
+    manifest: &mut Manifest,
+) {
+    let rel_path = walk.rel_path();
+    let inode = match ctx.caps.has(Features::LINKS) {
+        true => hard_link_id(metadata),
+        false => None,
+    };
+    if let Some(inode) = inode {
+        if walk.inodes.contains_key(&inode) {
+            // Sized, as the file is sent instead if the link is refused.
+            manifest.entries.push(Entry {
+                kind: EntryKind::Link,
+                path: rel_path.lossy(),
+                size: metadata.len(),
+            });
+            return;
+        }
+        walk.inodes.insert(inode, (rel_path.clone(), 0));
+    }
+    manifest.entries.push(Entry {
+        kind: EntryKind::File,
+        path: rel_path.lossy(),
+        size: metadata.len(),
+    });
+}
 
-    let pb = app.create_progress_bar(total);
+/// Send the manifest of `paths` if the peer supports it.
+///
+/// Returns the progress to report while sending, or `None` if the peer
+/// refused the transfer (every path is then recorded as skipped).
+fn announce<S: Read + Write, P: AsRef<Path>>(
+    ctx: &Context,
+    from: String,
+    paths: &[P],
+    stream: &mut S,
+    summary: &mut Summary,
+) -> anyhow::Result<Option<Overall>> {
+    if !ctx.caps.has(Features::MANIFEST) || paths.is_empty() {
+        return Ok(Some(Overall::default()));
+    }
+    let manifest = Manifest {
+        from,
+        ..list_paths(ctx, paths)
+    };
+    println!(
+        "Sending {} files, {}",
+        manifest.files().count(),
+        human_size(manifest.total_size())
+    );
+    manifest.write(stream, ctx.raw_names())?;
+    stream.flush()?;
 
-    println!("Receiving file: {}, size: {} bytes", file_name, total);
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    match &marker {
+        b":mok:" => Ok(Some(Overall::new(&manifest))),
+        b":rej:" => {
+            let err = read_error(stream)?;
+            println!("Peer refused the transfer: {}", err.message);
+            for path in paths {
+                summary.skipped.push(Skipped {
+                    direction: Direction::Sent,
+                    path: path.as_ref().to_path_buf(),
+                    reason: err.message.clone(),
+                });
+            }
+            Ok(None)
+        }
+        b":err:" => Err(read_error(stream)?.into()),
+        _ => Err(ProtocolError::UnexpectedReply(marker).into()),
+    }
+}
 
-    let mut remaining = total;
-    let mut buffer = create_buffer(BUFFER_SIZE);
-    let mut received = 0;
+/// Read a `:man:` frame (marker already consumed), let the app decide on
+/// it and check that the accepted files fit in the download directory.
+///
+/// Answers with `:mok:`, or `:rej:` if everything was rejected; returns
+/// [`InsufficientSpace`] for the runtime to report.
+fn receive_manifest<S: Read + Write>(ctx: &Context, stream: &mut S) -> anyhow::Result<Overall> {
+    let manifest = Manifest::read(stream, ctx.raw_names())?;
+    let download_dir = ctx.download_dir.as_ref();
+    println!(
+        "Incoming {} files, {}{}",
+        manifest.files().count(),
+        human_size(manifest.total_size()),
+        match manifest.from.is_empty() {
+            true => String::new(),
+            false => format!(" from {}", manifest.from),
+        }
+    );
 
-    while remaining > 0 {
-        let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+    let decision = (ctx.approve)(&manifest);
+    let overall = Overall::incoming(&manifest, &decision);
+    if decision == Decision::RejectAll {
+        println!("Rejecting the transfer");
+        write_reject(
+            stream,
+            ErrorCode::Rejected,
+            "Transfer rejected by the receiver",
+        )?;
+        return Ok(overall);
+    }
 
-        let n = stream.read(&mut buffer[..to_read])?;
-        if n == 0 {
-            anyhow::bail!("Unexpected EOF");
+    // Parts that will be resumed are already on disk.
+    let resumable: u64 = manifest
+        .files()
+        .filter(|e| decision.accepts(&e.path))
+        .filter_map(|e| {
+            let path = resolve_rel_path(download_dir, &e.path).ok()?;
+            let len = part_path(&path).metadata().ok()?.len();
+            Some(std::cmp::min(len, e.size))
+        })
+        .sum();
+    let needed = manifest
+        .files()
+        .filter(|e| decision.accepts(&e.path))
+        .map(|e| e.size)
+        .sum::<u64>()
+        .saturating_sub(resumable);
+    if let Some(available) = available_space(download_dir)
+        && needed > available
+    {
+        return Err(InsufficientSpace {
+            path: download_dir.to_path_buf(),
+            needed,
+            available,
         }
+        .into());
+    }
+
+    stream.write_all(b":mok:")?;
+    stream.flush()?;
+    Ok(overall)
+}
+
+fn skip_sent(summary: &mut Summary, path: &Path, reason: &str) {
+    println!("Skipping {}: {}", path.display(), reason);
+    summary.skipped.push(Skipped {
+        direction: Direction::Sent,
+        path: path.to_path_buf(),
+        reason: reason.to_owned(),
+    });
+}
+
+fn send_file<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    path: &Path,
+    marker: &[u8; 5],
+    name: &WireName,
+    stream: &mut S,
+    data: &mut [S],
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open file: {}", path.display()))?;
+    let metadata = file.metadata()?;
+    let total = metadata.len();
+
+    ctx.count_file(total);
+    println!("Sending file: {}, size: {} bytes", path.display(), total);
+    let meta = FileMeta {
+        size: total,
+        mtime: mtime_secs(&metadata),
+        attrs: ctx
+            .caps
+            .has(Features::METADATA)
+            .then(|| Attributes::of(&metadata)),
+    };
+    if ctx.caps.is_legacy() {
+        write_legacy_header(stream, name, total)?;
+    } else {
+        write_header(stream, marker, name, Some(meta), ctx.raw_names())?;
+    }
+    stream.flush()?;
 
-        file.write_all(&buffer[..n])?;
-        remaining -= n as u64;
-        received += n as u64;
+    let reply = match ctx.caps.is_legacy() {
+        true => Reply::Offset(0),
+        false => read_reply(stream)?,
+    };
+    let offset = match reply {
+        Reply::Offset(offset) => offset,
+        Reply::Rejected(err) => {
+            println!("Peer refused {}: {}", path.display(), err.message);
+            summary.skipped.push(Skipped {
+                direction: Direction::Sent,
+                path: path.to_path_buf(),
+                reason: err.message,
+            });
+            return Ok(());
+        }
+    };
+    let pb = (ctx.progress)(total);
+    if offset > total {
+        anyhow::bail!(
+            "Peer requested offset {} beyond end of {} ({} bytes)",
+            offset,
+            path.display(),
+            total
+        );
+    }
+    if offset > 0 {
+        println!("Resuming {} at byte {}", path.display(), offset);
+    }
+    pb.update(offset);
 
-        pb.update(received);
+    let chunked = ctx.caps.has(Features::COMPRESSION);
+    let compress = chunked && should_compress(path, &mut file, offset)?;
+    // With data connections, say whether (and in how many ranges) the
+    // file is split.
+    let parts = range_count(total - offset, data.len());
+    if !data.is_empty() {
+        stream.write_all(&[parts])?;
     }
+    stream.flush()?;
+
+    // The hash covers the whole file, including the part the peer already has.
+    let mut hasher = blake3::Hasher::new();
+    if parts > 0 {
+        let ranges = split_ranges(offset, total, parts);
+        let progress = |sent| pb.update(offset + sent);
+        send_ranges(path, stream, data, &ranges, chunked, compress, &progress)?;
+        hash_prefix(&mut file, &mut hasher, total)?;
+    } else if !chunked
+        && let Some(socket) = stream.plain_socket()
+        && zerocopy::send_file(&file, offset, total - offset, socket, &mut |sent| {
+            pb.update(offset + sent)
+        })?
+    {
+        // The data didn't pass through userspace; hash it from the file.
+        hash_prefix(&mut file, &mut hasher, total)?;
+    } else {
+        hash_prefix(&mut file, &mut hasher, offset)?;
+        let mut buffer = create_buffer(std::cmp::min((total - offset) as usize, BUFFER_SIZE));
+        let mut body = body_writer(&mut *stream, chunked, compress);
+        // Never send more than announced, even if the file grows meanwhile.
+        let mut reader = (&mut file).take(total - offset);
+        let mut i = offset;
+        loop {
+            let read_count = reader.read(&mut buffer)?;
+            if read_count == 0 {
+                break;
+            }
+            body.write_all(&buffer[..read_count])?;
+            hasher.update(&buffer[..read_count]);
 
+            i += read_count as u64;
+            pb.update(i);
+        }
+        body.flush()?;
+        if i != total {
+            anyhow::bail!("File shrank while sending: {}", path.display());
+        }
+    }
+    let hash = *hasher.finalize().as_bytes();
+    if ctx.caps.has(Features::CHECKSUM) {
+        stream.write_all(&hash)?;
+    }
+    stream.flush()?;
     pb.finish();
+    summary.files.push(FileRecord {
+        direction: Direction::Sent,
+        path: path.to_path_buf(),
+        size: total,
+        hash,
+    });
+    Ok(())
+}
 
+/// Feed the first `len` bytes of `file` to `hasher`, leaving the cursor at `len`.
+fn hash_prefix(file: &mut File, hasher: &mut blake3::Hasher, len: u64) -> anyhow::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let copied = std::io::copy(&mut (&mut *file).take(len), hasher)?;
+    if copied != len {
+        anyhow::bail!("File is shorter than expected");
+    }
     Ok(())
 }
 
-pub(crate) fn receiver_receive_file<A: ReceiverApp + ?Sized>(
-    app: &A,
-    stream: &mut A::UpgradeStream,
-) -> anyhow::Result<PathBuf> {
-    // Read name length
-    let mut len_buf = [0u8; 2];
-    stream.read_exact(&mut len_buf)?;
-    let name_len = u16::from_be_bytes(len_buf) as usize;
+/// File attributes carried in a file header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileMeta {
+    size: u64,
+    /// Seconds since the Unix epoch, 0 if unknown
+    mtime: u64,
+    /// Only sent with [`Features::METADATA`]
+    attrs: Option<Attributes>,
+}
 
-    // Read file size
-    let mut size_buf = [0u8; 8];
-    stream.read_exact(&mut size_buf)?;
-    let total = u64::from_be_bytes(size_buf);
+fn mtime_secs(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs())
+}
 
-    // Read filename
-    let mut name_buf = vec![0u8; name_len];
-    stream.read_exact(&mut name_buf)?;
+/// Write `marker | name_len | [size(u64) | mtime(u64) | [attrs]] | name`,
+/// with a raw name prefix (see `name`) if `raw`.
+fn write_header<S: Write>(
+    stream: &mut S,
+    marker: &[u8; 5],
+    name: &WireName,
+    meta: Option<FileMeta>,
+    raw: bool,
+) -> anyhow::Result<()> {
+    let name = name.for_peer(raw);
+    stream.write_all(marker)?;
+    name.write_prefix(stream, raw)?;
+    if let Some(meta) = meta {
+        stream.write_all(&meta.size.to_be_bytes())?;
+        stream.write_all(&meta.mtime.to_be_bytes())?;
+        if let Some(attrs) = meta.attrs {
+            attrs.write(stream)?;
+        }
+    }
+    name.write_name(stream)
+}
+
+/// Write a 1.0 `:fff: | name_len(u16) | size(u64) | name` header.
+fn write_legacy_header<S: Write>(stream: &mut S, name: &WireName, size: u64) -> anyhow::Result<()> {
+    let name = name.for_peer(false);
+    stream.write_all(b":fff:")?;
+    name.write_prefix(stream, false)?;
+    stream.write_all(&size.to_be_bytes())?;
+    name.write_name(stream)
+}
 
-    let file_name = String::from_utf8(name_buf).context("Invalid UTF-8 in file name")?;
+/// Receiver's answer to a file header.
+enum Reply {
+    /// `:off: | offset(u64)`, send the data from `offset`
+    Offset(u64),
+    /// `:rej:`, skip this file
+    Rejected(PeerError),
+}
 
-    let mut save_path = PathBuf::from(app.download_dir());
-    if !save_path.is_dir() {
-        std::fs::create_dir_all(&save_path)
-            .with_context(|| format!("Faild to create directoy: {}", save_path.display()))?;
+/// Read the receiver's reply to a file header.
+fn read_reply<S: Read>(stream: &mut S) -> anyhow::Result<Reply> {
+    let mut marker = [0u8; 5];
+    stream.read_exact(&mut marker)?;
+    match &marker {
+        b":off:" => {}
+        b":rej:" => return Ok(Reply::Rejected(read_error(stream)?)),
+        b":err:" => return Err(read_error(stream)?.into()),
+        _ => return Err(ProtocolError::UnexpectedReply(marker).into()),
     }
-    save_path.push(&file_name);
-    if save_path.exists() {
-        anyhow::bail!("File already exists: {}", save_path.display());
+    let mut offset = [0u8; 8];
+    stream.read_exact(&mut offset)?;
+    Ok(Reply::Offset(u64::from_be_bytes(offset)))
+}
+
+fn write_offset<S: Write>(stream: &mut S, offset: u64) -> anyhow::Result<()> {
+    stream.write_all(b":off:")?;
+    stream.write_all(&offset.to_be_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Write an `:err: | code(u16) | msg_len(u16) | message` frame.
+fn write_error<S: Write>(stream: &mut S, code: ErrorCode, message: &str) -> anyhow::Result<()> {
+    write_error_frame(stream, b":err:", code, message)
+}
+
+/// Refuse the file whose header was just read with a `:rej:` frame.
+fn write_reject<S: Write>(stream: &mut S, code: ErrorCode, message: &str) -> anyhow::Result<()> {
+    write_error_frame(stream, b":rej:", code, message)
+}
+
+fn write_error_frame<S: Write>(
+    stream: &mut S,
+    marker: &[u8; 5],
+    code: ErrorCode,
+    message: &str,
+) -> anyhow::Result<()> {
+    let mut end = std::cmp::min(message.len(), MAX_ERROR_MESSAGE);
+    while !message.is_char_boundary(end) {
+        end -= 1;
     }
+    let message = &message.as_bytes()[..end];
+    stream.write_all(marker)?;
+    stream.write_all(&u16::from(code).to_be_bytes())?;
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message)?;
+    stream.flush()?;
+    Ok(())
+}
 
-    let mut file = File::create(&save_path)?;
+/// Read an `:err:` or `:rej:` frame (marker already consumed).
+pub(crate) fn read_error<S: Read>(stream: &mut S) -> anyhow::Result<PeerError> {
+    let mut buf = [0u8; 2];
+    stream.read_exact(&mut buf)?;
+    let code = ErrorCode::from(u16::from_be_bytes(buf));
+    stream.read_exact(&mut buf)?;
+    let mut message = vec![0u8; u16::from_be_bytes(buf) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(PeerError {
+        code,
+        message: String::from_utf8_lossy(&message).into_owned(),
+    })
+}
 
-    let pb = app.create_progress_bar(total);
+/// Tell the peer why the session is ending, then hand `err` back.
+///
+/// Best effort: the stream may already be broken. Errors that came from
+/// the peer are not echoed back, and 1.0 peers don't know the frame.
+pub(crate) fn report_error<S: Write>(
+    stream: &mut S,
+    caps: Capabilities,
+    err: anyhow::Error,
+) -> anyhow::Error {
+    if !caps.is_legacy() && err.downcast_ref::<PeerError>().is_none() {
+        let _ = write_error(stream, ErrorCode::of(&err), &format!("{:#}", err));
+    }
+    err
+}
 
-    println!("Receiving file: {}, size: {} bytes", file_name, total);
+/// Path of the sidecar file that marks `path` as partially received.
+fn resume_path(path: &Path) -> PathBuf {
+    hidden_sibling(path, RESUME_SUFFIX)
+}
 
-    let mut remaining = total;
-    let mut buffer = create_buffer(BUFFER_SIZE);
-    let mut received = 0;
+/// Path of the file `path` is received into.
+fn part_path(path: &Path) -> PathBuf {
+    hidden_sibling(path, PART_SUFFIX)
+}
 
-    while remaining > 0 {
-        let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+fn hidden_sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
 
-        let n = stream.read(&mut buffer[..to_read])?;
-        if n == 0 {
-            anyhow::bail!("Unexpected EOF");
+/// Remove partial files in `dir` that can't be resumed.
+///
+/// Only `dir` itself is looked at, not the directories below it. A part
+/// is removed if its sidecar is intact but it wasn't written to within
+/// [`PART_MAX_AGE`]; a part without a sidecar wasn't written by us and is
+/// left alone. Sidecars without a part are removed. Best effort: errors
+/// are ignored.
+pub(crate) fn clean_partial_files(dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        let name = entry.file_name();
+        let Some(target) = name.to_str().and_then(|name| name.strip_prefix('.')) else {
+            continue;
+        };
+        if let Some(target) = target.strip_suffix(PART_SUFFIX) {
+            let resume = resume_path(&path.with_file_name(target));
+            if read_resume(&resume).is_none() {
+                continue;
+            }
+            let fresh = entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .is_ok_and(|t| t.elapsed().unwrap_or_default() < PART_MAX_AGE);
+            if fresh {
+                println!("Keeping partial file for resume: {}", path.display());
+                continue;
+            }
+            println!("Removing stale partial file: {}", path.display());
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_file(resume);
+        } else if let Some(target) = target.strip_suffix(RESUME_SUFFIX)
+            && read_resume(&path).is_some()
+            && !part_path(&path.with_file_name(target)).exists()
+        {
+            let _ = std::fs::remove_file(&path);
         }
+    }
+}
 
-        file.write_all(&buffer[..n])?;
-        remaining -= n as u64;
-        received += n as u64;
+/// Content of a resume sidecar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Resume {
+    /// Expected total size
+    size: u64,
+    /// Bytes known to be written without gaps, if less than the file length
+    /// (the file was being received in ranges)
+    valid: Option<u64>,
+}
 
-        pb.update(received);
-    }
+/// Read the resume sidecar at `path`.
+fn read_resume(path: &Path) -> Option<Resume> {
+    let content = std::fs::read_to_string(path).ok()?;
+    let mut lines = content.lines();
+    let size = lines.next()?.strip_prefix("size=")?.parse().ok()?;
+    let valid = match lines.next() {
+        Some(line) => Some(line.strip_prefix("valid=")?.parse().ok()?),
+        None => None,
+    };
+    Some(Resume { size, valid })
+}
 
-    pb.finish();
+fn write_resume(path: &Path, total: u64, valid: Option<u64>) -> anyhow::Result<()> {
+    let content = match valid {
+        Some(valid) => format!("size={}\nvalid={}\n", total, valid),
+        None => format!("size={}\n", total),
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("Failed to write resume file: {}", path.display()))
+}
+
+/// Where an incoming file goes.
+enum Target {
+    /// Write to `path`, starting at `offset`
+    Write { path: PathBuf, offset: u64 },
+    /// Refuse the file
+    Skip(&'static str),
+}
 
-    Ok(save_path)
+/// Decide where to write an incoming file.
+///
+/// A part left behind by an interrupted transfer of the same size is
+/// resumed. Any other existing file is a collision and is handled by the
+/// context's [`CollisionPolicy`].
+fn resolve_target(ctx: &Context, save_path: &Path, meta: FileMeta) -> Target {
+    match read_resume(&resume_path(save_path)) {
+        Some(resume) if resume.size == meta.size && ctx.caps.has(Features::RESUME) => {
+            let len = part_path(save_path)
+                .metadata()
+                .map(|m| m.len())
+                .unwrap_or(0);
+            let len = std::cmp::min(len, resume.valid.unwrap_or(len));
+            let offset = if len <= meta.size { len } else { 0 };
+            return Target::Write {
+                path: save_path.to_path_buf(),
+                offset,
+            };
+        }
+        // The sender's file changed size (or can't resume), start over.
+        Some(_) => {}
+        None if save_path.exists() => return resolve_collision(ctx, save_path, meta),
+        None => {}
+    }
+    Target::Write {
+        path: save_path.to_path_buf(),
+        offset: 0,
+    }
+}
+
+fn resolve_collision(ctx: &Context, save_path: &Path, meta: FileMeta) -> Target {
+    let policy = match ctx.collision {
+        CollisionPolicy::Ask => (ctx.ask_collision)(save_path),
+        policy => policy,
+    };
+    match policy {
+        CollisionPolicy::Overwrite => Target::Write {
+            path: save_path.to_path_buf(),
+            offset: 0,
+        },
+        CollisionPolicy::Rename => match renamed_path(save_path) {
+            Some(path) => Target::Write { path, offset: 0 },
+            None => Target::Skip("No free name for a renamed copy"),
+        },
+        CollisionPolicy::Newer => {
+            let local = save_path.metadata().map(|m| mtime_secs(&m)).unwrap_or(0);
+            if meta.mtime > local {
+                Target::Write {
+                    path: save_path.to_path_buf(),
+                    offset: 0,
+                }
+            } else {
+                Target::Skip("Existing file is newer")
+            }
+        }
+        CollisionPolicy::Skip | CollisionPolicy::Ask => Target::Skip("File already exists"),
+    }
+}
+
+/// First free `name (n).ext` next to `path`, keeping the bytes of a name
+/// that isn't valid Unicode.
+///
+/// Returns `None` when the first [`MAX_RENAMES`] candidates are all taken.
+pub fn renamed_path(path: &Path) -> Option<PathBuf> {
+    let (stem, ext) = split_extension(path.file_name().unwrap_or_default());
+    (1..=MAX_RENAMES)
+        .map(|n| {
+            let mut name = stem.to_os_string();
+            name.push(format!(" ({})", n));
+            if let Some(ext) = ext {
+                name.push(".");
+                name.push(ext);
+            }
+            path.with_file_name(name)
+        })
+        .find(|p| {
+            p.symlink_metadata().is_err() && !resume_path(p).exists() && !part_path(p).exists()
+        })
+}
+
+/// `name` split at its last `.`, unless that starts the name.
+#[cfg(unix)]
+fn split_extension(name: &OsStr) -> (&OsStr, Option<&OsStr>) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let bytes = name.as_bytes();
+    match bytes.iter().rposition(|&b| b == b'.') {
+        Some(dot) if dot > 0 => (
+            OsStr::from_bytes(&bytes[..dot]),
+            Some(OsStr::from_bytes(&bytes[dot + 1..])),
+        ),
+        _ => (name, None),
+    }
+}
+
+/// `name` split at its last `.`, unless that starts the name. A name that
+/// isn't valid Unicode is kept whole.
+#[cfg(not(unix))]
+fn split_extension(name: &OsStr) -> (&OsStr, Option<&OsStr>) {
+    match name.to_str().and_then(|s| s.rsplit_once('.')) {
+        Some((stem, ext)) if !stem.is_empty() => (OsStr::new(stem), Some(OsStr::new(ext))),
+        _ => (name, None),
+    }
+}
+
+/// Check a single name component received from the peer.
+///
+/// The length counts the bytes of the local name; everything else is
+/// checked on its lossy form.
+pub fn check_component(part: &OsStr) -> Result<(), &'static str> {
+    let name = part.to_string_lossy();
+    if name.is_empty() {
+        return Err("empty component");
+    }
+    if name == "." || name == ".." {
+        return Err("relative component");
+    }
+    if part.len() > MAX_NAME_LEN {
+        return Err("name longer than 255 bytes");
+    }
+    if name.contains('\0') {
+        return Err("contains NUL byte");
+    }
+    if name.chars().any(char::is_control) {
+        return Err("contains control character");
+    }
+    if name.ends_with(PART_SUFFIX) || name.ends_with(RESUME_SUFFIX) {
+        return Err("reserved for partial files");
+    }
+    if name.contains(['/', '\\']) {
+        return Err("contains path separator");
+    }
+    // `NUL.txt` and `con .tar.gz` are as reserved as `NUL`.
+    let stem = name.split('.').next().unwrap_or(&name).trim_end();
+    if WINDOWS_RESERVED
+        .iter()
+        .any(|r| r.eq_ignore_ascii_case(stem))
+    {
+        return Err("reserved device name");
+    }
+    if cfg!(windows)
+        && (name.contains(['<', '>', ':', '"', '|', '?', '*']) || name.ends_with(['.', ' ']))
+    {
+        return Err("not allowed on Windows");
+    }
+    Ok(())
+}
+
+/// Resolve a `/` separated relative path from a manifest against `base`.
+fn resolve_rel_path(base: &Path, rel_path: &str) -> Result<PathBuf, InvalidName> {
+    sanitize(base, &WireName::utf8(rel_path), true)
+}
+
+/// Map a name from the wire to a path below `base`.
+///
+/// Every component is checked with [`check_component`], so the result
+/// always stays below `base`. Without `tree` the name must be a single
+/// component.
+fn sanitize(base: &Path, name: &WireName, tree: bool) -> Result<PathBuf, InvalidName> {
+    let invalid = |reason| InvalidName {
+        name: name.lossy(),
+        reason,
+    };
+    if name.len() > MAX_PATH_LEN {
+        return Err(invalid("path longer than 4096 bytes"));
+    }
+    let parts = name.components();
+    if !tree && parts.len() > 1 {
+        return Err(invalid("contains path separator"));
+    }
+    let mut path = base.to_path_buf();
+    for part in &parts {
+        check_component(part).map_err(invalid)?;
+        path.push(part);
+    }
+    Ok(path)
+}
+
+/// Whether `path` or one of its parents below `base` is a symbolic link.
+///
+/// Received data is never written through such a path.
+fn through_symlink(base: &Path, path: &Path) -> bool {
+    let Ok(rel) = path.strip_prefix(base) else {
+        return true;
+    };
+    let mut current = base.to_path_buf();
+    for part in rel.components() {
+        current.push(part);
+        match current.symlink_metadata() {
+            Ok(metadata) if metadata.file_type().is_symlink() => return true,
+            Ok(_) => {}
+            // Nothing below a missing entry exists either.
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+/// Check the target of a symbolic link received for `path`.
+///
+/// The target must be a relative, `/` separated path that stays below
+/// `base` when resolved from the directory of the link. Returns it as a
+/// local path.
+///
+/// Text alone doesn't decide where a link leads once other links exist,
+/// so `..` is only allowed before the first name (a name may be, or later
+/// become, a link), and the target may not go through a symbolic link
+/// that already exists.
+fn check_link_target(base: &Path, path: &Path, target: &WireName) -> Result<PathBuf, &'static str> {
+    if target.is_empty() {
+        return Err("empty link target");
+    }
+    if target.len() > MAX_PATH_LEN {
+        return Err("path longer than 4096 bytes");
+    }
+    let parts = target.components();
+    if parts[0].is_empty() {
+        return Err("absolute link target");
+    }
+    let parent = path.parent().unwrap_or(base);
+    let mut depth = parent
+        .strip_prefix(base)
+        .map_or(0, |rel| rel.components().count());
+    let mut resolved = parent.to_path_buf();
+    let mut named = false;
+    let mut link = PathBuf::new();
+    for (i, part) in parts.iter().enumerate() {
+        match part.to_str() {
+            Some(".") => {}
+            Some("..") if named => return Err("`..` after a name in link target"),
+            Some("..") if depth == 0 => return Err("link points outside the download directory"),
+            Some("..") => {
+                depth -= 1;
+                resolved.pop();
+            }
+            _ => {
+                check_component(part)?;
+                depth += 1;
+                named = true;
+                resolved.push(part);
+                // The link itself may point at a link, but not go through one.
+                if i + 1 < parts.len() && through_symlink(base, &resolved) {
+                    return Err("link target goes through a symbolic link");
+                }
+            }
+        }
+        link.push(part);
+    }
+    Ok(link)
+}
+
+fn ensure_dir(path: &Path) -> anyhow::Result<()> {
+    if !path.is_dir() {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("Faild to create directoy: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Create the directory of a `:dir:` frame.
+///
+/// `:dir:` has no reply, so a refused directory is only logged and
+/// listed as skipped; the files below it are refused one by one.
+fn receive_dir<S: Read>(
+    ctx: &Context,
+    stream: &mut S,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let name = Prefix::read(stream, ctx.raw_names())?.read_name(stream)?;
+
+    let refused = match sanitize(&ctx.download_dir, &name, true) {
+        Ok(save_path) if through_symlink(&ctx.download_dir, &save_path) => {
+            (save_path, "path goes through a symbolic link".to_owned())
+        }
+        Ok(save_path) => match ctx.approve_entry(EntryKind::Directory, &name.lossy(), 0) {
+            Ok(()) => {
+                println!("Receiving directory: {}", save_path.display());
+                ensure_dir(&save_path)?;
+                clean_partial_files(&save_path);
+                return Ok(());
+            }
+            Err(reason) => (save_path, reason.to_owned()),
+        },
+        Err(err) => (PathBuf::from(name.lossy()), err.to_string()),
+    };
+    let (path, reason) = refused;
+    println!("Refusing directory {}: {}", path.display(), reason);
+    summary.skipped.push(Skipped {
+        direction: Direction::Received,
+        path,
+        reason,
+    });
+    Ok(())
+}
+
+/// Read a `:lnk:` frame (marker already consumed) and create the link.
+///
+/// Links that are unsafe or can't be created are refused with `:rej:`.
+fn receive_link<S: Read + Write>(
+    ctx: &Context,
+    stream: &mut S,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind)?;
+    let kind = kind[0];
+    let path_prefix = Prefix::read(stream, ctx.raw_names())?;
+    let target_prefix = Prefix::read(stream, ctx.raw_names())?;
+    let path = path_prefix.read_name(stream)?;
+    let target = target_prefix.read_name(stream)?;
+    if kind != SYMLINK && kind != HARD_LINK {
+        anyhow::bail!("Unknown link kind {}", kind);
+    }
+
+    match create_link(ctx, kind, &path, &target, summary) {
+        Ok(()) => {
+            stream.write_all(b":lnd:")?;
+            stream.flush()?;
+        }
+        Err((code, reason)) => {
+            let name = path.lossy();
+            println!("Refusing link {}: {}", name, reason);
+            write_reject(stream, code, &reason)?;
+            summary.skipped.push(Skipped {
+                direction: Direction::Received,
+                path: PathBuf::from(name),
+                reason,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn create_link(
+    ctx: &Context,
+    kind: u8,
+    path: &WireName,
+    target: &WireName,
+    summary: &mut Summary,
+) -> Result<(), (ErrorCode, String)> {
+    let rejected = |reason: &str| (ErrorCode::Rejected, reason.to_owned());
+    let io_error = |err: std::io::Error| (ErrorCode::Io, err.to_string());
+    let base = ctx.download_dir.as_ref();
+
+    ctx.approve_entry(EntryKind::Link, &path.lossy(), 0)
+        .map_err(rejected)?;
+    let path = sanitize(base, path, true).map_err(|err| rejected(&err.to_string()))?;
+    let parent = path.parent().unwrap_or(base);
+    if through_symlink(base, parent) {
+        return Err(rejected("path goes through a symbolic link"));
+    }
+    if path.symlink_metadata().is_ok() {
+        return Err((ErrorCode::Exists, "File already exists".to_owned()));
+    }
+
+    if kind == SYMLINK {
+        let link = check_link_target(base, &path, target).map_err(rejected)?;
+        ensure_dir(parent).map_err(|err| (ErrorCode::Io, format!("{:#}", err)))?;
+        println!("Receiving link: {} -> {}", path.display(), link.display());
+        return symlink(&link, &path).map_err(io_error);
+    }
+
+    let target_path = sanitize(base, target, true).map_err(|err| rejected(&err.to_string()))?;
+    let record = summary
+        .received()
+        .find(|f| f.path == target_path)
+        .cloned()
+        .filter(|_| !through_symlink(base, &target_path))
+        .ok_or_else(|| rejected("link target wasn't received in this session"))?;
+    ensure_dir(parent).map_err(|err| (ErrorCode::Io, format!("{:#}", err)))?;
+    println!(
+        "Receiving hard link: {} -> {}",
+        path.display(),
+        target_path.display()
+    );
+    std::fs::hard_link(&target_path, &path).map_err(io_error)?;
+    summary.files.push(FileRecord { path, ..record });
+    Ok(())
+}
+
+/// Persist the directory entry of a renamed file. Best effort.
+#[cfg(unix)]
+fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent()
+        && let Ok(dir) = File::open(parent)
+    {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) {}
+
+/// Create a symbolic link with a checked relative `target`.
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+    let resolved = path.parent().unwrap_or(Path::new("")).join(target);
+    match resolved.is_dir() {
+        true => std::os::windows::fs::symlink_dir(target, path),
+        false => std::os::windows::fs::symlink_file(target, path),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Refuse the file whose header was just read.
+///
+/// 1.0 peers send the data without waiting for a reply, so it is read and
+/// dropped to keep the stream in sync.
+fn refuse<S: Read + Write>(
+    ctx: &Context,
+    stream: &mut S,
+    size: u64,
+    code: ErrorCode,
+    message: &str,
+) -> anyhow::Result<()> {
+    if !ctx.caps.is_legacy() {
+        return write_reject(stream, code, message);
+    }
+    let drained = std::io::copy(&mut (&mut *stream).take(size), &mut std::io::sink())?;
+    if drained != size {
+        anyhow::bail!("Unexpected EOF");
+    }
+    Ok(())
+}
+
+fn receive_file<S: Read + Write + Send + PlainSocket>(
+    ctx: &Context,
+    stream: &mut S,
+    data: &mut [S],
+    tree: bool,
+    summary: &mut Summary,
+) -> anyhow::Result<()> {
+    // Read name length
+    let prefix = Prefix::read(stream, ctx.raw_names())?;
+
+    // Read file size and mtime (not sent by 1.0 peers)
+    let mut u64_buf = [0u8; 8];
+    stream.read_exact(&mut u64_buf)?;
+    let total = u64::from_be_bytes(u64_buf);
+    let mut meta = FileMeta {
+        size: total,
+        mtime: 0,
+        attrs: None,
+    };
+    if !ctx.caps.is_legacy() {
+        stream.read_exact(&mut u64_buf)?;
+        meta.mtime = u64::from_be_bytes(u64_buf);
+    }
+    if ctx.caps.has(Features::METADATA) {
+        meta.attrs = Some(Attributes::read(stream)?);
+    }
+
+    // Read filename
+    let name = prefix.read_name(stream)?;
+
+    let download_dir = ctx.download_dir.as_ref();
+    let save_path = match sanitize(download_dir, &name, tree) {
+        Ok(path) => path,
+        Err(err) => {
+            println!("Refusing file: {}", err);
+            refuse(ctx, stream, total, ErrorCode::Rejected, &err.to_string())?;
+            summary.skipped.push(Skipped {
+                direction: Direction::Received,
+                path: PathBuf::from(err.name),
+                reason: err.reason.to_owned(),
+            });
+            return Ok(());
+        }
+    };
+    if through_symlink(download_dir, &save_path) {
+        let reason = "path goes through a symbolic link";
+        println!("Refusing file {}: {}", save_path.display(), reason);
+        refuse(ctx, stream, total, ErrorCode::Rejected, reason)?;
+        summary.skipped.push(Skipped {
+            direction: Direction::Received,
+            path: save_path,
+            reason: reason.to_owned(),
+        });
+        return Ok(());
+    }
+    if let Err(reason) = ctx.approve_entry(EntryKind::File, &name.lossy(), total) {
+        println!("Refusing file {}: {}", save_path.display(), reason);
+        refuse(ctx, stream, total, ErrorCode::Rejected, reason)?;
+        summary.skipped.push(Skipped {
+            direction: Direction::Received,
+            path: save_path,
+            reason: reason.to_owned(),
+        });
+        return Ok(());
+    }
+    ctx.count_file(total);
+    ensure_dir(download_dir)?;
+    if let Some(parent) = save_path.parent() {
+        ensure_dir(parent)?;
+    }
+    let (save_path, offset) = match resolve_target(ctx, &save_path, meta) {
+        Target::Write { path, offset } => (path, offset),
+        Target::Skip(reason) => {
+            println!("Skipping {}: {}", save_path.display(), reason);
+            refuse(ctx, stream, total, ErrorCode::Exists, reason)?;
+            summary.skipped.push(Skipped {
+                direction: Direction::Received,
+                path: save_path,
+                reason: reason.to_owned(),
+            });
+            return Ok(());
+        }
+    };
+    let file_name = save_path
+        .strip_prefix(download_dir)
+        .unwrap_or(&save_path)
+        .display()
+        .to_string();
+    let resume_path = resume_path(&save_path);
+    let part_path = part_path(&save_path);
+    write_resume(&resume_path, total, None)?;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_path)
+        .with_context(|| format!("Failed to open file: {}", part_path.display()))?;
+    file.set_len(offset)?;
+
+    if !ctx.caps.is_legacy() {
+        write_offset(stream, offset)?;
+    }
+    let parts = match data.is_empty() {
+        true => 0,
+        false => {
+            let mut parts = [0u8; 1];
+            stream.read_exact(&mut parts)?;
+            parts[0]
+        }
+    };
+    if parts as usize > data.len() + 1 || (parts > 0 && total - offset < parts as u64) {
+        anyhow::bail!("Peer split {} into {} ranges", file_name, parts);
+    }
+
+    let pb = (ctx.progress)(total);
+
+    if offset > 0 {
+        println!(
+            "Resuming file: {}, size: {} bytes, from byte {}",
+            file_name, total, offset
+        );
+    } else {
+        println!("Receiving file: {}, size: {} bytes", file_name, total);
+    }
+
+    pb.update(offset);
+    let chunked = ctx.caps.has(Features::COMPRESSION);
+    let mut hasher = blake3::Hasher::new();
+    if parts > 0 {
+        let ranges = split_ranges(offset, total, parts);
+        // Ranges arrive out of order; until they are all written, only
+        // the part before `offset` may be resumed.
+        write_resume(&resume_path, total, Some(offset))?;
+        let written: Vec<_> = ranges.iter().map(|_| AtomicU64::new(0)).collect();
+        let progress = |received| pb.update(offset + received);
+        let result = receive_ranges(&file, stream, data, &ranges, chunked, &written, &progress);
+        if let Err(err) = result {
+            let _ = write_resume(
+                &resume_path,
+                total,
+                Some(contiguous_end(offset, &ranges, &written)),
+            );
+            return Err(err);
+        }
+        hash_prefix(&mut file, &mut hasher, total)?;
+    } else if !chunked
+        && let Some(socket) = stream.plain_socket()
+        && zerocopy::receive_file(socket, &file, offset, total - offset, &mut |received| {
+            pb.update(offset + received)
+        })?
+    {
+        hash_prefix(&mut file, &mut hasher, total)?;
+    } else {
+        hash_prefix(&mut file, &mut hasher, offset)?;
+        let mut remaining = total - offset;
+        let mut buffer = create_buffer(BUFFER_SIZE);
+        let mut received = offset;
+        let mut body = body_reader(&mut *stream, chunked, remaining);
+        while remaining > 0 {
+            let to_read = std::cmp::min(buffer.len() as u64, remaining) as usize;
+
+            let n = body.read(&mut buffer[..to_read])?;
+            if n == 0 {
+                anyhow::bail!("Unexpected EOF");
+            }
+
+            file.write_all(&buffer[..n])?;
+            hasher.update(&buffer[..n]);
+            remaining -= n as u64;
+            received += n as u64;
+
+            pb.update(received);
+        }
+    }
+
+    pb.finish();
+
+    let actual = *hasher.finalize().as_bytes();
+    let mut expected = actual;
+    if ctx.caps.has(Features::CHECKSUM) {
+        stream.read_exact(&mut expected)?;
+    }
+
+    let len = file.metadata()?.len();
+    if expected != actual || len != total {
+        drop(file);
+        let _ = std::fs::remove_file(&part_path);
+        let _ = std::fs::remove_file(&resume_path);
+        if len != total {
+            anyhow::bail!("Received {} bytes of {} for {}", len, total, file_name);
+        }
+        return Err(ChecksumMismatch {
+            path: save_path,
+            expected,
+            actual,
+        }
+        .into());
+    }
+
+    // Make the data durable before it shows up under the real name.
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&part_path, &save_path)
+        .with_context(|| format!("Failed to move file into place: {}", save_path.display()))?;
+    sync_parent(&save_path);
+    std::fs::remove_file(&resume_path)
+        .with_context(|| format!("Failed to remove resume file: {}", resume_path.display()))?;
+    if let Some(attrs) = meta.attrs
+        && let Err(err) = attrs.apply(&save_path, meta.mtime)
+    {
+        println!(
+            "Failed to preserve attributes of {}: {}",
+            save_path.display(),
+            err
+        );
+    }
+
+    summary.files.push(FileRecord {
+        direction: Direction::Received,
+        path: save_path,
+        size: total,
+        hash: actual,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    struct NoProgress;
+
+    impl ProgressBar for NoProgress {
+        fn update(&self, _: u64) {}
+        fn finish(&self) {}
+    }
+
+    /// Creates an empty, unique directory under the system temp dir.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fs-share-tf-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn context(download_dir: &Path) -> Context<'static> {
+        context_with(
+            download_dir,
+            CollisionPolicy::default(),
+            Capabilities::LATEST,
+        )
+    }
+
+    fn context_with(
+        download_dir: &Path,
+        collision: CollisionPolicy,
+        caps: Capabilities,
+    ) -> Context<'static> {
+        Context {
+            download_dir: Cow::Owned(download_dir.to_path_buf()),
+            progress: Box::new(|_| Box::new(NoProgress)),
+            collision,
+            ask_collision: Box::new(|_| CollisionPolicy::Skip),
+            links: LinkPolicy::default(),
+            caps,
+            overall: None,
+            approve: Box::new(|_| Decision::AcceptAll),
+        }
+    }
+
+    /// Header fields of a file with unknown attributes, as sent with
+    /// [`Capabilities::LATEST`].
+    fn meta(size: u64) -> FileMeta {
+        FileMeta {
+            size,
+            mtime: 0,
+            attrs: Some(Attributes {
+                mtime_nsec: 0,
+                atime: 0,
+                atime_nsec: 0,
+                mode: 0,
+                uid: crate::attrs::UNKNOWN_ID,
+                gid: crate::attrs::UNKNOWN_ID,
+            }),
+        }
+    }
+
+    /// Sends `src` over a loopback connection and receives it into `dst`.
+    fn transfer(src: &Path, dst: &Path) -> anyhow::Result<()> {
+        transfer_with(
+            &[src],
+            dst,
+            CollisionPolicy::default(),
+            Capabilities::LATEST,
+        )
+        .map(|_| ())
+    }
+
+    /// Sends every path in `srcs`, returns the sender's summary.
+    fn transfer_with(
+        srcs: &[&Path],
+        dst: &Path,
+        collision: CollisionPolicy,
+        caps: Capabilities,
+    ) -> anyhow::Result<Summary> {
+        transfer_links(srcs, dst, collision, caps, LinkPolicy::default())
+    }
+
+    /// Like [`transfer_with`], sending links according to `links`.
+    fn transfer_links(
+        srcs: &[&Path],
+        dst: &Path,
+        collision: CollisionPolicy,
+        caps: Capabilities,
+        links: LinkPolicy,
+    ) -> anyhow::Result<Summary> {
+        transfer_picked(srcs, dst, collision, caps, links, None)
+    }
+
+    /// Like [`transfer_links`]; with `decision`, the receiver only accepts
+    /// what it picks from the manifest of `srcs`.
+    fn transfer_picked(
+        srcs: &[&Path],
+        dst: &Path,
+        collision: CollisionPolicy,
+        caps: Capabilities,
+        links: LinkPolicy,
+        decision: Option<Decision>,
+    ) -> anyhow::Result<Summary> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dst = dst.to_path_buf();
+        let ctx = Context {
+            links,
+            ..context_with(Path::new("."), CollisionPolicy::default(), caps)
+        };
+        let manifest = list_paths(&ctx, srcs);
+
+        let receiver = std::thread::spawn(move || -> anyhow::Result<()> {
+            let (mut stream, _) = listener.accept()?;
+            let overall = decision.map(|d| Overall::incoming(&manifest, &d));
+            let ctx = Context {
+                overall: overall.as_ref(),
+                ..context_with(&dst, collision, caps)
+            };
+            let mut summary = Summary::default();
+            let mut marker = [0u8; 5];
+            while stream.read_exact(&mut marker).is_ok() {
+                match &marker {
+                    b":fff:" => receive_file(&ctx, &mut stream, &mut [], false, &mut summary)?,
+                    b":ffr:" => receive_file(&ctx, &mut stream, &mut [], true, &mut summary)?,
+                    b":dir:" => receive_dir(&ctx, &mut stream, &mut summary)?,
+                    b":lnk:" => receive_link(&ctx, &mut stream, &mut summary)?,
+                    _ => anyhow::bail!("unexpected marker {:?}", marker),
+                }
+            }
+            Ok(())
+        });
+
+        let mut stream = TcpStream::connect(addr)?;
+        let mut summary = Summary::default();
+        let sent = srcs
+            .iter()
+            .try_for_each(|src| send_path(&ctx, src, &mut stream, &mut [], &mut summary));
+        let _ = stream.shutdown(Shutdown::Write);
+        let received = receiver.join().unwrap();
+        sent.and(received).map(|_| summary)
+    }
+
+    #[test]
+    fn resolve_rel_path_joins_components() {
+        let path = resolve_rel_path(Path::new("base"), "a/b/c.txt").unwrap();
+        assert_eq!(path, Path::new("base").join("a").join("b").join("c.txt"));
+    }
+
+    #[test]
+    fn resolve_rel_path_rejects_escaping_components() {
+        for rel in ["../x", "a/../../x", "/etc/passwd", "a//b", "", "./a"] {
+            assert!(
+                resolve_rel_path(Path::new("base"), rel).is_err(),
+                "accepted {rel:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn hostile_names_are_refused() {
+        let long_name = "x".repeat(MAX_NAME_LEN + 1);
+        let long_path = vec!["x"; MAX_PATH_LEN / 2 + 1].join("/");
+        let names = [
+            "../../.bashrc",
+            "..",
+            ".",
+            "",
+            "/etc/passwd",
+            "a\0b",
+            "a\nb",
+            "a\\b",
+            "..\\..\\boot.ini",
+            "C:\\Windows",
+            "CON",
+            "nul.txt",
+            "com1",
+            "Lpt9 .log",
+            long_name.as_str(),
+            "a/b",
+        ];
+        for name in names {
+            assert!(
+                sanitize(Path::new("base"), &WireName::utf8(name), false).is_err(),
+                "accepted {name:?}"
+            );
+        }
+        for name in ["a/../b", "a//b", "/abs", "a/CON/b", long_path.as_str()] {
+            assert!(
+                sanitize(Path::new("base"), &WireName::utf8(name), true).is_err(),
+                "accepted {name:?}"
+            );
+        }
+
+        let reason = |name: &str| {
+            sanitize(Path::new("base"), &WireName::utf8(name), true)
+                .unwrap_err()
+                .reason
+        };
+        assert_eq!(reason(&long_name), "name longer than 255 bytes");
+        assert_eq!(reason(&long_path), "path longer than 4096 bytes");
+    }
+
+    #[test]
+    fn ordinary_names_are_accepted() {
+        let longest = "x".repeat(MAX_NAME_LEN);
+        for name in [
+            ".hidden",
+            "console.log",
+            "a b (1).txt",
+            "ünïcode.txt",
+            &longest,
+        ] {
+            assert_eq!(
+                sanitize(Path::new("base"), &WireName::utf8(name), false).unwrap(),
+                Path::new("base").join(name)
+            );
+        }
+        assert_eq!(
+            sanitize(Path::new("base"), &WireName::utf8("a/b/c"), true).unwrap(),
+            Path::new("base").join("a").join("b").join("c")
+        );
+    }
+
+    #[test]
+    fn refused_file_is_reported_to_sender() {
+        let dst = temp_dir("refused-dst");
+        let mut wire = Vec::new();
+        write_header(
+            &mut wire,
+            b":fff:",
+            &WireName::utf8("../evil.txt"),
+            Some(meta(4)),
+            true,
+        )
+        .unwrap();
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(wire[5..].to_vec()),
+            output: Vec::new(),
+        };
+        let mut summary = Summary::default();
+
+        receive_file(&context(&dst), &mut stream, &mut [], false, &mut summary).unwrap();
+
+        assert_eq!(&stream.output[..5], b":rej:");
+        let err = read_error(&mut &stream.output[5..]).unwrap();
+        assert_eq!(err.code, ErrorCode::Rejected);
+        assert_eq!(summary.skipped.len(), 1);
+        assert!(summary.files.is_empty());
+        assert!(!dst.parent().unwrap().join("evil.txt").exists());
+
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn refused_file_is_skipped_by_sender() {
+        let src = temp_dir("refused-src");
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        let mut reply = Vec::new();
+        write_reject(&mut reply, ErrorCode::Rejected, "not wanted").unwrap();
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(reply),
+            output: Vec::new(),
+        };
+        let mut summary = Summary::default();
+
+        send_file(
+            &context(&src),
+            &src.join("a.txt"),
+            b":fff:",
+            &WireName::utf8("a.txt"),
+            &mut stream,
+            &mut [],
+            &mut summary,
+        )
+        .unwrap();
+
+        // Only the header went out.
+        let mut header = Vec::new();
+        let metadata = src.join("a.txt").metadata().unwrap();
+        let sent = FileMeta {
+            size: 5,
+            mtime: mtime_secs(&metadata),
+            attrs: Some(Attributes::of(&metadata)),
+        };
+        write_header(
+            &mut header,
+            b":fff:",
+            &WireName::utf8("a.txt"),
+            Some(sent),
+            true,
+        )
+        .unwrap();
+        assert_eq!(stream.output, header);
+        assert_eq!(summary.skipped[0].reason, "not wanted");
+        assert!(summary.files.is_empty());
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn directory_tree_round_trip() {
+        let src = temp_dir("tree-src");
+        let dst = temp_dir("tree-dst");
+        std::fs::create_dir_all(src.join("proj/a/b")).unwrap();
+        std::fs::create_dir_all(src.join("proj/empty")).unwrap();
+        std::fs::write(src.join("proj/a/x.txt"), b"hello").unwrap();
+        std::fs::write(src.join("proj/a/b/y.bin"), vec![7u8; 300 * 1024]).unwrap();
+
+        transfer(&src.join("proj"), &dst).unwrap();
+
+        assert_eq!(std::fs::read(dst.join("proj/a/x.txt")).unwrap(), b"hello");
+        assert_eq!(
+            std::fs::read(dst.join("proj/a/b/y.bin")).unwrap(),
+            vec![7u8; 300 * 1024]
+        );
+        assert!(dst.join("proj/empty").is_dir());
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_keep_their_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let src = temp_dir("raw-names-src");
+        let dir = src.join(OsStr::from_bytes(b"caf\xe9"));
+        let file = OsStr::from_bytes(b"n\xff.txt");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(file), b"raw").unwrap();
+        std::os::unix::fs::symlink(file, dir.join("link")).unwrap();
+
+        let raw = temp_dir("raw-names-dst");
+        let summary = transfer_links(
+            &[&dir],
+            &raw,
+            CollisionPolicy::default(),
+            Capabilities::LATEST,
+            LinkPolicy::Preserve,
+        )
+        .unwrap();
+        assert!(summary.skipped.is_empty());
+        let received = raw.join(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(std::fs::read(received.join(file)).unwrap(), b"raw");
+        assert_eq!(std::fs::read_link(received.join("link")).unwrap(), file);
+
+        // A peer without raw names gets the lossy form.
+        let dst = temp_dir("raw-names-lossy");
+        let caps = Capabilities {
+            features: Features::ALL.without(Features::RAW_NAMES),
+            ..Capabilities::LATEST
+        };
+        transfer_with(&[&dir], &dst, CollisionPolicy::default(), caps).unwrap();
+        assert_eq!(
+            std::fs::read(dst.join("caf\u{fffd}/n\u{fffd}.txt")).unwrap(),
+            b"raw"
+        );
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&raw);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn metadata_is_preserved_unless_disabled() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let src = temp_dir("metadata-src");
+        let script = src.join("build.sh");
+        std::fs::write(&script, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o750)).unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_600_000_000, 123_456_789);
+        File::options()
+            .write(true)
+            .open(&script)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        for features in [Features::ALL, Features::ALL.without(Features::METADATA)] {
+            let dst = temp_dir("metadata-dst");
+            let caps = Capabilities {
+                features,
+                ..Capabilities::LATEST
+            };
+            transfer_with(&[&script], &dst, CollisionPolicy::default(), caps).unwrap();
+
+            let received = dst.join("build.sh").metadata().unwrap();
+            let preserved = features.contains(Features::METADATA);
+            assert_eq!(received.permissions().mode() & 0o777 == 0o750, preserved);
+            assert_eq!(received.modified().unwrap() == mtime, preserved);
+            let _ = std::fs::remove_dir_all(&dst);
+        }
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_follow_the_link_policy() {
+        use std::os::unix::fs::symlink;
+
+        let src = temp_dir("symlink-src");
+        std::fs::create_dir_all(src.join("proj/sub")).unwrap();
+        std::fs::write(src.join("proj/sub/a.txt"), b"a").unwrap();
+        symlink("sub/a.txt", src.join("proj/link.txt")).unwrap();
+        symlink("sub", src.join("proj/link-dir")).unwrap();
+        symlink("../../../outside", src.join("proj/sub/escape")).unwrap();
+        symlink("/etc/passwd", src.join("proj/absolute")).unwrap();
+        // A loop is only a problem when following links.
+        symlink("..", src.join("proj/sub/parent")).unwrap();
+
+        let sent = |links| {
+            let dst = temp_dir("symlink-dst");
+            let summary = transfer_links(
+                &[&src.join("proj")],
+                &dst,
+                CollisionPolicy::default(),
+                Capabilities::LATEST,
+                links,
+            )
+            .unwrap();
+            let mut skipped: Vec<_> = summary.skipped.iter().map(|s| s.reason.clone()).collect();
+            skipped.sort();
+            (dst, skipped)
+        };
+
+        let (dst, skipped) = sent(LinkPolicy::Preserve);
+        let proj = dst.join("proj");
+        assert_eq!(
+            std::fs::read_link(proj.join("link.txt")).unwrap(),
+            Path::new("sub/a.txt")
+        );
+        assert_eq!(
+            std::fs::read_link(proj.join("link-dir")).unwrap(),
+            Path::new("sub")
+        );
+        assert_eq!(
+            std::fs::read_link(proj.join("sub/parent")).unwrap(),
+            Path::new("..")
+        );
+        assert!(proj.join("sub/escape").symlink_metadata().is_err());
+        assert_eq!(
+            skipped,
+            vec![
+                "Absolute link target",
+                "link points outside the download directory"
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&dst);
+
+        let (dst, skipped) = sent(LinkPolicy::Follow);
+        let proj = dst.join("proj");
+        assert!(!proj.join("link.txt").is_symlink());
+        assert_eq!(std::fs::read(proj.join("link.txt")).unwrap(), b"a");
+        assert_eq!(std::fs::read(proj.join("link-dir/a.txt")).unwrap(), b"a");
+        assert!(!proj.join("sub/parent").exists());
+        assert!(skipped.contains(&"Symbolic link loop".to_owned()));
+        let _ = std::fs::remove_dir_all(&dst);
+
+        let (dst, skipped) = sent(LinkPolicy::Skip);
+        assert!(dst.join("proj/link.txt").symlink_metadata().is_err());
+        assert_eq!(skipped.len(), 5);
+        let _ = std::fs::remove_dir_all(&dst);
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_are_recreated() {
+        use std::os::unix::fs::MetadataExt;
+
+        let src = temp_dir("hardlink-src");
+        let dst = temp_dir("hardlink-dst");
+        std::fs::create_dir_all(src.join("proj")).unwrap();
+        std::fs::write(src.join("proj/a.bin"), vec![3u8; 100 * 1024]).unwrap();
+        std::fs::hard_link(src.join("proj/a.bin"), src.join("proj/b.bin")).unwrap();
+
+        let summary = transfer_with(
+            &[&src.join("proj")],
+            &dst,
+            CollisionPolicy::default(),
+            Capabilities::LATEST,
+        )
+        .unwrap();
+        assert_eq!(summary.files.len(), 2);
+
+        let a = dst.join("proj/a.bin").metadata().unwrap();
+        let b = dst.join("proj/b.bin").metadata().unwrap();
+        assert_eq!(a.ino(), b.ino());
+        assert_eq!(
+            std::fs::read(dst.join("proj/b.bin")).unwrap(),
+            vec![3u8; 100 * 1024]
+        );
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn manifest_lists_what_is_sent() {
+        let src = temp_dir("manifest-src");
+        std::fs::create_dir_all(src.join("proj/sub")).unwrap();
+        std::fs::write(src.join("top.txt"), b"top").unwrap();
+        std::fs::write(src.join("proj/a.bin"), vec![1u8; 1000]).unwrap();
+        std::fs::hard_link(src.join("proj/a.bin"), src.join("proj/b.bin")).unwrap();
+        std::fs::write(src.join("proj/sub/c.txt"), b"c").unwrap();
+        std::os::unix::fs::symlink("../top.txt", src.join("proj/sub/top")).unwrap();
+
+        let ctx = context(Path::new("."));
+        let manifest = list_paths(&ctx, &[src.join("top.txt"), src.join("proj")]);
+        let entries: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|e| (e.kind, e.path.as_str(), e.size))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (EntryKind::File, "top.txt", 3),
+                (EntryKind::Directory, "proj", 0),
+                (EntryKind::File, "proj/a.bin", 1000),
+                (EntryKind::Link, "proj/b.bin", 1000),
+                (EntryKind::Directory, "proj/sub", 0),
+                (EntryKind::File, "proj/sub/c.txt", 1),
+                (EntryKind::Link, "proj/sub/top", 0),
+            ]
+        );
+        assert_eq!(manifest.total_size(), 1004);
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn manifest_is_approved_and_checked_for_space() {
+        let dst = temp_dir("manifest-dst");
+        let manifest = |size| Manifest {
+            from: "alice@laptop".to_owned(),
+            entries: vec![Entry {
+                kind: EntryKind::File,
+                path: "big.bin".to_owned(),
+                size,
+            }],
+        };
+        let answer = |ctx: &Context, size| {
+            let mut wire = Vec::new();
+            manifest(size).write(&mut wire, true).unwrap();
+            let mut stream = Duplex {
+                input: std::io::Cursor::new(wire[5..].to_vec()),
+                output: Vec::new(),
+            };
+            let overall = receive_manifest(ctx, &mut stream);
+            (overall, stream.output)
+        };
+
+        let (overall, reply) = answer(&context(&dst), 10);
+        assert_eq!(reply, b":mok:");
+        assert_eq!(overall.unwrap().check("big.bin", 10), Ok(()));
+
+        let ctx = Context {
+            approve: Box::new(|m| {
+                assert_eq!(m.from, "alice@laptop");
+                Decision::RejectAll
+            }),
+            ..context(&dst)
+        };
+        let (overall, reply) = answer(&ctx, 10);
+        assert!(reply.starts_with(b":rej:"));
+        assert_eq!(overall.unwrap().check("big.bin", 10), Err("not accepted"));
+
+        if available_space(&dst).is_some() {
+            let (overall, reply) = answer(&context(&dst), u64::MAX);
+            let err = overall.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<InsufficientSpace>().unwrap().needed,
+                u64::MAX
+            );
+            assert!(reply.is_empty());
+        }
+
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn unannounced_files_are_offered_one_by_one() {
+        let dst = temp_dir("approve-dst");
+        // Only the first file is answered with `:off:` and gets data.
+        let mut input = Vec::new();
+        write_header(
+            &mut input,
+            b":fff:",
+            &WireName::utf8("keep.txt"),
+            Some(meta(2)),
+            true,
+        )
+        .unwrap();
+        let mut body = crate::compress::ChunkWriter::new(&mut input, false);
+        body.write_all(b"ok").unwrap();
+        body.flush().unwrap();
+        input.extend_from_slice(blake3::hash(b"ok").as_bytes());
+        write_header(
+            &mut input,
+            b":fff:",
+            &WireName::utf8("drop.txt"),
+            Some(meta(2)),
+            true,
+        )
+        .unwrap();
+        input.drain(..5);
+        let ctx = Context {
+            approve: Box::new(|m| match m.entries[0].path.as_str() {
+                "keep.txt" => Decision::AcceptAll,
+                _ => Decision::RejectAll,
+            }),
+            ..context(&dst)
+        };
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(input),
+            output: Vec::new(),
+        };
+        let mut summary = Summary::default();
+        receive_file(&ctx, &mut stream, &mut [], false, &mut summary).unwrap();
+        let mut marker = [0u8; 5];
+        stream.read_exact(&mut marker).unwrap();
+        receive_file(&ctx, &mut stream, &mut [], false, &mut summary).unwrap();
+
+        assert_eq!(std::fs::read(dst.join("keep.txt")).unwrap(), b"ok");
+        assert!(!dst.join("drop.txt").exists());
+        assert_eq!(summary.skipped[0].reason, "rejected by the receiver");
+
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    /// A `:lnk:` frame as written by [`send_link`].
+    fn link_frame(kind: u8, path: &str, target: &str) -> Vec<u8> {
+        let mut frame = Vec::new();
+        let (path, target) = (WireName::utf8(path), WireName::utf8(target));
+        write_link(&mut frame, kind, &path, &target, true).unwrap();
+        frame.split_off(5)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn received_links_are_not_written_through() {
+        let dst = temp_dir("link-chain").join("download");
+        std::fs::create_dir_all(&dst).unwrap();
+        // Each target stays inside on its own, but `x` would resolve to the
+        // parent of the download directory through `a/b/s`.
+        let mut wire = link_frame(SYMLINK, "a/b/s", "../..");
+        wire.extend(link_frame(SYMLINK, "x", "a/b/s/.."));
+        wire.extend(link_frame(SYMLINK, "abs", "/etc"));
+        wire.extend(link_frame(HARD_LINK, "passwd", "../../etc/passwd"));
+        write_header(
+            &mut wire,
+            b":ffr:",
+            &WireName::utf8("a/b/s/pwned.txt"),
+            Some(meta(5)),
+            true,
+        )
+        .unwrap();
+        wire.extend_from_slice(b"owned");
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(wire),
+            output: Vec::new(),
+        };
+        let ctx = context(&dst);
+        let mut summary = Summary::default();
+
+        for _ in 0..4 {
+            receive_link(&ctx, &mut stream, &mut summary).unwrap();
+        }
+        let mut marker = [0u8; 5];
+        stream.read_exact(&mut marker).unwrap();
+        receive_file(&ctx, &mut stream, &mut [], true, &mut summary).unwrap();
+
+        assert!(dst.join("x").symlink_metadata().is_err());
+        assert!(!dst.join("pwned.txt").exists());
+        assert!(dst.join("abs").symlink_metadata().is_err());
+        assert!(dst.join("passwd").symlink_metadata().is_err());
+        let reasons: Vec<_> = summary.skipped.iter().map(|s| s.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "link target goes through a symbolic link",
+                "absolute link target",
+                "Invalid name \"../../etc/passwd\": relative component",
+                "path goes through a symbolic link"
+            ]
+        );
+
+        let _ = std::fs::remove_dir_all(dst.parent().unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refused_entries_are_not_created() {
+        let dst = temp_dir("refused-entries");
+        let entry = |kind, path: &str| Entry {
+            kind,
+            path: path.to_owned(),
+            size: 0,
+        };
+        let manifest = Manifest {
+            from: String::new(),
+            entries: vec![
+                entry(EntryKind::Directory, "d"),
+                entry(EntryKind::File, "d/a"),
+                entry(EntryKind::Link, "d/l"),
+                entry(EntryKind::Directory, "e"),
+                entry(EntryKind::Link, "e/l"),
+            ],
+        };
+        let mut wire = Vec::new();
+        for dir in ["d", "e"] {
+            write_header(&mut wire, b":dir:", &WireName::utf8(dir), None, true).unwrap();
+        }
+        wire.extend(link_frame(SYMLINK, "d/l", "a"));
+        wire.extend(link_frame(SYMLINK, "e/l", "../d/a"));
+
+        // Everything refused, then only `d/a` and `d/l` picked.
+        let picked = Decision::Pick(HashSet::from(["d/a".to_owned(), "d/l".to_owned()]));
+        for (decision, created) in [(Decision::RejectAll, 0), (picked, 2)] {
+            let overall = Overall::incoming(&manifest, &decision);
+            let ctx = Context {
+                overall: Some(&overall),
+                ..context(&dst)
+            };
+            let mut stream = Duplex {
+                input: std::io::Cursor::new(wire.clone()),
+                output: Vec::new(),
+            };
+            let mut summary = Summary::default();
+            let mut marker = [0u8; 5];
+            for _ in 0..2 {
+                stream.read_exact(&mut marker).unwrap();
+                receive_dir(&ctx, &mut stream, &mut summary).unwrap();
+            }
+            for _ in 0..2 {
+                receive_link(&ctx, &mut stream, &mut summary).unwrap();
+            }
+
+            assert_eq!(dst.join("d").exists(), created > 0);
+            assert_eq!(dst.join("d/l").is_symlink(), created > 0);
+            assert!(!dst.join("e").exists());
+            assert_eq!(summary.skipped.len(), 4 - created);
+            assert!(summary.skipped.iter().all(|s| s.reason == "not accepted"));
+        }
+
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn picked_hard_link_is_received_as_file() {
+        let src = temp_dir("picked-link-src");
+        std::fs::create_dir_all(src.join("proj")).unwrap();
+        std::fs::write(src.join("proj/a.bin"), vec![1u8; 1000]).unwrap();
+        std::fs::hard_link(src.join("proj/a.bin"), src.join("proj/b.bin")).unwrap();
+
+        // Only the link: its file is sent in its place.
+        let dst = temp_dir("picked-link-dst");
+        let decision = Decision::Pick(HashSet::from(["proj/b.bin".to_owned()]));
+        let summary = transfer_picked(
+            &[&src.join("proj")],
+            &dst,
+            CollisionPolicy::default(),
+            Capabilities::LATEST,
+            LinkPolicy::default(),
+            Some(decision),
+        )
+        .unwrap();
+        assert!(!dst.join("proj/a.bin").exists());
+        assert_eq!(std::fs::read(dst.join("proj/b.bin")).unwrap(), [1u8; 1000]);
+        assert_eq!(summary.files.len(), 1);
+
+        // Both, with the link's path taken: the refused link is renamed.
+        std::fs::remove_file(dst.join("proj/b.bin")).unwrap();
+        std::fs::write(dst.join("proj/b.bin"), b"old").unwrap();
+        let both = ["proj", "proj/a.bin", "proj/b.bin"].map(str::to_owned);
+        transfer_picked(
+            &[&src.join("proj")],
+            &dst,
+            CollisionPolicy::Rename,
+            Capabilities::LATEST,
+            LinkPolicy::default(),
+            Some(Decision::Pick(HashSet::from(both))),
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(dst.join("proj/b.bin")).unwrap(), b"old");
+        assert_eq!(
+            std::fs::read(dst.join("proj/b (1).bin")).unwrap(),
+            [1u8; 1000]
+        );
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn link_chains_stay_in_download_dir() {
+        let dst = temp_dir("link-chain-up").join("download");
+        std::fs::create_dir_all(&dst).unwrap();
+        let mut wire = link_frame(SYMLINK, "d/up", "..");
+        // `d/up/..` is the parent of the download directory, not `d`.
+        wire.extend(link_frame(SYMLINK, "esc", "d/up/.."));
+        wire.extend(link_frame(SYMLINK, "deep", "d/up/d"));
+        wire.extend(link_frame(SYMLINK, "alias", "d/up"));
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(wire),
+            output: Vec::new(),
+        };
+        let ctx = context(&dst);
+        let mut summary = Summary::default();
+
+        for _ in 0..4 {
+            receive_link(&ctx, &mut stream, &mut summary).unwrap();
+        }
+
+        assert!(dst.join("d/up").is_symlink());
+        assert!(dst.join("alias").is_symlink());
+        assert!(dst.join("esc").symlink_metadata().is_err());
+        assert!(dst.join("deep").symlink_metadata().is_err());
+        let reasons: Vec<_> = summary.skipped.iter().map(|s| s.reason.as_str()).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "link target goes through a symbolic link",
+                "link target goes through a symbolic link"
+            ]
+        );
+
+        let _ = std::fs::remove_dir_all(dst.parent().unwrap());
+    }
+
+    #[test]
+    fn link_targets_stay_in_download_dir() {
+        let base = Path::new("dl");
+        let link = base.join("a").join("l");
+        assert!(check_link_target(base, &link, &WireName::utf8("b/c.txt")).is_ok());
+        assert!(check_link_target(base, &link, &WireName::utf8("../c.txt")).is_ok());
+        assert!(check_link_target(base, &link, &WireName::utf8("./../c.txt")).is_ok());
+        // `x` may become a link to `..` later on.
+        assert!(check_link_target(base, &link, &WireName::utf8("./x/../../c.txt")).is_err());
+        assert!(check_link_target(base, &link, &WireName::utf8("../../c.txt")).is_err());
+        assert!(check_link_target(base, &link, &WireName::utf8("/etc/passwd")).is_err());
+        assert!(check_link_target(base, &link, &WireName::utf8("a\\..\\..")).is_err());
+        assert!(check_link_target(base, &link, &WireName::utf8("")).is_err());
+    }
+
+    #[test]
+    fn interrupted_file_is_resumed() {
+        let src = temp_dir("resume-src");
+        let dst = temp_dir("resume-dst");
+        let data = (0..500 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        std::fs::write(src.join("big.bin"), &data).unwrap();
+
+        // State left behind by a dropped connection.
+        std::fs::write(part_path(&dst.join("big.bin")), &data[..123_456]).unwrap();
+        write_resume(&resume_path(&dst.join("big.bin")), data.len() as u64, None).unwrap();
+
+        transfer(&src.join("big.bin"), &dst).unwrap();
+
+        assert_eq!(std::fs::read(dst.join("big.bin")).unwrap(), data);
+        assert!(!resume_path(&dst.join("big.bin")).exists());
+        assert!(!part_path(&dst.join("big.bin")).exists());
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn partial_files_are_cleaned_up_unless_resumable() {
+        let dir = temp_dir("clean-parts");
+        std::fs::create_dir_all(dir.join("tree")).unwrap();
+        // Resumable
+        std::fs::write(part_path(&dir.join("a.bin")), b"12").unwrap();
+        write_resume(&resume_path(&dir.join("a.bin")), 10, None).unwrap();
+        // Part without sidecar: not ours
+        std::fs::write(part_path(&dir.join("b.bin")), b"12").unwrap();
+        // Sidecar without part
+        write_resume(&resume_path(&dir.join("c.bin")), 10, None).unwrap();
+        // Resumable, but abandoned long ago
+        let old = part_path(&dir.join("d.bin"));
+        std::fs::write(&old, b"12").unwrap();
+        write_resume(&resume_path(&dir.join("d.bin")), 10, None).unwrap();
+        // Abandoned too, but in a subdirectory
+        let below = part_path(&dir.join("tree/e.bin"));
+        std::fs::write(&below, b"12").unwrap();
+        write_resume(&resume_path(&dir.join("tree/e.bin")), 10, None).unwrap();
+        let long_ago = std::time::SystemTime::now() - PART_MAX_AGE * 2;
+        for part in [&old, &below] {
+            File::options()
+                .write(true)
+                .open(part)
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap();
+        }
+
+        clean_partial_files(&dir);
+
+        assert!(part_path(&dir.join("a.bin")).exists());
+        assert!(resume_path(&dir.join("a.bin")).exists());
+        assert!(part_path(&dir.join("b.bin")).exists());
+        assert!(below.exists());
+        assert!(!resume_path(&dir.join("c.bin")).exists());
+        assert!(!old.exists());
+        assert!(!resume_path(&dir.join("d.bin")).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// In-memory stream: reads from `input`, collects writes in `output`.
+    struct Duplex {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl PlainSocket for Duplex {}
+
+    #[test]
+    fn corrupted_file_is_removed() {
+        let dst = temp_dir("checksum-dst");
+        let mut wire = Vec::new();
+        write_header(
+            &mut wire,
+            b":fff:",
+            &WireName::utf8("a.txt"),
+            Some(meta(5)),
+            true,
+        )
+        .unwrap();
+        let mut body = crate::compress::ChunkWriter::new(&mut wire, false);
+        body.write_all(b"hello").unwrap();
+        body.flush().unwrap();
+        wire.extend_from_slice(blake3::hash(b"hellO").as_bytes());
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(wire[5..].to_vec()),
+            output: Vec::new(),
+        };
+
+        let err = receive_file(
+            &context(&dst),
+            &mut stream,
+            &mut [],
+            false,
+            &mut Summary::default(),
+        )
+        .unwrap_err();
+
+        let err = err.downcast_ref::<ChecksumMismatch>().unwrap();
+        assert_eq!(err.expected, *blake3::hash(b"hellO").as_bytes());
+        assert_eq!(err.actual, *blake3::hash(b"hello").as_bytes());
+        assert!(!dst.join("a.txt").exists());
+        assert!(!part_path(&dst.join("a.txt")).exists());
+        assert!(!resume_path(&dst.join("a.txt")).exists());
+
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn error_frame_round_trip() {
+        let mut wire = Vec::new();
+        write_error(&mut wire, ErrorCode::DiskFull, "no space left").unwrap();
+        assert_eq!(&wire[..5], b":err:");
+
+        let err = read_error(&mut &wire[5..]).unwrap();
+        assert_eq!(err.code, ErrorCode::DiskFull);
+        assert_eq!(err.message, "no space left");
+    }
+
+    #[test]
+    fn error_reply_to_file_header_is_peer_error() {
+        let src = temp_dir("peer-error-src");
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        let mut reply = Vec::new();
+        write_error(&mut reply, ErrorCode::DiskFull, "no space left").unwrap();
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(reply),
+            output: Vec::new(),
+        };
+
+        let err = send_file(
+            &context(&src),
+            &src.join("a.txt"),
+            b":fff:",
+            &WireName::utf8("a.txt"),
+            &mut stream,
+            &mut [],
+            &mut Summary::default(),
+        )
+        .unwrap_err();
+
+        let err = err.downcast_ref::<PeerError>().unwrap();
+        assert_eq!(err.code, ErrorCode::DiskFull);
+        assert_eq!(err.message, "no space left");
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn unknown_reply_to_file_header_is_protocol_error() {
+        let err = read_reply(&mut &b":xyz:\0\0\0\0\0\0\0\0"[..])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(&ProtocolError::UnexpectedReply(*b":xyz:"))
+        );
+    }
+
+    /// Sends `new` over an existing `old` file and returns what ends up on disk.
+    fn collide(name: &str, policy: CollisionPolicy, old_is_newer: bool) -> (Vec<u8>, Vec<PathBuf>) {
+        let src = temp_dir(&format!("{}-src", name));
+        let dst = temp_dir(&format!("{}-dst", name));
+        std::fs::write(src.join("a.txt"), b"new").unwrap();
+        std::fs::write(dst.join("a.txt"), b"old").unwrap();
+        let past = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        let older = if old_is_newer {
+            src.join("a.txt")
+        } else {
+            dst.join("a.txt")
+        };
+        File::options()
+            .write(true)
+            .open(older)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+
+        transfer_with(&[&src.join("a.txt")], &dst, policy, Capabilities::LATEST).unwrap();
+
+        let content = std::fs::read(dst.join("a.txt")).unwrap();
+        let mut files = std::fs::read_dir(&dst)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect::<Vec<_>>();
+        files.sort();
+        let files = files
+            .iter()
+            .map(|p| p.strip_prefix(&dst).unwrap().to_path_buf())
+            .collect();
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+        (content, files)
+    }
+
+    #[test]
+    fn collision_skip_keeps_existing_file() {
+        let (content, files) = collide("skip", CollisionPolicy::Skip, false);
+        assert_eq!(content, b"old");
+        assert_eq!(files, [PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn collision_overwrite_replaces_existing_file() {
+        let (content, files) = collide("overwrite", CollisionPolicy::Overwrite, true);
+        assert_eq!(content, b"new");
+        assert_eq!(files, [PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn collision_rename_keeps_both_files() {
+        let (content, files) = collide("rename", CollisionPolicy::Rename, false);
+        assert_eq!(content, b"old");
+        assert_eq!(files, [PathBuf::from("a (1).txt"), PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn collision_newer_compares_mtime() {
+        assert_eq!(collide("newer-a", CollisionPolicy::Newer, false).0, b"new");
+        assert_eq!(collide("newer-b", CollisionPolicy::Newer, true).0, b"old");
+    }
+
+    #[test]
+    fn collision_ask_uses_app_answer() {
+        // The test context answers every question with `Skip`.
+        assert_eq!(collide("ask", CollisionPolicy::Ask, false).0, b"old");
+    }
+
+    #[test]
+    fn renamed_path_finds_free_name() {
+        let dir = temp_dir("renamed");
+        std::fs::write(dir.join("a.tar.gz"), b"").unwrap();
+        std::fs::write(dir.join("a.tar (1).gz"), b"").unwrap();
+        std::fs::write(dir.join(".hidden"), b"").unwrap();
+
+        assert_eq!(
+            renamed_path(&dir.join("a.tar.gz")),
+            Some(dir.join("a.tar (2).gz"))
+        );
+        assert_eq!(
+            renamed_path(&dir.join(".hidden")),
+            Some(dir.join(".hidden (1)"))
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+
+            let raw = dir.join(OsStr::from_bytes(b"caf\xe9.txt"));
+            std::fs::write(&raw, b"").unwrap();
+            assert_eq!(
+                renamed_path(&raw),
+                Some(dir.join(OsStr::from_bytes(b"caf\xe9 (1).txt")))
+            );
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn legacy_peer_gets_plain_frames() {
+        let src = temp_dir("legacy-src");
+        let dst = temp_dir("legacy-dst");
+        std::fs::write(src.join("a.txt"), b"new").unwrap();
+        std::fs::write(src.join("b.txt"), b"second").unwrap();
+        std::fs::write(dst.join("a.txt"), b"old").unwrap();
+
+        // `a.txt` is skipped without a reply and must be drained.
+        let summary = transfer_with(
+            &[&src.join("a.txt"), &src.join("b.txt")],
+            &dst,
+            CollisionPolicy::Skip,
+            Capabilities::LEGACY,
+        )
+        .unwrap();
+
+        assert_eq!(summary.files.len(), 2);
+        assert_eq!(std::fs::read(dst.join("a.txt")).unwrap(), b"old");
+        assert_eq!(std::fs::read(dst.join("b.txt")).unwrap(), b"second");
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn legacy_frame_layout() {
+        let src = temp_dir("legacy-frame");
+        std::fs::write(src.join("a.txt"), b"hi").unwrap();
+        let mut stream = Duplex {
+            input: std::io::Cursor::new(Vec::new()),
+            output: Vec::new(),
+        };
+        let ctx = context_with(&src, CollisionPolicy::default(), Capabilities::LEGACY);
+
+        send_path(
+            &ctx,
+            &src.join("a.txt"),
+            &mut stream,
+            &mut [],
+            &mut Summary::default(),
+        )
+        .unwrap();
+
+        let mut expected = b":fff:\0\x05\0\0\0\0\0\0\0\x02a.txt".to_vec();
+        expected.extend_from_slice(b"hi");
+        assert_eq!(stream.output, expected);
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn directories_are_skipped_without_feature() {
+        let src = temp_dir("nodirs-src");
+        let dst = temp_dir("nodirs-dst");
+        std::fs::create_dir_all(src.join("proj")).unwrap();
+        std::fs::write(src.join("proj/a.txt"), b"a").unwrap();
+        std::fs::write(src.join("b.txt"), b"b").unwrap();
+        let caps = Capabilities {
+            features: Features::RESUME | Features::CHECKSUM,
+            ..Capabilities::LATEST
+        };
+
+        let summary = transfer_with(
+            &[&src.join("proj"), &src.join("b.txt")],
+            &dst,
+            CollisionPolicy::default(),
+            caps,
+        )
+        .unwrap();
+
+        assert_eq!(summary.skipped[0].path, src.join("proj"));
+        assert!(!dst.join("proj").exists());
+        assert_eq!(std::fs::read(dst.join("b.txt")).unwrap(), b"b");
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn files_round_trip_with_and_without_compression() {
+        let src = temp_dir("compress-src");
+        let text: Vec<u8> = b"GET /index.html 200\n".repeat(40_000);
+        std::fs::write(src.join("access.log"), &text).unwrap();
+        std::fs::write(src.join("archive.zip"), &text).unwrap();
+
+        for features in [Features::ALL, Features::ALL.without(Features::COMPRESSION)] {
+            let dst = temp_dir("compress-dst");
+            let caps = Capabilities {
+                features,
+                ..Capabilities::LATEST
+            };
+            let summary = transfer_with(
+                &[&src.join("access.log"), &src.join("archive.zip")],
+                &dst,
+                CollisionPolicy::default(),
+                caps,
+            )
+            .unwrap();
+
+            assert_eq!(summary.files.len(), 2);
+            assert_eq!(std::fs::read(dst.join("access.log")).unwrap(), text);
+            assert_eq!(std::fs::read(dst.join("archive.zip")).unwrap(), text);
+            let _ = std::fs::remove_dir_all(&dst);
+        }
+
+        let _ = std::fs::remove_dir_all(&src);
+    }
+
+    #[test]
+    fn large_file_is_split_over_data_connections() {
+        let src = temp_dir("parallel-src");
+        let dst = temp_dir("parallel-dst");
+        let size = crate::parallel::PARALLEL_MIN_SIZE + 12345;
+        let content: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src.join("big.bin"), &content).unwrap();
+
+        // Control connection plus two data connections
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut senders, mut receivers) = (Vec::new(), Vec::new());
+        for _ in 0..3 {
+            senders.push(TcpStream::connect(addr).unwrap());
+            receivers.push(listener.accept().unwrap().0);
+        }
+
+        let dst_dir = dst.clone();
+        let receiver = std::thread::spawn(move || -> anyhow::Result<Summary> {
+            let ctx = context(&dst_dir);
+            let (control, data) = receivers.split_first_mut().unwrap();
+            let mut marker = [0u8; 5];
+            control.read_exact(&mut marker)?;
+            let mut summary = Summary::default();
+            receive_file(&ctx, control, data, false, &mut summary)?;
+            Ok(summary)
+        });
+
+        let ctx = context(&src);
+        let (control, data) = senders.split_first_mut().unwrap();
+        let mut sent = Summary::default();
+        send_path(&ctx, &src.join("big.bin"), control, data, &mut sent).unwrap();
+        let received = receiver.join().unwrap().unwrap();
+
+        assert_eq!(std::fs::read(dst.join("big.bin")).unwrap(), content);
+        assert_eq!(sent.files[0].hash, received.files[0].hash);
+        assert!(!resume_path(&dst.join("big.bin")).exists());
+
+        let _ = std::fs::remove_dir_all(&src);
+        let _ = std::fs::remove_dir_all(&dst);
+    }
+
+    #[test]
+    fn split_transfer_resumes_from_contiguous_part() {
+        let dir = temp_dir("parallel-resume");
+        let path = dir.join("big.bin");
+        std::fs::write(part_path(&path), vec![1u8; 100]).unwrap();
+        // Ranges were written up to byte 100, but only 40 bytes without gaps.
+        write_resume(&resume_path(&path), 200, Some(40)).unwrap();
+
+        match resolve_target(&context(&dir), &path, meta(200)) {
+            Target::Write { offset, .. } => assert_eq!(offset, 40),
+            Target::Skip(reason) => panic!("skipped: {}", reason),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,344 @@
+//! # Bandwidth Limiting
+//!
+//! A token bucket [`Throttle`] shared by every connection of a session, and
+//! [`Throttled`], a stream wrapper that waits for tokens before reading or
+//! writing.
+//!
+//! Reads and writes have a bucket each, so the limit applies to both
+//! directions on its own. Tokens refill at the current rate of the
+//! [`RateLimit`]; a bucket holds at most [`BURST`] worth of them, so a
+//! limited stream never sends more than that at once.
+//!
+//! A [`Throttled`] stream with a limit hides its socket from
+//! [`PlainSocket`], so `sendfile`/`splice` can't bypass it.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{manifest::human_size, zerocopy::PlainSocket};
+
+/// Time worth of bytes a bucket holds when full.
+pub const BURST: Duration = Duration::from_millis(100);
+
+/// Smallest amount a stream may move at once, however low the rate.
+const MIN_CHUNK: u64 = 4 * 1024;
+
+/// How long after the last wait a throttle still counts as active.
+const ACTIVE_FOR: Duration = Duration::from_secs(1);
+
+/// Bytes per second allowed, fixed or by time of day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RateLimit {
+    Fixed(u64),
+    Schedule(Schedule),
+}
+
+impl RateLimit {
+    /// Rate at `minute` (minutes since local midnight), `None` if unlimited.
+    pub fn at(&self, minute: u16) -> Option<u64> {
+        match self {
+            Self::Fixed(rate) => Some(*rate),
+            Self::Schedule(schedule) => schedule.at(minute),
+        }
+    }
+
+    /// Rate in effect now (at least one byte per second).
+    pub fn now(&self) -> Option<u64> {
+        self.at(local_minute()).map(|rate| rate.max(1))
+    }
+}
+
+impl fmt::Display for RateLimit {
+    /// `20.0 MB/s`, or `08:00 5.0 MB/s, 18:00 unlimited` for a schedule
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rate = |rate: Option<u64>| match rate {
+            Some(rate) => format!("{}/s", human_size(rate)),
+            None => "unlimited".to_owned(),
+        };
+        match self {
+            Self::Fixed(r) => write!(f, "{}", rate(Some(*r))),
+            Self::Schedule(schedule) => {
+                for (i, (start, r)) in schedule.entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:02}:{:02} {}", start / 60, start % 60, rate(*r))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Rates by time of day.
+///
+/// Each entry applies from its start until the start of the next one,
+/// and the last one until the first one on the next day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    /// Start (minutes since midnight) and rate (`None` for unlimited),
+    /// sorted by start
+    entries: Vec<(u16, Option<u64>)>,
+}
+
+impl Schedule {
+    /// Build a schedule from `(start, rate)` entries in any order.
+    ///
+    /// Returns `None` if there are no entries, a start is past `23:59` or
+    /// two entries start at the same minute.
+    pub fn new(mut entries: Vec<(u16, Option<u64>)>) -> Option<Self> {
+        entries.sort_by_key(|(start, _)| *start);
+        let valid = !entries.is_empty()
+            && entries.iter().all(|(start, _)| *start < 24 * 60)
+            && entries.windows(2).all(|w| w[0].0 != w[1].0);
+        valid.then_some(Self { entries })
+    }
+
+    /// Rate at `minute` (minutes since midnight).
+    pub fn at(&self, minute: u16) -> Option<u64> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= minute)
+            // Before the first entry, the last one of the day before applies.
+            .or(self.entries.last())
+            .and_then(|(_, rate)| *rate)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Self {
+            tokens: 0.0,
+            last: Instant::now(),
+        }
+    }
+}
+
+/// Token buckets for one session.
+#[derive(Debug)]
+pub struct Throttle {
+    limit: RateLimit,
+    read: Mutex<Bucket>,
+    write: Mutex<Bucket>,
+    /// When a stream last had to wait
+    waited: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            read: Mutex::new(Bucket::new()),
+            write: Mutex::new(Bucket::new()),
+            waited: Mutex::new(None),
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// The rate if a stream had to wait for it within the last second.
+    pub fn active(&self) -> Option<u64> {
+        let waited = (*self.waited.lock().unwrap())?;
+        (waited.elapsed() < ACTIVE_FOR)
+            .then(|| self.limit.now())
+            .flatten()
+    }
+
+    /// Most bytes a stream may move at once at `rate`.
+    fn chunk(rate: u64) -> usize {
+        let burst = rate as f64 * BURST.as_secs_f64();
+        std::cmp::max(burst as u64, MIN_CHUNK) as usize
+    }
+
+    /// Take `len` tokens from `bucket`, waiting if the bucket runs dry.
+    ///
+    /// Tokens may go negative; the wait pays the debt off, so concurrent
+    /// streams share the rate.
+    fn take(&self, bucket: &Mutex<Bucket>, rate: u64, len: usize) {
+        let wait = {
+            let mut bucket = bucket.lock().unwrap();
+            let now = Instant::now();
+            let capacity = Self::chunk(rate) as f64;
+            let refill = now.duration_since(bucket.last).as_secs_f64() * rate as f64;
+            bucket.tokens = (bucket.tokens + refill).min(capacity) - len as f64;
+            bucket.last = now;
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / rate as f64),
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            *self.waited.lock().unwrap() = Some(Instant::now());
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Give back tokens taken for bytes that weren't moved.
+    fn refund(bucket: &Mutex<Bucket>, len: usize) {
+        bucket.lock().unwrap().tokens += len as f64;
+    }
+}
+
+/// A stream whose reads and writes are limited by a shared [`Throttle`].
+pub struct Throttled<S> {
+    inner: S,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl<S> Throttled<S> {
+    /// Wrap `inner`; without a throttle it is passed through.
+    pub fn new(inner: S, throttle: Option<Arc<Throttle>>) -> Self {
+        Self { inner, throttle }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(throttle) = &self.throttle else {
+            return self.inner.read(buf);
+        };
+        let Some(rate) = throttle.limit.now() else {
+            return self.inner.read(buf);
+        };
+        // Received bytes can't be held back, pay for them afterwards.
+        let len = std::cmp::min(buf.len(), Throttle::chunk(rate));
+        let n = self.inner.read(&mut buf[..len])?;
+        throttle.take(&throttle.read, rate, n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(throttle) = &self.throttle else {
+            return self.inner.write(buf);
+        };
+        let Some(rate) = throttle.limit.now() else {
+            return self.inner.write(buf);
+        };
+        let len = std::cmp::min(buf.len(), Throttle::chunk(rate));
+        throttle.take(&throttle.write, rate, len);
+        let result = self.inner.write(&buf[..len]);
+        let written = *result.as_ref().unwrap_or(&0);
+        Throttle::refund(&throttle.write, len - written);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: PlainSocket> PlainSocket for Throttled<S> {
+    fn plain_socket(&self) -> Option<&TcpStream> {
+        match self.throttle {
+            Some(_) => None,
+            None => self.inner.plain_socket(),
+        }
+    }
+}
+
+/// Minutes since local midnight.
+#[cfg(all(unix, not(target_os = "android")))]
+fn local_minute() -> u16 {
+    // SAFETY: `time` accepts a null pointer, and `tm` is only read after
+    // `localtime_r` filled it in.
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+        if libc::localtime_r(&now, tm.as_mut_ptr()).is_null() {
+            return utc_minute();
+        }
+        let tm = tm.assume_init();
+        (tm.tm_hour * 60 + tm.tm_min) as u16
+    }
+}
+
+/// Minutes since midnight, in UTC where the local time zone isn't known.
+#[cfg(not(all(unix, not(target_os = "android"))))]
+fn local_minute() -> u16 {
+    utc_minute()
+}
+
+fn utc_minute() -> u16 {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    ((secs / 60) % (24 * 60)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule_wraps_around_midnight() {
+        let schedule = Schedule::new(vec![(18 * 60, None), (8 * 60, Some(1000))]).unwrap();
+        assert_eq!(schedule.at(8 * 60), Some(1000));
+        assert_eq!(schedule.at(17 * 60 + 59), Some(1000));
+        assert_eq!(schedule.at(18 * 60), None);
+        assert_eq!(schedule.at(3 * 60), None);
+
+        assert!(Schedule::new(Vec::new()).is_none());
+        assert!(Schedule::new(vec![(24 * 60, None)]).is_none());
+        assert!(Schedule::new(vec![(60, None), (60, Some(1))]).is_none());
+        assert_eq!(
+            RateLimit::Schedule(schedule).to_string(),
+            "08:00 1000 B/s, 18:00 unlimited"
+        );
+    }
+
+    #[test]
+    fn writes_are_limited_to_the_rate() {
+        let rate = 200 * 1024;
+        let throttle = Arc::new(Throttle::new(RateLimit::Fixed(rate)));
+        let mut stream = Throttled::new(Vec::new(), Some(throttle.clone()));
+        let start = Instant::now();
+        stream.write_all(&vec![0u8; 100 * 1024]).unwrap();
+
+        // Half a second for 100 KiB, minus what may go out right away.
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(stream.get_ref().len(), 100 * 1024);
+        assert_eq!(throttle.active(), Some(rate));
+    }
+
+    #[test]
+    fn unlimited_streams_pass_through() {
+        let throttle = Arc::new(Throttle::new(RateLimit::Schedule(
+            Schedule::new(vec![(0, None)]).unwrap(),
+        )));
+        let mut stream = Throttled::new(Vec::new(), Some(throttle.clone()));
+        stream.write_all(&vec![0u8; 1 << 20]).unwrap();
+        assert_eq!(stream.get_ref().len(), 1 << 20);
+        assert_eq!(throttle.active(), None);
+
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let plain = TcpStream::connect(socket.local_addr().unwrap()).unwrap();
+        let limited = Throttled::new(plain, Some(throttle));
+        assert!(limited.plain_socket().is_none());
+        let plain = Throttled::new(limited.into_inner(), None);
+        assert!(plain.plain_socket().is_some());
+    }
+}
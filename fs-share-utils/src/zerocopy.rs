@@ -0,0 +1,233 @@
+//! # Zero-Copy Transfers
+//!
+//! When file data travels unchanged over a plain TCP connection (no
+//! encryption, no compression chunks), Linux can move it without copying
+//! it through a userspace buffer:
+//!
+//! - the sending side uses `sendfile(2)` from the file to the socket,
+//! - the receiving side uses `splice(2)` from the socket through a pipe
+//!   into the file, at the file offset.
+//!
+//! Streams opt in through [`PlainSocket`]. On other platforms, or if the
+//! kernel refuses the first call, the buffered loop is used instead.
+
+use std::{fs::File, io, net::TcpStream};
+
+/// A stream that may be a plain TCP connection underneath.
+///
+/// Return the socket only if bytes written to it reach the peer unchanged
+/// (e.g. not for TLS or encrypted streams).
+pub trait PlainSocket {
+    /// The socket carrying this stream's bytes as is, if any
+    fn plain_socket(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl PlainSocket for TcpStream {
+    fn plain_socket(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// Send `len` bytes of `file`, starting at `offset`, to `socket`.
+///
+/// `progress` is called with the number of bytes sent so far. Returns
+/// `Ok(false)` without sending anything if zero-copy isn't available, so
+/// the caller can fall back to a buffered copy.
+#[cfg(target_os = "linux")]
+pub fn send_file(
+    file: &File,
+    offset: u64,
+    len: u64,
+    socket: &TcpStream,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let mut pos = offset as libc::off_t;
+    let mut sent = 0u64;
+    while sent < len {
+        let count = std::cmp::min(len - sent, MAX_CHUNK) as usize;
+        // SAFETY: both descriptors are open for the duration of the call
+        // and `pos` is a valid pointer.
+        let n = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut pos, count) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP) if sent == 0 => {
+                    return Ok(false);
+                }
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while sending",
+            ));
+        }
+        sent += n as u64;
+        progress(sent);
+    }
+    Ok(true)
+}
+
+/// Receive `len` bytes from `socket` into `file`, starting at `offset`.
+///
+/// `progress` is called with the number of bytes received so far. Returns
+/// `Ok(false)` without reading anything if zero-copy isn't available.
+#[cfg(target_os = "linux")]
+pub fn receive_file(
+    socket: &TcpStream,
+    file: &File,
+    offset: u64,
+    len: u64,
+    progress: &mut dyn FnMut(u64),
+) -> io::Result<bool> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Ok(false);
+    }
+    // SAFETY: pipe2 succeeded, the descriptors are ours to close.
+    let (pipe_read, pipe_write) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    // A larger pipe means fewer calls; keep the default if this fails.
+    // SAFETY: plain fcntl on a descriptor we own.
+    let capacity = unsafe {
+        libc::fcntl(
+            pipe_write.as_raw_fd(),
+            libc::F_SETPIPE_SZ,
+            MAX_CHUNK as libc::c_int,
+        );
+        libc::fcntl(pipe_write.as_raw_fd(), libc::F_GETPIPE_SZ)
+    };
+    let capacity = if capacity > 0 {
+        capacity as u64
+    } else {
+        64 * 1024
+    };
+
+    let mut pos = offset as libc::loff_t;
+    let mut received = 0u64;
+    while received < len {
+        let count = std::cmp::min(len - received, capacity) as usize;
+        // SAFETY: valid descriptors, null offsets for the socket and pipe.
+        let n = unsafe {
+            libc::splice(
+                socket.as_raw_fd(),
+                std::ptr::null_mut(),
+                pipe_write.as_raw_fd(),
+                std::ptr::null_mut(),
+                count,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_MORE,
+            )
+        };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EINVAL | libc::ENOSYS) if received == 0 => return Ok(false),
+                _ => return Err(err),
+            }
+        }
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        // Drain the pipe into the file before reading more.
+        let mut in_pipe = n as usize;
+        while in_pipe > 0 {
+            // SAFETY: valid descriptors, `pos` is a valid pointer.
+            let m = unsafe {
+                libc::splice(
+                    pipe_read.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    file.as_raw_fd(),
+                    &mut pos,
+                    in_pipe,
+                    libc::SPLICE_F_MOVE,
+                )
+            };
+            if m < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::EINTR) {
+                    continue;
+                }
+                return Err(err);
+            }
+            if m == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            in_pipe -= m as usize;
+        }
+        received += n as u64;
+        progress(received);
+    }
+    Ok(true)
+}
+
+/// Largest amount moved by a single call
+#[cfg(target_os = "linux")]
+const MAX_CHUNK: u64 = 1024 * 1024;
+
+/// Zero-copy isn't available on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(
+    _file: &File,
+    _offset: u64,
+    _len: u64,
+    _socket: &TcpStream,
+    _progress: &mut dyn FnMut(u64),
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Zero-copy isn't available on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn receive_file(
+    _socket: &TcpStream,
+    _file: &File,
+    _offset: u64,
+    _len: u64,
+    _progress: &mut dyn FnMut(u64),
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn sendfile_and_splice_round_trip() {
+        let dir = std::env::temp_dir().join(format!("fs-share-zerocopy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("src.bin"), &data).unwrap();
+        let src = File::open(dir.join("src.bin")).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // Send everything after the first 100 bytes, as a resumed transfer would.
+        let len = data.len() as u64 - 100;
+        let sender = std::thread::spawn(move || send_file(&src, 100, len, &client, &mut |_| {}));
+        let dst = File::create(dir.join("dst.bin")).unwrap();
+        let mut last = 0;
+        assert!(receive_file(&server, &dst, 100, len, &mut |n| last = n).unwrap());
+        assert!(sender.join().unwrap().unwrap());
+
+        assert_eq!(last, len);
+        let received = std::fs::read(dir.join("dst.bin")).unwrap();
+        assert_eq!(&received[100..], &data[100..]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,5 +1,7 @@
-use clap::{Parser, Subcommand};
-use std::{net::SocketAddr, path::PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
+use fs_share_utils::throttle::{RateLimit, Schedule};
+use regex::Regex;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 /// Default UDP broadcast port used for discovery
 const BROADCAST_PORT: u16 = 7755;
@@ -7,11 +9,51 @@ const BROADCAST_PORT: u16 = 7755;
 #[derive(Debug, Parser)]
 #[command(version, about)]
 pub struct Cli {
-    /// Select application mode (send or receive)
+    /// Select application mode (send, receive, serve or peers)
     #[command(subcommand)]
     pub mode: Mode,
 }
 
+/// How the connection is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SecureMode {
+    /// No encryption (compatible with older versions)
+    #[default]
+    None,
+    /// Encrypt with a short pairing code shown on the receiver (SPAKE2)
+    Pake,
+    /// TLS with self-signed certificates, pinned on first use
+    Tls,
+}
+
+/// What to do when a received file already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Keep the existing file
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save as `name (1).ext`
+    #[default]
+    Rename,
+    /// Replace only if the incoming file is newer
+    Newer,
+    /// Ask for every file
+    Ask,
+}
+
+/// How to send symbolic links inside directories
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Links {
+    /// Send the files and directories they point to
+    Follow,
+    /// Recreate the links on the other side
+    #[default]
+    Preserve,
+    /// Leave them out
+    Skip,
+}
+
 /// Available CLI modes
 #[derive(Debug, Subcommand)]
 pub enum Mode {
@@ -21,6 +63,24 @@ pub enum Mode {
         #[arg(short, long)]
         receiver_addr: Option<SocketAddr>,
 
+        /// Send to the discovered receiver with this name, without asking
+        #[arg(long, conflicts_with = "receiver_addr")]
+        to_name: Option<String>,
+
+        /// Send to the first discovered receiver, without asking
+        #[arg(long, conflicts_with = "receiver_addr")]
+        to_first: bool,
+
+        /// Send to the discovered receiver whose listing matches this
+        /// regular expression (e.g. 'OS: linux'), without asking
+        #[arg(long = "match", value_parser = parse_pattern, conflicts_with = "receiver_addr")]
+        pattern: Option<Regex>,
+
+        /// Search for receivers this long (e.g. 5s), then pick the only one
+        /// found [default with --to-name, --to-first or --match: 5s]
+        #[arg(long, value_parser = parse_duration, conflicts_with = "receiver_addr")]
+        discover_timeout: Option<Duration>,
+
         /// Directory where received files will be saved
         #[arg(short, long)]
         download_dir: Option<PathBuf>,
@@ -33,7 +93,40 @@ pub enum Mode {
         #[arg(long, default_value_t = BROADCAST_PORT)]
         broadcast_port: u16,
 
-        /// Files to send
+        /// How to secure the connection (must match the receiver)
+        #[arg(long, value_enum, default_value_t = SecureMode::None)]
+        secure: SecureMode,
+
+        /// Directory holding the TLS identity and known peers
+        #[arg(long)]
+        config_dir: Option<PathBuf>,
+
+        /// What to do when a received file already exists
+        #[arg(long, value_enum, default_value_t = OnConflict::Rename)]
+        on_conflict: OnConflict,
+
+        /// How to send symbolic links inside directories
+        #[arg(long, value_enum, default_value_t = Links::Preserve)]
+        links: Links,
+
+        /// Never compress file data (useful on fast networks)
+        #[arg(long)]
+        disable_compression: bool,
+
+        /// Don't send or apply permissions, timestamps and ownership
+        #[arg(long)]
+        no_preserve: bool,
+
+        /// Extra TCP connections used to split large files (0-16)
+        #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=16))]
+        streams: u8,
+
+        /// Limit bandwidth per direction, in bytes per second (e.g. 20M), or
+        /// by time of day (e.g. 08:00=5M,18:00=off)
+        #[arg(long, value_parser = parse_limit)]
+        limit: Option<RateLimit>,
+
+        /// Files or directories to send
         #[arg()]
         args: Vec<PathBuf>,
     },
@@ -60,9 +153,218 @@ pub enum Mode {
         #[arg(short, long, default_value_t = BROADCAST_PORT)]
         broadcast_port: u16,
 
-        /// Files to send
+        /// How to secure the connection (must match the sender)
+        #[arg(long, value_enum, default_value_t = SecureMode::None)]
+        secure: SecureMode,
+
+        /// Directory holding the TLS identity and known peers
+        #[arg(long)]
+        config_dir: Option<PathBuf>,
+
+        /// What to do when a received file already exists
+        #[arg(long, value_enum, default_value_t = OnConflict::Rename)]
+        on_conflict: OnConflict,
+
+        /// How to send symbolic links inside directories
+        #[arg(long, value_enum, default_value_t = Links::Preserve)]
+        links: Links,
+
+        /// Never compress file data (useful on fast networks)
+        #[arg(long)]
+        disable_compression: bool,
+
+        /// Don't send or apply permissions, timestamps and ownership
+        #[arg(long)]
+        no_preserve: bool,
+
+        /// Accept incoming files without asking
+        #[arg(short = 'y', long)]
+        yes: bool,
+
+        /// Reject incoming transfers larger than this (e.g. 500M, 2G)
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+
+        /// Limit bandwidth per direction, in bytes per second (e.g. 20M), or
+        /// by time of day (e.g. 08:00=5M,18:00=off)
+        #[arg(long, value_parser = parse_limit)]
+        limit: Option<RateLimit>,
+
+        /// Files or directories to send
         #[arg()]
         args: Vec<PathBuf>,
     },
+
+    /// Serve files over HTTP for browsers and phones
+    Serve {
+        /// TCP listener address (IP:PORT) for the HTTP server
+        #[arg(short, long)]
+        tcp_listener_addr: Option<SocketAddr>,
+
+        /// Directory to save uploaded files
+        #[arg(short, long)]
+        download_dir: Option<PathBuf>,
+
+        /// Disable the upload form
+        #[arg(long)]
+        disable_upload: bool,
+
+        /// Disable broadcasting presence (no auto-discovery)
+        #[arg(long)]
+        disable_broadcast: bool,
+
+        /// UDP broadcast port used for discovery
+        #[arg(short, long, default_value_t = BROADCAST_PORT)]
+        broadcast_port: u16,
+
+        /// Files or directories to serve
+        #[arg()]
+        args: Vec<PathBuf>,
+    },
+
+    /// List receivers discoverable from here, without sending anything
+    Peers {
+        /// How long to listen (e.g. 3s) [default: 3s, or until stopped with --watch]
+        #[arg(short, long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+
+        /// Keep listening and update the list as receivers come and go
+        #[arg(short, long)]
+        watch: bool,
+
+        /// Print JSON instead of a table (a line per change with --watch)
+        #[arg(long)]
+        json: bool,
+
+        /// UDP broadcast port used for discovery
+        #[arg(short, long, default_value_t = BROADCAST_PORT)]
+        broadcast_port: u16,
+    },
+}
+
+/// Parse a size such as `512`, `20M` or `1.5G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size: {:?}", s))?;
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size unit: {:?}", unit)),
+    };
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// Parse a bandwidth limit: a rate such as `20M`, or a schedule of
+/// `HH:MM=RATE` entries such as `08:00=5M,18:00=off`.
+pub fn parse_limit(s: &str) -> Result<RateLimit, String> {
+    let rate = |s: &str| match s.trim() {
+        "off" | "none" => Ok(None),
+        s => match parse_size(s)? {
+            0 => Err("a rate of 0 would stop all transfers".to_owned()),
+            rate => Ok(Some(rate)),
+        },
+    };
+    if !s.contains('=') {
+        return rate(s)?
+            .map(RateLimit::Fixed)
+            .ok_or_else(|| format!("invalid limit: {:?}", s));
+    }
+    let entries = s
+        .split(',')
+        .map(|entry| {
+            let (time, r) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected HH:MM=RATE, got {:?}", entry))?;
+            let (hour, minute) = time
+                .trim()
+                .split_once(':')
+                .and_then(|(h, m)| Some((h.parse::<u16>().ok()?, m.parse::<u16>().ok()?)))
+                .filter(|(h, m)| *h < 24 && *m < 60)
+                .ok_or_else(|| format!("invalid time: {:?}", time))?;
+            Ok((hour * 60 + minute, rate(r)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Schedule::new(entries)
+        .map(RateLimit::Schedule)
+        .ok_or_else(|| format!("two entries start at the same time: {:?}", s))
+}
+
+/// Parse a duration such as `500ms`, `5s`, `2m` or `1h` (bare numbers
+/// are seconds).
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let digits = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {:?}", s))?;
+    let secs = match unit.trim() {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("invalid duration unit: {:?}", unit)),
+    };
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("invalid duration: {:?}", s))
+}
+
+/// Compile a `--match` pattern.
+pub fn parse_pattern(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| format!("invalid pattern {:?}: {}", s, e))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("20M"), Ok(20 << 20));
+        assert_eq!(parse_size("1.5G"), Ok(3 << 29));
+        assert_eq!(parse_size("2kb"), Ok(2048));
+        assert_eq!(parse_size("4 GiB"), Ok(4 << 30));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn limits_are_parsed() {
+        assert_eq!(parse_limit("20M"), Ok(RateLimit::Fixed(20 << 20)));
+        let schedule = Schedule::new(vec![(8 * 60, Some(5 << 20)), (18 * 60 + 30, None)]);
+        assert_eq!(
+            parse_limit("08:00=5M, 18:30=off"),
+            Ok(RateLimit::Schedule(schedule.unwrap()))
+        );
+        assert!(parse_limit("0").is_err());
+        assert!(parse_limit("off").is_err());
+        assert!(parse_limit("25:00=1M").is_err());
+        assert!(parse_limit("08:00=1M,08:00=2M").is_err());
+    }
+
+    #[test]
+    fn durations_are_parsed() {
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1.5m"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+}
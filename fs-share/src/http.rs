@@ -0,0 +1,869 @@
+//! # HTTP Server Mode
+//!
+//! Serves files over plain HTTP/1.1 so that browsers and phones can
+//! download them without installing fs-share.
+//!
+//! ## Routes
+//!
+//! ```text
+//! GET  /                 index page with download links and upload form
+//! GET  /f/<index>/<name> file download (supports `Range: bytes=...`)
+//! POST /upload           multipart/form-data upload into the download dir
+//! ```
+//!
+//! Files are addressed by their index in the shared list, so a request
+//! can never reach anything outside of it. Uploaded names are checked like
+//! the ones received from a peer and never replace an existing file, and an
+//! upload larger than the free space of the download dir is refused.
+
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use fs_share_utils::{
+    collision::{check_component, renamed_path},
+    error::InsufficientSpace,
+    manifest::{available_space, human_size},
+};
+
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Maximum size of the request line plus headers
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Connections served at once; more are answered with 503
+const MAX_CONNECTIONS: usize = 32;
+
+/// Names tried for an upload whose free name keeps being taken by others
+const MAX_CREATE_ATTEMPTS: usize = 8;
+
+/// A file offered for download.
+struct SharedFile {
+    /// Name shown on the index page (relative path for directory entries)
+    name: String,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Files and settings shared by all connections.
+pub struct HttpServer {
+    files: Vec<SharedFile>,
+    download_dir: PathBuf,
+    allow_upload: bool,
+}
+
+impl HttpServer {
+    /// Collect `paths` (directories are expanded recursively).
+    pub fn new<P: AsRef<Path>>(
+        paths: impl Iterator<Item = P>,
+        download_dir: PathBuf,
+        allow_upload: bool,
+    ) -> anyhow::Result<Self> {
+        let mut files = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let name = path
+                .file_name()
+                .with_context(|| format!("Invalid file name: {}", path.display()))?
+                .to_string_lossy()
+                .into_owned();
+            collect_files(path, name, &mut files)?;
+        }
+        Ok(Self {
+            files,
+            download_dir,
+            allow_upload,
+        })
+    }
+
+    /// Serve connections from `incoming`, one thread per connection, up to
+    /// [`MAX_CONNECTIONS`] at once.
+    pub fn run<I>(self, incoming: I)
+    where
+        I: Iterator<Item = io::Result<TcpStream>>,
+    {
+        let server = Arc::new(self);
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in incoming {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    eprintln!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let Some(slot) = ConnectionSlot::take(&active) else {
+                // The answer fits in the socket buffer; don't wait on it.
+                let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                let _ = write_response(
+                    &mut &stream,
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "Too many connections\n",
+                    false,
+                );
+                continue;
+            };
+            let server = server.clone();
+            std::thread::spawn(move || {
+                let _slot = slot;
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = server.handle_connection(stream) {
+                    match peer {
+                        Some(peer) => eprintln!("HTTP connection {} failed: {:#}", peer, e),
+                        None => eprintln!("HTTP connection failed: {:#}", e),
+                    }
+                }
+            });
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            let request = match Request::read(&mut reader)? {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+            let keep_alive = request.keep_alive();
+            self.handle_request(&request, &mut reader, &mut writer)?;
+            writer.flush()?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle_request<R: BufRead, W: Write>(
+        &self,
+        request: &Request,
+        body: &mut R,
+        out: &mut W,
+    ) -> anyhow::Result<()> {
+        let head = request.method == "HEAD";
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET" | "HEAD", "/") => {
+                let page = self.index_page();
+                write_response(out, "200 OK", "text/html; charset=utf-8", &page, head)
+            }
+            ("GET" | "HEAD", path) if path.starts_with("/f/") => {
+                let index = path[3..]
+                    .split('/')
+                    .next()
+                    .and_then(|i| i.parse::<usize>().ok());
+                match index.and_then(|i| self.files.get(i)) {
+                    Some(file) => send_file(out, file, request.header("range"), head),
+                    None => not_found(out, head),
+                }
+            }
+            ("POST", "/upload") if self.allow_upload => {
+                let result = self.upload(request, body, out);
+                if let Err(e) = &result {
+                    // Errors writing the file are ours, the rest the client's.
+                    let status = if e.is::<InsufficientSpace>() {
+                        "507 Insufficient Storage"
+                    } else if e.is::<io::Error>() {
+                        "500 Internal Server Error"
+                    } else {
+                        "400 Bad Request"
+                    };
+                    let message = format!("Upload failed: {:#}\n", e);
+                    let _ = write_response(out, status, "text/plain", &message, false);
+                }
+                // The rest of the body is unread, so the connection ends.
+                result
+            }
+            (_, "/upload") | ("GET" | "HEAD", _) => {
+                discard_body(request, body)?;
+                not_found(out, head)
+            }
+            _ => {
+                discard_body(request, body)?;
+                write_response(
+                    out,
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    "Method not allowed\n",
+                    false,
+                )
+            }
+        }
+    }
+
+    fn index_page(&self) -> String {
+        let mut page = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+             <title>fs-share</title></head><body>\n<h1>fs-share</h1>\n<ul>\n",
+        );
+        for (i, file) in self.files.iter().enumerate() {
+            page.push_str(&format!(
+                "<li><a href=\"/f/{}/{}\">{}</a> ({})</li>\n",
+                i,
+                percent_encode(file.name.rsplit('/').next().unwrap_or(&file.name)),
+                html_escape(&file.name),
+                human_size(file.size)
+            ));
+        }
+        page.push_str("</ul>\n");
+        if self.allow_upload {
+            page.push_str(
+                "<h2>Upload</h2>\n<form method=\"post\" action=\"/upload\" \
+                 enctype=\"multipart/form-data\">\n<input type=\"file\" name=\"file\" multiple>\n\
+                 <button type=\"submit\">Upload</button>\n</form>\n",
+            );
+        }
+        page.push_str("</body></html>\n");
+        page
+    }
+
+    fn upload<R: BufRead, W: Write>(
+        &self,
+        request: &Request,
+        body: &mut R,
+        out: &mut W,
+    ) -> anyhow::Result<()> {
+        let boundary = request
+            .header("content-type")
+            .and_then(multipart_boundary)
+            .context("Upload is not multipart/form-data")?;
+        let length = request
+            .content_length()
+            .context("Upload without Content-Length")?;
+
+        // The length includes the multipart framing, so this errs on the
+        // safe side.
+        if let Some(available) = available_space(&self.download_dir)
+            && length > available
+        {
+            return Err(InsufficientSpace {
+                path: self.download_dir.clone(),
+                needed: length,
+                available,
+            }
+            .into());
+        }
+        if !self.download_dir.is_dir() {
+            std::fs::create_dir_all(&self.download_dir).with_context(|| {
+                format!("Faild to create directoy: {}", self.download_dir.display())
+            })?;
+        }
+        let mut body = body.take(length);
+        let saved = receive_multipart(&mut body, &boundary, &self.download_dir)?;
+        // Drain whatever follows the closing boundary.
+        io::copy(&mut body, &mut io::sink())?;
+
+        let mut page = String::from(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>fs-share</title>\
+             </head><body>\n<p>Uploaded:</p>\n<ul>\n",
+        );
+        for (path, size) in &saved {
+            println!("Received upload: {}, size: {} bytes", path.display(), size);
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            page.push_str(&format!(
+                "<li>{} ({})</li>\n",
+                html_escape(&name),
+                human_size(*size)
+            ));
+        }
+        page.push_str("</ul>\n<a href=\"/\">Back</a>\n</body></html>\n");
+        write_response(out, "200 OK", "text/html; charset=utf-8", &page, false)
+    }
+}
+
+/// One of the [`MAX_CONNECTIONS`] connections served at once, released
+/// when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(active.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Add `path` (a file or a directory tree) to `files`.
+fn collect_files(path: &Path, name: String, files: &mut Vec<SharedFile>) -> anyhow::Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)
+            .with_context(|| format!("Failed to read directory: {}", path.display()))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            collect_files(&entry.path(), child, files)?;
+        }
+    } else {
+        let size = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read metadata: {}", path.display()))?
+            .len();
+        files.push(SharedFile {
+            name,
+            path: path.to_path_buf(),
+            size,
+        });
+    }
+    Ok(())
+}
+
+/// Parsed request line and headers.
+struct Request {
+    method: String,
+    path: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Read the next request. Returns `None` when the client closed the connection.
+    fn read<R: BufRead>(reader: &mut R) -> anyhow::Result<Option<Self>> {
+        let mut line = String::new();
+        let mut total = 0;
+        let mut read_line = |line: &mut String| -> anyhow::Result<usize> {
+            line.clear();
+            // One byte over the limit is enough to tell it was exceeded;
+            // a line without `\n` is never buffered beyond that.
+            let limit = (MAX_HEADER_SIZE - total + 1) as u64;
+            let n = reader.by_ref().take(limit).read_line(line)?;
+            total += n;
+            if total > MAX_HEADER_SIZE {
+                anyhow::bail!("Request header too large");
+            }
+            Ok(n)
+        };
+
+        if read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let mut parts = line.split_whitespace();
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(t), Some(v)) => (m.to_owned(), t.to_owned(), v.to_owned()),
+            _ => anyhow::bail!("Malformed request line"),
+        };
+        let path = percent_decode(target.split('?').next().unwrap_or("/"));
+
+        let mut headers = Vec::new();
+        loop {
+            if read_line(&mut line)? == 0 {
+                anyhow::bail!("Unexpected EOF in request header");
+            }
+            let l = line.trim_end();
+            if l.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = l.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+            }
+        }
+        Ok(Some(Self {
+            method,
+            path,
+            version,
+            headers,
+        }))
+    }
+
+    /// Value of header `name` (lowercase)
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.header("content-length")?.parse().ok()
+    }
+
+    fn keep_alive(&self) -> bool {
+        match self.header("connection") {
+            Some(v) if v.eq_ignore_ascii_case("close") => false,
+            Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.version == "HTTP/1.1",
+        }
+    }
+}
+
+fn discard_body<R: BufRead>(request: &Request, body: &mut R) -> anyhow::Result<()> {
+    if let Some(length) = request.content_length() {
+        io::copy(&mut body.take(length), &mut io::sink())?;
+    }
+    Ok(())
+}
+
+fn write_response<W: Write>(
+    out: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &str,
+    head: bool,
+) -> anyhow::Result<()> {
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if !head {
+        out.write_all(body.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn not_found<W: Write>(out: &mut W, head: bool) -> anyhow::Result<()> {
+    write_response(out, "404 Not Found", "text/plain", "Not found\n", head)
+}
+
+/// A byte range requested with `Range: bytes=...`.
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// Serve the whole file
+    Full,
+    /// Inclusive range `start..=end`
+    Partial(u64, u64),
+    /// Range can't be satisfied
+    Unsatisfiable,
+}
+
+/// Parse a single-range `Range` header against a file of `size` bytes.
+///
+/// Multiple ranges and unknown units fall back to the full file.
+fn parse_range(header: Option<&str>, size: u64) -> ByteRange {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(s) if !s.contains(',') => s.trim(),
+        _ => return ByteRange::Full,
+    };
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return ByteRange::Full,
+        // Suffix range: last `n` bytes
+        ("", n) => match n.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (size.saturating_sub(n), size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (s, "") => match s.parse::<u64>() {
+            Ok(s) => (s, size.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (s, e) => match (s.parse::<u64>(), e.parse::<u64>()) {
+            (Ok(s), Ok(e)) if s <= e => (s, std::cmp::min(e, size.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+    if size == 0 || start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
+
+fn send_file<W: Write>(
+    out: &mut W,
+    shared: &SharedFile,
+    range: Option<&str>,
+    head: bool,
+) -> anyhow::Result<()> {
+    let mut file = match File::open(&shared.path) {
+        Ok(f) => f,
+        Err(_) => return not_found(out, head),
+    };
+    let size = file.metadata()?.len();
+    let file_name = shared.name.rsplit('/').next().unwrap_or(&shared.name);
+    let disposition = format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        file_name.replace(['"', '\\'], "_"),
+        percent_encode(file_name)
+    );
+
+    let (status, start, len) = match parse_range(range, size) {
+        ByteRange::Full => ("200 OK", 0, size),
+        ByteRange::Partial(start, end) => ("206 Partial Content", start, end - start + 1),
+        ByteRange::Unsatisfiable => {
+            write!(
+                out,
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\n\
+                 Content-Length: 0\r\n\r\n",
+                size
+            )?;
+            return Ok(());
+        }
+    };
+
+    write!(
+        out,
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\
+         Accept-Ranges: bytes\r\nContent-Disposition: {}\r\n",
+        status, len, disposition
+    )?;
+    if status.starts_with("206") {
+        write!(
+            out,
+            "Content-Range: bytes {}-{}/{}\r\n",
+            start,
+            start + len - 1,
+            size
+        )?;
+    }
+    out.write_all(b"\r\n")?;
+    if head {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(start))?;
+    let copied = io::copy(&mut file.take(len), out)?;
+    if copied != len {
+        anyhow::bail!("File changed while serving: {}", shared.path.display());
+    }
+    Ok(())
+}
+
+/// Extract the boundary from a `multipart/form-data` content type.
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut parts = content_type.split(';');
+    if !parts
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    parts
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| k.eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim_matches('"').to_owned())
+        .filter(|b| !b.is_empty())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Append one read to `buf`. Returns `false` on EOF.
+fn fill_more<R: Read>(reader: &mut R, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let min = buf.len() + 1;
+    fill_at_least(reader, buf, min)
+}
+
+/// Read until `buf.len() >= min`. Returns `false` on EOF before `min`.
+fn fill_at_least<R: Read>(reader: &mut R, buf: &mut Vec<u8>, min: usize) -> io::Result<bool> {
+    while buf.len() < min {
+        if reader.by_ref().take(BUFFER_SIZE as u64).read_to_end(buf)? == 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Stream every file part of a multipart body into `dir`.
+///
+/// Returns the saved paths and their sizes.
+fn receive_multipart<R: Read>(
+    body: &mut R,
+    boundary: &str,
+    dir: &Path,
+) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let part_end = format!("\r\n--{}", boundary).into_bytes();
+    let mut buf = Vec::new();
+    let mut saved = Vec::new();
+
+    // Skip the preamble up to the first delimiter.
+    loop {
+        if let Some(i) = find(&buf, &delimiter) {
+            buf.drain(..i + delimiter.len());
+            break;
+        }
+        let keep = buf.len().saturating_sub(delimiter.len());
+        buf.drain(..keep);
+        if !fill_more(body, &mut buf)? {
+            anyhow::bail!("Multipart body without boundary");
+        }
+    }
+
+    loop {
+        // After a delimiter: `--` ends the body, CRLF starts a part.
+        if !fill_at_least(body, &mut buf, 2)? {
+            anyhow::bail!("Truncated multipart body");
+        }
+        if buf.starts_with(b"--") {
+            return Ok(saved);
+        }
+        if !buf.starts_with(b"\r\n") {
+            anyhow::bail!("Malformed multipart delimiter");
+        }
+        buf.drain(..2);
+
+        // Part headers
+        let headers_end = loop {
+            if let Some(i) = find(&buf, b"\r\n\r\n") {
+                break i;
+            }
+            if buf.len() > MAX_HEADER_SIZE || !fill_more(body, &mut buf)? {
+                anyhow::bail!("Malformed multipart part header");
+            }
+        };
+        let headers = String::from_utf8_lossy(&buf[..headers_end]).into_owned();
+        buf.drain(..headers_end + 4);
+
+        let mut file = match upload_file_name(&headers)? {
+            Some(name) => {
+                let (path, file) = create_unique(dir, &name)?;
+                Some((path, file, 0u64))
+            }
+            None => None,
+        };
+
+        // Part body, up to the next delimiter
+        loop {
+            if let Some(i) = find(&buf, &part_end) {
+                if let Some((_, f, size)) = file.as_mut() {
+                    f.write_all(&buf[..i])?;
+                    *size += i as u64;
+                }
+                buf.drain(..i + part_end.len());
+                break;
+            }
+            let keep = buf.len().saturating_sub(part_end.len());
+            if let Some((_, f, size)) = file.as_mut() {
+                f.write_all(&buf[..keep])?;
+                *size += keep as u64;
+            }
+            buf.drain(..keep);
+            if !fill_more(body, &mut buf)? {
+                if let Some((path, _, _)) = file {
+                    let _ = std::fs::remove_file(path);
+                }
+                anyhow::bail!("Truncated multipart body");
+            }
+        }
+        if let Some((path, _, size)) = file {
+            saved.push((path, size));
+        }
+    }
+}
+
+/// File name of a multipart part, reduced to its last path component.
+///
+/// `None` for a part that isn't a file, or a file input left empty. The
+/// name is refused as a received one would be.
+fn upload_file_name(headers: &str) -> anyhow::Result<Option<String>> {
+    let Some((_, disposition)) = headers
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case("content-disposition"))
+    else {
+        return Ok(None);
+    };
+    let Some((_, name)) = disposition
+        .split(';')
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(k, _)| k.eq_ignore_ascii_case("filename"))
+    else {
+        return Ok(None);
+    };
+    let name = name.trim_matches('"');
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    if name.is_empty() {
+        return Ok(None);
+    }
+    check_component(OsStr::new(name))
+        .map_err(|reason| anyhow::anyhow!("Invalid file name {:?}: {}", name, reason))?;
+    Ok(Some(name.to_owned()))
+}
+
+/// Create `dir/name`, or its first free `name (n).ext` if it exists.
+fn create_unique(dir: &Path, name: &str) -> anyhow::Result<(PathBuf, File)> {
+    let wanted = dir.join(name);
+    let mut path = wanted.clone();
+    // A name taken between the check and the creation is looked up again.
+    for _ in 0..MAX_CREATE_ATTEMPTS {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                path = renamed_path(&wanted)
+                    .with_context(|| format!("No free name for {}", wanted.display()))?;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()));
+            }
+        }
+    }
+    anyhow::bail!("No free name for {}", wanted.display())
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Print the URL(s) the server can be reached at.
+pub fn print_url(addr: SocketAddr) {
+    println!("Serving on http://{}/", addr);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parse_range_variants() {
+        assert_eq!(parse_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            parse_range(Some("bytes=0-9"), 100),
+            ByteRange::Partial(0, 9)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=90-"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-10"), 100),
+            ByteRange::Partial(90, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=50-500"), 100),
+            ByteRange::Partial(50, 99)
+        );
+        assert_eq!(
+            parse_range(Some("bytes=100-"), 100),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), ByteRange::Full);
+        assert_eq!(parse_range(Some("items=0-1"), 100), ByteRange::Full);
+    }
+
+    #[test]
+    fn multipart_upload_is_saved() {
+        let dir = std::env::temp_dir().join(format!("fs-share-http-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), b"existing").unwrap();
+
+        let content = vec![b'x'; 300 * 1024];
+        let mut body = Vec::new();
+        body.extend_from_slice(b"--XyZ\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\n");
+        body.extend_from_slice(b"ignored\r\n--XyZ\r\n");
+        body.extend_from_slice(
+            b"Content-Disposition: form-data; name=\"file\"; filename=\"../a.txt\"\r\n\
+              Content-Type: text/plain\r\n\r\n",
+        );
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+        let saved = receive_multipart(&mut Cursor::new(body), "XyZ", &dir).unwrap();
+
+        assert_eq!(saved, vec![(dir.join("a (1).txt"), content.len() as u64)]);
+        assert_eq!(std::fs::read(dir.join("a (1).txt")).unwrap(), content);
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"existing");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn upload_names_are_checked() {
+        let name = |filename: &str| {
+            upload_file_name(&format!(
+                "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"",
+                filename
+            ))
+        };
+        assert_eq!(name("../a.txt").unwrap().as_deref(), Some("a.txt"));
+        assert_eq!(name("C:\\Users\\b.txt").unwrap().as_deref(), Some("b.txt"));
+        assert_eq!(name("").unwrap(), None);
+        assert_eq!(
+            upload_file_name("Content-Disposition: form-data; name=\"note\"").unwrap(),
+            None
+        );
+        assert!(name("dir/..").is_err());
+        assert!(name("a\x1b[2Jb.txt").is_err());
+        assert!(name("a.txt.fs-share-part").is_err());
+        assert!(name("con.txt").is_err());
+        assert!(name(&"x".repeat(300)).is_err());
+    }
+
+    #[test]
+    fn oversized_header_is_not_buffered() {
+        let line = vec![b'a'; 10 * MAX_HEADER_SIZE];
+        let mut reader = &line[..];
+        assert!(Request::read(&mut reader).is_err());
+        assert!(line.len() - reader.len() <= MAX_HEADER_SIZE + 1);
+    }
+
+    #[test]
+    fn connections_are_capped() {
+        let slots = Arc::new(AtomicUsize::new(0));
+        let taken: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| ConnectionSlot::take(&slots).unwrap())
+            .collect();
+        assert!(ConnectionSlot::take(&slots).is_none());
+        drop(taken);
+        assert!(ConnectionSlot::take(&slots).is_some());
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let name = "my file (1).txt";
+        assert_eq!(percent_decode(&percent_encode(name)), name);
+        assert_eq!(percent_decode("/f/1/a%2"), "/f/1/a%2");
+    }
+}
@@ -1,50 +1,160 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use clap::Parser;
-use fs_share_utils::{receiver::run_v1_0 as run_receiver_app, sender::run_v1_0 as run_sender_app};
+use fs_share_utils::{
+    collision::CollisionPolicy,
+    links::LinkPolicy,
+    protocol::Features,
+    receiver::run_v1_0 as run_receiver_app,
+    sender::{discover, run_v1_0 as run_sender_app},
+    throttle::{RateLimit, Throttle, Throttled},
+};
 use socket2::{Domain, Socket, Type};
 
 use crate::{
-    cli::Mode,
+    cli::{Links, Mode, OnConflict, SecureMode},
+    http::HttpServer,
     pb::{my_pb, no_pb},
-    receiver::ReceiverApp,
+    receiver::{ReceiverApp, advertise, announce, start_discovery},
+    select::Selection,
     sender::{ReceiverData, SenderAppV1},
-    utils::{create_tcp_listener, receiver_upgrade_stream, select_ip, sender_upgrade_stream},
+    stream::Security,
+    tls::TlsConfig,
+    utils::{
+        broadcast_target, create_tcp_listener, multicast_addrs, print_summary, read_line,
+        select_addr,
+    },
 };
 
 mod cli;
+mod http;
+mod pake;
 mod pb;
+mod peers;
 mod receiver;
+mod select;
 mod sender;
+mod stream;
+mod tls;
 mod utils;
 
+impl From<OnConflict> for CollisionPolicy {
+    fn from(value: OnConflict) -> Self {
+        match value {
+            OnConflict::Skip => Self::Skip,
+            OnConflict::Overwrite => Self::Overwrite,
+            OnConflict::Rename => Self::Rename,
+            OnConflict::Newer => Self::Newer,
+            OnConflict::Ask => Self::Ask,
+        }
+    }
+}
+
+impl From<Links> for LinkPolicy {
+    fn from(value: Links) -> Self {
+        match value {
+            Links::Follow => Self::Follow,
+            Links::Preserve => Self::Preserve,
+            Links::Skip => Self::Skip,
+        }
+    }
+}
+
+/// Load (or create) the TLS identity and print its fingerprint.
+fn load_tls_config(config_dir: Option<PathBuf>) -> anyhow::Result<TlsConfig> {
+    let dir = match config_dir {
+        Some(dir) => dir,
+        None => tls::default_config_dir()?,
+    };
+    let config = TlsConfig::load(&dir, tls::device_name())?;
+    println!("Your fingerprint: {}", config.fingerprint());
+    Ok(config)
+}
+
+/// Protocol features to offer, minus the ones disabled on the command line.
+fn features(disable_compression: bool, no_preserve: bool) -> Features {
+    let mut features = Features::ALL;
+    if disable_compression {
+        features = features.without(Features::COMPRESSION);
+    }
+    if no_preserve {
+        features = features.without(Features::METADATA);
+    }
+    features
+}
+
+/// Token buckets for `limit`, shared by every connection of the session.
+fn throttle(limit: Option<RateLimit>) -> Option<Arc<Throttle>> {
+    let limit = limit?;
+    println!("Bandwidth limit: {}", limit);
+    Some(Arc::new(Throttle::new(limit)))
+}
+
 fn main() -> anyhow::Result<()> {
     let cli = cli::Cli::parse();
 
     match cli.mode {
         Mode::Send {
             receiver_addr,
+            to_name,
+            to_first,
+            pattern,
+            discover_timeout,
             download_dir,
             disable_progress,
             broadcast_port,
+            secure,
+            config_dir,
+            on_conflict,
+            links,
+            disable_compression,
+            no_preserve,
+            streams,
+            limit,
             args,
         } => {
+            let security = match secure {
+                SecureMode::None => Security::Plain,
+                SecureMode::Pake => {
+                    Security::Pake(read_line("Enter the pairing code shown on the receiver: ")?)
+                }
+                SecureMode::Tls => Security::Tls(Arc::new(load_tls_config(config_dir)?)),
+            };
+            let secure = !matches!(security, Security::Plain);
+            let throttle = throttle(limit);
+            let pb_throttle = throttle.clone();
             let mut app = SenderAppV1 {
-                broadcast_addr: SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::UNSPECIFIED,
-                    broadcast_port,
-                )),
+                broadcast_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), broadcast_port),
+                multicast_addrs: multicast_addrs(broadcast_port),
                 receiver_addr,
                 download_dir: download_dir.unwrap_or("./".into()),
-                upgrade_stream: Box::new(sender_upgrade_stream),
-                pb: Box::new(my_pb),
+                upgrade_stream: Box::new(move |stream| {
+                    let stream = security.sender_upgrade(stream)?;
+                    Ok(Throttled::new(stream, throttle.clone()))
+                }),
+                secure,
+                pb: Box::new(move |n| my_pb(n, pb_throttle.clone())),
+                collision: on_conflict.into(),
+                links: links.into(),
+                features: features(disable_compression, no_preserve),
+                data_streams: streams,
+                selection: Selection {
+                    name: to_name,
+                    first: to_first,
+                    pattern,
+                    timeout: discover_timeout,
+                },
             };
             if disable_progress {
                 app.pb = Box::new(no_pb);
             }
 
             //run_sender_app::<_, _, _, ReceiverData>(app, args.iter(), TcpStream::connect)?;
-            run_sender_app::<_, _, _, ReceiverData>(app, args.iter(), |addr| {
+            let summary = run_sender_app::<_, _, _, ReceiverData>(app, args.iter(), |addr| {
                 let domain = if addr.is_ipv6() {
                     Domain::IPV6
                 } else {
@@ -58,6 +168,7 @@ fn main() -> anyhow::Result<()> {
                 stream.set_nodelay(true)?;
                 Ok(stream)
             })?;
+            print_summary(&summary);
         }
         Mode::Receive {
             tcp_listener_addr,
@@ -65,30 +176,103 @@ fn main() -> anyhow::Result<()> {
             disable_broadcast,
             disable_progress,
             broadcast_port,
+            secure,
+            config_dir,
+            on_conflict,
+            links,
+            disable_compression,
+            no_preserve,
+            yes,
+            max_size,
+            limit,
             args,
         } => {
-            let addr = match tcp_listener_addr {
-                Some(v) => v,
-                None => {
-                    let ip = select_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
-                    SocketAddr::new(ip, 0)
+            let security = match secure {
+                SecureMode::None => Security::Plain,
+                SecureMode::Pake => {
+                    let code = pake::generate_code()?;
+                    println!("Pairing code: {}", code);
+                    Security::Pake(code)
                 }
+                SecureMode::Tls => Security::Tls(Arc::new(load_tls_config(config_dir)?)),
             };
+            let secure = !matches!(security, Security::Plain);
+            let addr = tcp_listener_addr
+                .or_else(select_addr)
+                .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+            let throttle = throttle(limit);
+            let pb_throttle = throttle.clone();
             let mut app = ReceiverApp {
-                broadcast_addr: SocketAddr::V4(SocketAddrV4::new(
-                    Ipv4Addr::BROADCAST,
-                    broadcast_port,
-                )),
+                broadcast_addr: broadcast_target(addr, broadcast_port),
                 download_dir: download_dir.unwrap_or("./".into()),
                 disable_broadcaster: disable_broadcast,
-                upgrade_stream: Box::new(receiver_upgrade_stream),
-                pb: Box::new(my_pb),
+                upgrade_stream: Box::new(move |stream| {
+                    let stream = security.receiver_upgrade(stream)?;
+                    Ok(Throttled::new(stream, throttle.clone()))
+                }),
+                secure,
+                pb: Box::new(move |n| my_pb(n, pb_throttle.clone())),
+                collision: on_conflict.into(),
+                links: links.into(),
+                features: features(disable_compression, no_preserve),
+                yes,
+                max_size,
             };
 
             if disable_progress {
                 app.pb = Box::new(no_pb);
             }
-            run_receiver_app(app, args.iter(), |_| create_tcp_listener(addr))?;
+            let summary = run_receiver_app(app, args.iter(), |_| create_tcp_listener(addr))?;
+            print_summary(&summary);
+        }
+        Mode::Serve {
+            tcp_listener_addr,
+            download_dir,
+            disable_upload,
+            disable_broadcast,
+            broadcast_port,
+            args,
+        } => {
+            let server = HttpServer::new(
+                args.iter(),
+                download_dir.unwrap_or("./".into()),
+                !disable_upload,
+            )?;
+            let addr = tcp_listener_addr
+                .or_else(select_addr)
+                .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+            let (listener_addr, incoming) = create_tcp_listener(addr)?;
+            http::print_url(listener_addr);
+
+            // Same fields as a receiver plus the service, so other
+            // instances list it but don't try to send to it.
+            let _broadcaster = (!disable_broadcast).then(|| {
+                let broadcaster = announce(
+                    "v1.fs-share",
+                    broadcast_target(listener_addr, broadcast_port),
+                    listener_addr,
+                )
+                .add_field("http")
+                .build();
+                let advertiser = advertise(listener_addr).add_txt("service", "http").build();
+                start_discovery(broadcaster, advertiser)
+            });
+            server.run(incoming);
+        }
+        Mode::Peers {
+            duration,
+            watch,
+            json,
+            broadcast_port,
+        } => {
+            // Every announcement, not just new ones, for the last seen times
+            let discovery = discover::<ReceiverData>(
+                "v1.fs-share",
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), broadcast_port),
+                &multicast_addrs(broadcast_port),
+                false,
+            )?;
+            peers::run(discovery, duration, watch, json)?;
         }
     }
     Ok(())
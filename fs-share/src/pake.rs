@@ -0,0 +1,408 @@
+//! # Pairing Code Encryption (SPAKE2)
+//!
+//! Authenticated, encrypted stream derived from a short pairing code.
+//!
+//! The receiver shows a code, the sender types it in. Both sides run
+//! SPAKE2 over Ristretto255 with the code as password, confirm the
+//! resulting key and then exchange ChaCha20-Poly1305 records. A wrong
+//! code fails the key confirmation, and an eavesdropper can't run an
+//! offline dictionary attack against the code.
+//!
+//! ## Handshake
+//!
+//! ```text
+//! sender   -> X = x*G + w*M      (32 bytes)
+//! receiver -> Y = y*G + w*N      (32 bytes)
+//! sender   -> HMAC(k_sender, X | Y)
+//! receiver -> HMAC(k_receiver, X | Y)
+//! ```
+//!
+//! ## Records
+//!
+//! ```text
+//! len(u32) | ciphertext (len bytes, includes 16 byte tag)
+//! ```
+//!
+//! Each direction has its own key and a 64-bit record counter as nonce.
+
+use std::io::{self, Read, Write};
+
+use anyhow::Context;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, aead::Aead};
+use curve25519_dalek::{
+    RistrettoPoint, Scalar, constants::RISTRETTO_BASEPOINT_POINT, ristretto::CompressedRistretto,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Maximum plaintext bytes per record
+const RECORD_SIZE: usize = 64 * 1024;
+
+/// Poly1305 tag size
+const TAG_SIZE: usize = 16;
+
+const DOMAIN: &[u8] = b"fs-share-spake2-v1";
+
+/// Generate a six digit pairing code formatted as `123-456`.
+pub fn generate_code() -> anyhow::Result<String> {
+    let mut bytes = [0u8; 4];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("Failed to generate code: {}", e))?;
+    let n = u32::from_be_bytes(bytes) % 1_000_000;
+    Ok(format!("{:03}-{:03}", n / 1000, n % 1000))
+}
+
+/// Normalize user input so `123 456`, `123456` and `123-456` match.
+fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+}
+
+/// Hash a label to a Ristretto point with unknown discrete log.
+fn hash_to_point(label: &[u8]) -> RistrettoPoint {
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(
+        &Sha512::new()
+            .chain_update(DOMAIN)
+            .chain_update(label)
+            .finalize(),
+    );
+    RistrettoPoint::from_uniform_bytes(&bytes)
+}
+
+fn password_scalar(code: &str) -> Scalar {
+    let mut bytes = [0u8; 64];
+    bytes.copy_from_slice(
+        &Sha512::new()
+            .chain_update(DOMAIN)
+            .chain_update(b"password")
+            .chain_update(normalize_code(code).as_bytes())
+            .finalize(),
+    );
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+fn random_scalar() -> anyhow::Result<Scalar> {
+    let mut bytes = [0u8; 64];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow::anyhow!("Failed to generate key: {}", e))?;
+    Ok(Scalar::from_bytes_mod_order_wide(&bytes))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Sender,
+    Receiver,
+}
+
+/// Keys derived from the SPAKE2 shared secret.
+struct SessionKeys {
+    confirm_sender: [u8; 32],
+    confirm_receiver: [u8; 32],
+    sender_to_receiver: [u8; 32],
+    receiver_to_sender: [u8; 32],
+}
+
+impl SessionKeys {
+    fn derive(shared: &RistrettoPoint, x: &[u8; 32], y: &[u8; 32]) -> Self {
+        let mut ikm = Vec::with_capacity(96);
+        ikm.extend_from_slice(shared.compress().as_bytes());
+        ikm.extend_from_slice(x);
+        ikm.extend_from_slice(y);
+        let hk = Hkdf::<Sha256>::new(Some(DOMAIN), &ikm);
+        let expand = |info: &[u8]| {
+            let mut key = [0u8; 32];
+            hk.expand(info, &mut key)
+                .expect("32 bytes is a valid HKDF length");
+            key
+        };
+        Self {
+            confirm_sender: expand(b"confirm sender"),
+            confirm_receiver: expand(b"confirm receiver"),
+            sender_to_receiver: expand(b"sender to receiver"),
+            receiver_to_sender: expand(b"receiver to sender"),
+        }
+    }
+}
+
+fn confirmation(key: &[u8; 32], x: &[u8; 32], y: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(x);
+    mac.update(y);
+    mac
+}
+
+/// Run the sender side of the pairing handshake.
+pub fn sender_handshake<S: Read + Write>(stream: S, code: &str) -> anyhow::Result<PakeStream<S>> {
+    handshake(stream, code, Role::Sender)
+}
+
+/// Run the receiver side of the pairing handshake.
+pub fn receiver_handshake<S: Read + Write>(stream: S, code: &str) -> anyhow::Result<PakeStream<S>> {
+    handshake(stream, code, Role::Receiver)
+}
+
+fn handshake<S: Read + Write>(
+    mut stream: S,
+    code: &str,
+    role: Role,
+) -> anyhow::Result<PakeStream<S>> {
+    let w = password_scalar(code);
+    let m = hash_to_point(b"M");
+    let n = hash_to_point(b"N");
+    let (own_blind, peer_blind) = match role {
+        Role::Sender => (m, n),
+        Role::Receiver => (n, m),
+    };
+
+    let secret = random_scalar()?;
+    let own = (secret * RISTRETTO_BASEPOINT_POINT + w * own_blind)
+        .compress()
+        .to_bytes();
+    stream
+        .write_all(&own)
+        .context("Failed to send pairing message")?;
+    stream.flush()?;
+
+    let mut peer = [0u8; 32];
+    stream
+        .read_exact(&mut peer)
+        .context("Failed to read pairing message")?;
+    let peer_point = CompressedRistretto(peer)
+        .decompress()
+        .context("Invalid pairing message from peer")?;
+    let shared = secret * (peer_point - w * peer_blind);
+
+    let (x, y) = match role {
+        Role::Sender => (own, peer),
+        Role::Receiver => (peer, own),
+    };
+    let keys = SessionKeys::derive(&shared, &x, &y);
+    let (own_confirm, peer_confirm, send_key, recv_key) = match role {
+        Role::Sender => (
+            keys.confirm_sender,
+            keys.confirm_receiver,
+            keys.sender_to_receiver,
+            keys.receiver_to_sender,
+        ),
+        Role::Receiver => (
+            keys.confirm_receiver,
+            keys.confirm_sender,
+            keys.receiver_to_sender,
+            keys.sender_to_receiver,
+        ),
+    };
+
+    let tag = confirmation(&own_confirm, &x, &y).finalize().into_bytes();
+    stream
+        .write_all(&tag)
+        .context("Failed to send key confirmation")?;
+    stream.flush()?;
+
+    let mut peer_tag = [0u8; 32];
+    stream
+        .read_exact(&mut peer_tag)
+        .context("Failed to read key confirmation")?;
+    confirmation(&peer_confirm, &x, &y)
+        .verify_slice(&peer_tag)
+        .map_err(|_| anyhow::anyhow!("Pairing failed: the peer used a different code"))?;
+
+    Ok(PakeStream {
+        inner: stream,
+        sealer: ChaCha20Poly1305::new(&send_key.into()),
+        opener: ChaCha20Poly1305::new(&recv_key.into()),
+        send_counter: 0,
+        recv_counter: 0,
+        read_buf: Vec::new(),
+        read_pos: 0,
+        write_buf: Vec::with_capacity(RECORD_SIZE),
+    })
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+/// Encrypted stream produced by the pairing handshake.
+pub struct PakeStream<S> {
+    inner: S,
+    sealer: ChaCha20Poly1305,
+    opener: ChaCha20Poly1305,
+    send_counter: u64,
+    recv_counter: u64,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write_buf: Vec<u8>,
+}
+
+impl<S: Read + Write> PakeStream<S> {
+    /// Encrypt and send buffered plaintext as one record.
+    fn write_record(&mut self) -> io::Result<()> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let ciphertext = self
+            .sealer
+            .encrypt(&nonce(self.send_counter), self.write_buf.as_slice())
+            .map_err(|_| io::Error::other("Failed to encrypt record"))?;
+        self.send_counter += 1;
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    /// Receive and decrypt the next record. Returns `false` on clean EOF.
+    fn read_record(&mut self) -> io::Result<bool> {
+        let mut len = [0u8; 4];
+        if self.inner.read(&mut len[..1])? == 0 {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut len[1..])?;
+        let len = u32::from_be_bytes(len) as usize;
+        if !(TAG_SIZE..=RECORD_SIZE + TAG_SIZE).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid encrypted record length",
+            ));
+        }
+        let mut ciphertext = vec![0u8; len];
+        self.inner.read_exact(&mut ciphertext)?;
+        self.read_buf = self
+            .opener
+            .decrypt(&nonce(self.recv_counter), ciphertext.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Record failed to verify"))?;
+        self.recv_counter += 1;
+        self.read_pos = 0;
+        Ok(true)
+    }
+}
+
+impl<S: Read + Write> Read for PakeStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read_pos == self.read_buf.len() {
+            if !self.read_record()? {
+                return Ok(0);
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.read_buf.len() - self.read_pos);
+        buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+        self.read_pos += n;
+        Ok(n)
+    }
+}
+
+impl<S: Read + Write> Write for PakeStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.write_buf.len() == RECORD_SIZE {
+            self.write_record()?;
+        }
+        let n = std::cmp::min(buf.len(), RECORD_SIZE - self.write_buf.len());
+        self.write_buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_record()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn pair(
+        sender_code: &'static str,
+        receiver_code: &'static str,
+    ) -> (
+        anyhow::Result<PakeStream<TcpStream>>,
+        anyhow::Result<PakeStream<TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            receiver_handshake(stream, receiver_code)
+        });
+        let sender = sender_handshake(TcpStream::connect(addr).unwrap(), sender_code);
+        (sender, handle.join().unwrap())
+    }
+
+    #[test]
+    fn generated_code_has_expected_shape() {
+        let code = generate_code().unwrap();
+        assert_eq!(code.len(), 7);
+        assert_eq!(&code[3..4], "-");
+        assert!(normalize_code(&code).chars().all(|c| c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn matching_codes_exchange_data() {
+        let (sender, receiver) = pair("123-456", "123456");
+        let mut sender = sender.unwrap();
+        let mut receiver = receiver.unwrap();
+
+        let data = (0..300 * 1024).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let handle = thread::spawn(move || {
+            sender.write_all(&data).unwrap();
+            sender.flush().unwrap();
+            let mut reply = [0u8; 5];
+            sender.read_exact(&mut reply).unwrap();
+            reply
+        });
+
+        let mut got = vec![0u8; expected.len()];
+        receiver.read_exact(&mut got).unwrap();
+        assert_eq!(got, expected);
+        receiver.write_all(b":eof:").unwrap();
+        receiver.flush().unwrap();
+        assert_eq!(&handle.join().unwrap(), b":eof:");
+    }
+
+    #[test]
+    fn mismatched_codes_fail() {
+        let (sender, receiver) = pair("123-456", "654-321");
+        assert!(sender.is_err());
+        assert!(receiver.is_err());
+    }
+
+    #[test]
+    fn tampered_record_is_rejected() {
+        let mut a = Vec::new();
+        {
+            let keys = [7u8; 32];
+            let mut stream = PakeStream {
+                inner: io::Cursor::new(&mut a),
+                sealer: ChaCha20Poly1305::new(&keys.into()),
+                opener: ChaCha20Poly1305::new(&keys.into()),
+                send_counter: 0,
+                recv_counter: 0,
+                read_buf: Vec::new(),
+                read_pos: 0,
+                write_buf: Vec::new(),
+            };
+            stream.write_all(b"hello").unwrap();
+            stream.flush().unwrap();
+        }
+        let last = a.len() - 1;
+        a[last] ^= 1;
+
+        let keys = [7u8; 32];
+        let mut stream = PakeStream {
+            inner: io::Cursor::new(a),
+            sealer: ChaCha20Poly1305::new(&keys.into()),
+            opener: ChaCha20Poly1305::new(&keys.into()),
+            send_counter: 0,
+            recv_counter: 0,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            write_buf: Vec::new(),
+        };
+        let mut buf = [0u8; 5];
+        assert!(stream.read_exact(&mut buf).is_err());
+    }
+}
@@ -1,4 +1,6 @@
-use fs_share_utils::pb::ProgressBar;
+use std::sync::Arc;
+
+use fs_share_utils::{manifest::human_size, pb::ProgressBar, throttle::Throttle};
 
 struct NoProgress;
 
@@ -9,24 +11,37 @@ impl ProgressBar for NoProgress {
 
 struct MyPrograssBar {
     inner: indicatif::ProgressBar,
+    throttle: Option<Arc<Throttle>>,
 }
 
 impl ProgressBar for MyPrograssBar {
     fn update(&self, size: u64) {
         self.inner.set_position(size);
+        if let Some(throttle) = &self.throttle {
+            match throttle.active() {
+                Some(rate) => self
+                    .inner
+                    .set_message(format!(" throttled to {}/s", human_size(rate))),
+                None => self.inner.set_message(""),
+            }
+        }
     }
     fn finish(&self) {
         self.inner.finish();
     }
 }
 
-pub fn my_pb(n: u64) -> Box<dyn ProgressBar> {
+/// Progress bar that says when `throttle` holds the transfer back.
+pub fn my_pb(n: u64, throttle: Option<Arc<Throttle>>) -> Box<dyn ProgressBar> {
     let pb = indicatif::ProgressBar::new(n);
-    pb.set_style(indicatif::ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+    pb.set_style(indicatif::ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}){msg}")
         .unwrap()
         .with_key("eta", |state: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
         .progress_chars("#>-"));
-    Box::new(MyPrograssBar { inner: pb })
+    Box::new(MyPrograssBar {
+        inner: pb,
+        throttle,
+    })
 }
 
 pub fn no_pb(_: u64) -> Box<dyn ProgressBar> {
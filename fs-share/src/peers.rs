@@ -0,0 +1,318 @@
+//! # Peers Mode
+//!
+//! Lists the receivers discoverable from here without starting a transfer,
+//! as a table or as JSON, once or continuously (`--watch`).
+
+use std::{
+    io::{IsTerminal, Write},
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use fs_share_utils::{broadcast::receiver::Discovery, sender::ReceiverData as RD};
+
+use crate::sender::ReceiverData;
+
+/// How long to listen without `--duration` (and without `--watch`)
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(3);
+
+/// Peers not heard from for this long are dropped in `--watch` mode.
+///
+/// Receivers broadcast every 300 ms and mDNS is queried every second, so a
+/// live receiver is heard from several times within it.
+const EXPIRE_AFTER: Duration = Duration::from_secs(5);
+
+/// How often the table is redrawn in `--watch` mode
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// A discovered receiver and when it was last heard from.
+#[derive(Debug, Clone)]
+struct Peer {
+    data: ReceiverData,
+    last_seen: SystemTime,
+}
+
+/// Receivers by address, in the order they were first found.
+#[derive(Debug, Default)]
+struct Peers {
+    peers: Vec<Peer>,
+}
+
+impl Peers {
+    /// Record an announcement; returns whether the list changed.
+    fn see(&mut self, data: ReceiverData, now: SystemTime) -> bool {
+        match self.peers.iter_mut().find(|p| p.data.addr() == data.addr()) {
+            Some(peer) => {
+                peer.last_seen = now;
+                let changed = peer.data != data;
+                peer.data = data;
+                changed
+            }
+            None => {
+                self.peers.push(Peer {
+                    data,
+                    last_seen: now,
+                });
+                true
+            }
+        }
+    }
+
+    /// Drop peers not heard from for `after`; returns whether any were.
+    fn expire(&mut self, now: SystemTime, after: Duration) -> bool {
+        let len = self.peers.len();
+        self.peers.retain(|p| age(p.last_seen, now) < after);
+        self.peers.len() != len
+    }
+
+    /// Aligned table with a header row.
+    fn table(&self, now: SystemTime) -> String {
+        let mut rows = vec![[
+            "NAME".to_owned(),
+            "OS".to_owned(),
+            "ADDRESS".to_owned(),
+            "SERVICE".to_owned(),
+            "LAST SEEN".to_owned(),
+        ]];
+        for peer in &self.peers {
+            rows.push([
+                printable(peer.data.name()),
+                printable(&format!("{} ({})", peer.data.os(), peer.data.arch())),
+                peer.data.addr().to_string(),
+                service(&peer.data).to_owned(),
+                seen_ago(age(peer.last_seen, now)),
+            ]);
+        }
+
+        let mut widths = [0; 5];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let mut table = String::new();
+        for row in &rows {
+            let mut line = String::new();
+            for (cell, width) in row.iter().zip(widths) {
+                line += &format!("{:<width$}  ", cell, width = width);
+            }
+            table += line.trim_end();
+            table.push('\n');
+        }
+        table
+    }
+
+    /// JSON array on one line, `last_seen` in seconds since the Unix epoch.
+    fn json(&self) -> String {
+        let peers: Vec<String> = self
+            .peers
+            .iter()
+            .map(|peer| {
+                format!(
+                    "{{\"name\":{},\"os\":{},\"arch\":{},\"addr\":{},\"service\":{},\"last_seen\":{}}}",
+                    json_string(peer.data.name()),
+                    json_string(peer.data.os()),
+                    json_string(peer.data.arch()),
+                    json_string(&peer.data.addr().to_string()),
+                    json_string(service(&peer.data)),
+                    peer.last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                )
+            })
+            .collect();
+        format!("[{}]", peers.join(","))
+    }
+}
+
+/// List receivers found through `discovery`.
+///
+/// Listens for `duration` and prints the list once; with `watch`, prints it
+/// again whenever it changes (the table also every second, for the last
+/// seen times) until `duration` is over, if given.
+pub fn run(
+    (stop, rx, handle): Discovery<ReceiverData>,
+    duration: Option<Duration>,
+    watch: bool,
+    json: bool,
+) -> anyhow::Result<()> {
+    let duration = duration.or((!watch).then_some(DEFAULT_DURATION));
+    let deadline = duration.map(|d| Instant::now() + d);
+    let redraw = watch && !json && std::io::stdout().is_terminal();
+
+    if !watch {
+        eprintln!("Searching for receivers for {:?}...", duration.unwrap());
+    }
+
+    let mut peers = Peers::default();
+    let mut last_shown: Option<Instant> = None;
+    loop {
+        let wait = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min(REFRESH),
+                None => break,
+            },
+            None => REFRESH,
+        };
+        let mut changed = match rx.recv_timeout(wait) {
+            Ok((_, data)) => peers.see(data, SystemTime::now()),
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if !watch {
+            continue;
+        }
+
+        changed |= peers.expire(SystemTime::now(), EXPIRE_AFTER);
+        let due = redraw && last_shown.is_none_or(|t| t.elapsed() >= REFRESH);
+        if changed || due || last_shown.is_none() {
+            if !json && !redraw && last_shown.is_some() {
+                println!(); // between the tables of a log
+            }
+            show(&peers, json, redraw)?;
+            last_shown = Some(Instant::now());
+        }
+    }
+
+    stop();
+    let _ = handle.join();
+
+    if !watch {
+        if json || !peers.peers.is_empty() {
+            show(&peers, json, false)?;
+        } else {
+            eprintln!("No receivers found");
+        }
+    }
+    Ok(())
+}
+
+/// Print the list, in place of the previous one with `redraw`.
+fn show(peers: &Peers, json: bool, redraw: bool) -> anyhow::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    if json {
+        writeln!(stdout, "{}", peers.json())?;
+    } else {
+        if redraw {
+            // Clear the screen and move to the top left
+            write!(stdout, "\x1b[2J\x1b[H")?;
+            writeln!(stdout, "Watching for receivers (Ctrl-C to stop)...\n")?;
+        }
+        write!(stdout, "{}", peers.table(SystemTime::now()))?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn service(data: &ReceiverData) -> &'static str {
+    if data.accepts_transfers() {
+        "fs-share"
+    } else {
+        "http"
+    }
+}
+
+fn age(last_seen: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(last_seen).unwrap_or_default()
+}
+
+/// `now`, `12s ago`, `3m ago`
+fn seen_ago(age: Duration) -> String {
+    match age.as_secs() {
+        0 => "now".to_owned(),
+        secs @ 1..60 => format!("{}s ago", secs),
+        secs => format!("{}m ago", secs / 60),
+    }
+}
+
+/// `s` with control characters replaced, so an announced name can't move
+/// the cursor or clear the terminal.
+fn printable(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
+
+/// Quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if c.is_control() => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_share_utils::broadcast::{receiver::PayloadReader, sender::encode_field};
+
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn peer(fields: &[&str]) -> ReceiverData {
+        let mut payload = Vec::new();
+        for field in fields {
+            encode_field(&mut payload, field.as_bytes());
+        }
+        let src: SocketAddr = "192.168.1.9:7755".parse().unwrap();
+        ReceiverData::try_from((src, PayloadReader::new(&payload))).unwrap()
+    }
+
+    #[test]
+    fn peers_are_listed_and_expire() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let alice = peer(&["alice", "linux", "x86_64", "192.168.1.9:4000"]);
+        let web = peer(&["bob \"web\"", "macos", "aarch64", "0.0.0.0:8080", "http"]);
+
+        let mut peers = Peers::default();
+        assert!(peers.see(alice.clone(), start));
+        assert!(peers.see(web, start + Duration::from_secs(3)));
+        // Heard from again, unchanged
+        assert!(!peers.see(alice, start + Duration::from_secs(4)));
+
+        let now = start + Duration::from_secs(7);
+        assert_eq!(
+            peers.table(now),
+            "NAME       OS               ADDRESS           SERVICE   LAST SEEN\n\
+             alice      linux (x86_64)   192.168.1.9:4000  fs-share  3s ago\n\
+             bob \"web\"  macos (aarch64)  192.168.1.9:8080  http      4s ago\n"
+        );
+        assert_eq!(
+            peers.json(),
+            "[{\"name\":\"alice\",\"os\":\"linux\",\"arch\":\"x86_64\",\"addr\":\"192.168.1.9:4000\",\
+             \"service\":\"fs-share\",\"last_seen\":1700000004},\
+             {\"name\":\"bob \\\"web\\\"\",\"os\":\"macos\",\"arch\":\"aarch64\",\"addr\":\"192.168.1.9:8080\",\
+             \"service\":\"http\",\"last_seen\":1700000003}]"
+        );
+
+        assert!(!peers.expire(now, EXPIRE_AFTER));
+        assert!(peers.expire(start + Duration::from_millis(8_500), EXPIRE_AFTER));
+        assert_eq!(peers.peers.len(), 1);
+        assert_eq!(peers.peers[0].data.name(), "alice");
+    }
+
+    #[test]
+    fn table_hides_control_characters() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let eve = peer(&["eve\x1b[2J", "linux\r", "x86\n64", "192.168.1.9:4000"]);
+
+        let mut peers = Peers::default();
+        peers.see(eve, now);
+        assert_eq!(
+            peers.table(now),
+            "NAME     OS               ADDRESS           SERVICE   LAST SEEN\n\
+             eve?[2J  linux? (x86?64)  192.168.1.9:4000  fs-share  now\n"
+        );
+    }
+}
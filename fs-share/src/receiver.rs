@@ -1,22 +1,125 @@
 use std::{
     borrow::Cow,
     io::{Read, Write},
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream},
+    net::{IpAddr, SocketAddr, TcpStream},
     path::{Path, PathBuf},
+    thread::JoinHandle,
 };
 
 use anyhow::Context;
-use fs_share_utils::{broadcast::sender::Broadcaster, pb::ProgressBar, receiver::App};
+use fs_share_utils::{
+    broadcast::{
+        mdns::{MdnsAdvertiser, MdnsAdvertiserBuilder},
+        sender::{Broadcaster, BroadcasterBuilder},
+    },
+    collision::CollisionPolicy,
+    links::LinkPolicy,
+    manifest::{Decision, Manifest},
+    pb::ProgressBar,
+    protocol::Features,
+    receiver::App,
+    zerocopy::PlainSocket,
+};
+
+use crate::utils::create_mdns_socket;
+
+/// Broadcaster announcing this device and `listener_addr`.
+///
+/// Fields: user name, OS, arch, listener address. A listener bound to one
+/// IPv4 address is only announced on its interface; one bound to all of
+/// them is announced on each with that interface's address.
+pub fn announce(header: &str, target: SocketAddr, listener_addr: SocketAddr) -> BroadcasterBuilder {
+    let mut builder = Broadcaster::builder()
+        .header(header)
+        .target_addr(target)
+        .directed_broadcast(true);
+    // Loopback has no interface to broadcast on; announce it as before.
+    if let IpAddr::V4(ip) = listener_addr.ip()
+        && !ip.is_unspecified()
+        && !ip.is_loopback()
+    {
+        builder = builder.bind_addr(SocketAddr::new(IpAddr::V4(ip), 0));
+    }
+    builder
+        .add_field(user_name())
+        .add_field(std::env::consts::OS)
+        .add_field(std::env::consts::ARCH)
+        .add_addr_field(listener_addr)
+}
+
+/// mDNS advertiser with the same fields as [`announce`], as TXT records.
+pub fn advertise(listener_addr: SocketAddr) -> MdnsAdvertiserBuilder {
+    let device = crate::tls::device_name();
+    let host: String = device
+        .rsplit('@')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    let mut builder = MdnsAdvertiser::builder()
+        .instance(format!("{} ({})", device, listener_addr.port()))
+        .host(format!("{}-{}", host, listener_addr.port()))
+        .listener_addr(listener_addr)
+        .add_txt("name", user_name())
+        .add_txt("os", std::env::consts::OS)
+        .add_txt("arch", std::env::consts::ARCH)
+        .add_txt("addr", listener_addr.to_string());
+    if let IpAddr::V4(ip) = listener_addr.ip() {
+        builder = builder.interface(ip);
+    }
+    match create_mdns_socket() {
+        Ok(socket) => builder.socket(socket),
+        Err(e) => {
+            eprintln!("mDNS advertising unavailable: {:#}", e);
+            builder
+        }
+    }
+}
+
+/// Start the UDP broadcaster and mDNS advertiser together.
+pub fn start_discovery(
+    broadcaster: Broadcaster,
+    advertiser: MdnsAdvertiser,
+) -> (impl FnOnce(), JoinHandle<()>) {
+    let (stop_broadcaster, broadcaster) = broadcaster.start();
+    let (stop_advertiser, advertiser) = advertiser.start();
+    let handle = std::thread::spawn(move || {
+        let _ = broadcaster.join();
+        let _ = advertiser.join();
+    });
+    let stop = move || {
+        stop_broadcaster();
+        stop_advertiser();
+    };
+    (stop, handle)
+}
+
+fn user_name() -> String {
+    ["USER", "USERNAME"]
+        .iter()
+        .find_map(|&key| std::env::var(key).ok())
+        .unwrap_or("Unknown".into())
+}
 
 pub struct ReceiverApp<U> {
     pub broadcast_addr: SocketAddr,
     pub download_dir: PathBuf,
     pub disable_broadcaster: bool,
     pub upgrade_stream: Box<dyn Fn(TcpStream) -> anyhow::Result<U> + 'static>,
+    /// Whether `upgrade_stream` encrypts the connection
+    pub secure: bool,
     pub pb: Box<dyn Fn(u64) -> Box<dyn ProgressBar>>,
+    pub collision: CollisionPolicy,
+    pub links: LinkPolicy,
+    pub features: Features,
+    /// Accept incoming files without asking
+    pub yes: bool,
+    /// Reject incoming transfers larger than this
+    pub max_size: Option<u64>,
 }
 
-impl<U: Read + Write> App for ReceiverApp<U> {
+impl<U: Read + Write + Send + PlainSocket> App for ReceiverApp<U> {
     type Stream = TcpStream;
     type UpgradeStream = U;
     fn prefix(&self) -> &str {
@@ -34,6 +137,9 @@ impl<U: Read + Write> App for ReceiverApp<U> {
     fn upgrade_stream(&self, stream: Self::Stream) -> anyhow::Result<Self::UpgradeStream> {
         (*self.upgrade_stream)(stream)
     }
+    fn secure(&self) -> bool {
+        self.secure
+    }
     fn preprocess_connection(&self, stream: &mut Self::Stream) -> anyhow::Result<bool> {
         let addr = stream.local_addr()?;
         stream
@@ -47,25 +153,30 @@ impl<U: Read + Write> App for ReceiverApp<U> {
     fn create_progress_bar(&self, n: u64) -> Box<dyn ProgressBar> {
         (self.pb)(n)
     }
+    fn collision_policy(&self) -> CollisionPolicy {
+        self.collision
+    }
+    fn ask_collision(&self, path: &Path) -> CollisionPolicy {
+        crate::utils::ask_collision(path)
+    }
+    fn link_policy(&self) -> LinkPolicy {
+        self.links
+    }
+    fn features(&self) -> Features {
+        self.features
+    }
+    fn device_name(&self) -> String {
+        crate::tls::device_name()
+    }
+    fn approve_incoming(&self, manifest: &Manifest) -> Decision {
+        crate::utils::approve_incoming(manifest, self.yes, self.max_size)
+    }
     fn start_broadcaster(
         &self,
         listener_addr: SocketAddr,
     ) -> (impl FnOnce(), std::thread::JoinHandle<()>) {
-        let bc_sender = Broadcaster::builder()
-            .header(self.prefix())
-            .target_addr(self.broadcast_addr())
-            .bind_addr(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)))
-            .add_field(
-                ["USER", "USERNAME"]
-                    .iter()
-                    .find_map(|&key| std::env::var(key).ok())
-                    .unwrap_or("Unknown".into()),
-            )
-            .add_field(std::env::consts::OS)
-            .add_field(std::env::consts::ARCH)
-            .add_field(listener_addr.to_string())
-            .build();
+        let bc_sender = announce(self.prefix(), self.broadcast_addr(), listener_addr).build();
 
-        bc_sender.start()
+        start_discovery(bc_sender, advertise(listener_addr).build())
     }
 }
@@ -0,0 +1,155 @@
+//! Picking a discovered receiver without asking (`--to-name`,
+//! `--to-first`, `--match`, `--discover-timeout`).
+
+use std::{fmt::Display, time::Duration};
+
+use fs_share_utils::sender::ReceiverData;
+use regex::Regex;
+
+/// How long to search when a strategy is given without `--discover-timeout`
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Selection strategy from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    /// Receiver name, compared without case
+    pub name: Option<String>,
+    /// Take the first receiver that qualifies
+    pub first: bool,
+    /// Pattern for the receiver's listing
+    pub pattern: Option<Regex>,
+    /// How long to search
+    pub timeout: Option<Duration>,
+}
+
+impl Selection {
+    /// No strategy given: list receivers and ask for one.
+    pub fn is_interactive(&self) -> bool {
+        self.name.is_none() && !self.first && self.pattern.is_none() && self.timeout.is_none()
+    }
+
+    /// How long to search before choosing.
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Whether `item` can be picked.
+    pub fn qualifies<V: Display + ReceiverData>(&self, item: &V) -> bool {
+        item.accepts_transfers()
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| item.name().eq_ignore_ascii_case(name))
+            && self
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| pattern.is_match(&item.to_string()))
+    }
+
+    /// Choose among the receivers found once the search is over.
+    ///
+    /// Fails if none qualifies, or several do without `--to-first`.
+    pub fn choose<'a, V: Display + ReceiverData>(&self, found: &'a [V]) -> anyhow::Result<&'a V> {
+        let candidates: Vec<&V> = found.iter().filter(|item| self.qualifies(*item)).collect();
+        match candidates[..] {
+            [] => anyhow::bail!(
+                "No receiver{} found within {:?}",
+                self.criteria(),
+                self.timeout()
+            ),
+            [item] => Ok(item),
+            [item, ..] if self.first => Ok(item),
+            _ => {
+                let list: Vec<String> = candidates
+                    .iter()
+                    .map(|item| format!("  {}", item))
+                    .collect();
+                anyhow::bail!(
+                    "{} receivers{} found, pick one with --to-name, --match or --to-first:\n{}",
+                    candidates.len(),
+                    self.criteria(),
+                    list.join("\n")
+                )
+            }
+        }
+    }
+
+    /// ` named "x" matching "y"`, for error messages
+    fn criteria(&self) -> String {
+        let mut s = String::new();
+        if let Some(name) = &self.name {
+            s += &format!(" named {:?}", name);
+        }
+        if let Some(pattern) = &self.pattern {
+            s += &format!(" matching {:?}", pattern.to_string());
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Peer(&'static str, bool);
+
+    impl Display for Peer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "Name: {}", self.0)
+        }
+    }
+
+    impl ReceiverData for Peer {
+        fn addr(&self) -> SocketAddr {
+            "127.0.0.1:1".parse().unwrap()
+        }
+        fn accepts_transfers(&self) -> bool {
+            self.1
+        }
+        fn name(&self) -> &str {
+            self.0
+        }
+    }
+
+    #[test]
+    fn receivers_are_chosen_by_strategy() {
+        let found = [
+            Peer("alice", true),
+            Peer("bob", true),
+            Peer("Alice-laptop", true),
+            Peer("web", false),
+        ];
+
+        let by_name = Selection {
+            name: Some("ALICE".into()),
+            ..Default::default()
+        };
+        assert_eq!(by_name.choose(&found).unwrap().0, "alice");
+
+        let by_pattern = Selection {
+            pattern: Some(Regex::new("(?i)alice").unwrap()),
+            ..Default::default()
+        };
+        let error = by_pattern.choose(&found).unwrap_err().to_string();
+        assert!(error.starts_with("2 receivers matching \"(?i)alice\" found"));
+        assert!(error.contains("  Name: alice\n  Name: Alice-laptop"));
+
+        let first = Selection {
+            first: true,
+            ..by_pattern
+        };
+        assert_eq!(first.choose(&found).unwrap().0, "alice");
+
+        // HTTP servers can't be sent to, so they are never picked.
+        let only = Selection {
+            timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        assert!(!only.is_interactive());
+        assert!(only.choose(&found[3..]).is_err());
+        assert_eq!(only.choose(&found[2..]).unwrap().0, "Alice-laptop");
+    }
+}
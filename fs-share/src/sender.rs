@@ -2,27 +2,35 @@ use std::{
     borrow::Cow,
     fmt::Display,
     io::{Read, Write},
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    net::{IpAddr, SocketAddr, SocketAddrV6, TcpStream},
     path::{Path, PathBuf},
     sync::mpsc::Receiver,
     thread::JoinHandle,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use colored::Colorize;
 use fs_share_utils::{
     broadcast::receiver::PayloadReader,
+    collision::CollisionPolicy,
+    links::LinkPolicy,
     pb::ProgressBar,
+    protocol::Features,
     sender::{App, ReceiverData as RD},
+    zerocopy::PlainSocket,
 };
 
+use crate::select::Selection;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ReceiverData {
     name: String,
     os: String,
     arch: String,
     addr: SocketAddr,
+    /// Advertised by `fs-share serve` (browser download only)
+    http: bool,
 }
 
 impl Display for ReceiverData {
@@ -30,6 +38,9 @@ impl Display for ReceiverData {
         write!(f, "Name: {}, ", self.name)?;
         write!(f, "OS: {} ({}), ", self.os, self.arch)?;
         write!(f, "Addr: {}", self.addr)?;
+        if self.http {
+            write!(f, " (HTTP: http://{}/)", self.addr)?;
+        }
         Ok(())
     }
 }
@@ -39,68 +50,106 @@ impl<'a> TryFrom<(SocketAddr, PayloadReader<'a>)> for ReceiverData {
     fn try_from(value: (SocketAddr, PayloadReader)) -> Result<Self, Self::Error> {
         let a = value.0;
         let mut value = value.1;
-        let v = value.next();
-        if v.is_none() {
-            return Err(());
-        }
-        let name = unsafe { str::from_utf8_unchecked(v.unwrap()) };
-
-        let v = value.next();
-        if v.is_none() {
-            return Err(());
-        }
-        let os = unsafe { str::from_utf8_unchecked(v.unwrap()) };
-
-        let v = value.next();
-        if v.is_none() {
-            return Err(());
-        }
-        let arch = unsafe { str::from_utf8_unchecked(v.unwrap()) };
-
-        let v = value.next();
-        if v.is_none() {
-            return Err(());
-        }
-        let addr = unsafe { str::from_utf8_unchecked(v.unwrap()) };
-        let addr = addr.parse::<SocketAddr>();
-        if addr.is_err() {
-            return Err(());
-        }
-        let mut addr = addr.unwrap();
-        if matches!(
-            addr.ip(),
-            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
-                | IpAddr::V4(Ipv4Addr::LOCALHOST)
-                | IpAddr::V6(Ipv6Addr::UNSPECIFIED)
-                | IpAddr::V6(Ipv6Addr::LOCALHOST)
-        ) {
-            addr = SocketAddr::new(a.ip(), addr.port());
-        }
+        // Fields come off the network; anything that isn't UTF-8 is dropped
+        let mut field = || str::from_utf8(value.next().ok_or(())?).map_err(|_| ());
+        let name = field()?;
+        let os = field()?;
+        let arch = field()?;
+        let addr = field()?;
+        let addr = advertised_addr(addr, a).ok_or(())?;
+        // Optional service field, absent on plain receivers
+        let http = value.next() == Some(&b"http"[..]);
 
         Ok(Self {
             name: name.to_owned(),
             os: os.to_owned(),
             arch: arch.to_owned(),
             addr,
+            http,
         })
     }
 }
 
+/// Address a receiver advertised, as reachable from here.
+///
+/// Unspecified and loopback addresses are replaced with the packet's
+/// source. A link-local IPv6 address takes the scope id of the source (the
+/// interface the packet arrived on): the receiver's own zone means nothing
+/// on this device, and may be a name (`%eth0`) `SocketAddr` can't parse.
+fn advertised_addr(field: &str, src: SocketAddr) -> Option<SocketAddr> {
+    let addr = match field.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => {
+            let (host, port) = field.rsplit_once(':')?;
+            let host = host.strip_prefix('[')?.strip_suffix(']')?;
+            let (ip, _zone) = host.split_once('%')?;
+            SocketAddr::V6(SocketAddrV6::new(
+                ip.parse().ok()?,
+                port.parse().ok()?,
+                0,
+                0,
+            ))
+        }
+    };
+    let scope_id = match src {
+        SocketAddr::V6(src) => src.scope_id(),
+        SocketAddr::V4(_) => 0,
+    };
+    let addr = match addr {
+        _ if addr.ip().is_unspecified() || addr.ip().is_loopback() => match src {
+            SocketAddr::V6(src) => {
+                SocketAddr::V6(SocketAddrV6::new(*src.ip(), addr.port(), 0, scope_id))
+            }
+            SocketAddr::V4(src) => SocketAddr::new(IpAddr::V4(*src.ip()), addr.port()),
+        },
+        SocketAddr::V6(addr) if addr.ip().is_unicast_link_local() && scope_id != 0 => {
+            SocketAddr::V6(SocketAddrV6::new(*addr.ip(), addr.port(), 0, scope_id))
+        }
+        addr => addr,
+    };
+    Some(addr)
+}
+
+impl ReceiverData {
+    pub fn os(&self) -> &str {
+        &self.os
+    }
+    pub fn arch(&self) -> &str {
+        &self.arch
+    }
+}
+
 impl RD for ReceiverData {
     fn addr(&self) -> SocketAddr {
         self.addr
     }
+    fn accepts_transfers(&self) -> bool {
+        !self.http
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
 }
 
 pub struct SenderAppV1<U> {
     pub broadcast_addr: SocketAddr,
+    /// IPv6 groups to listen on as well, scope id the interface index
+    pub multicast_addrs: Vec<SocketAddrV6>,
     pub receiver_addr: Option<SocketAddr>,
     pub download_dir: PathBuf,
     pub upgrade_stream: Box<dyn Fn(TcpStream) -> anyhow::Result<U> + 'static>,
+    /// Whether `upgrade_stream` encrypts the connection
+    pub secure: bool,
     pub pb: Box<dyn Fn(u64) -> Box<dyn ProgressBar>>,
+    pub collision: CollisionPolicy,
+    pub links: LinkPolicy,
+    pub features: Features,
+    pub data_streams: u8,
+    /// How to pick among discovered receivers
+    pub selection: Selection,
 }
 
-impl<U: Read + Write> App for SenderAppV1<U> {
+impl<U: Read + Write + Send + PlainSocket> App for SenderAppV1<U> {
     type Stream = TcpStream;
     type UpgradeStream = U;
     fn prefix(&self) -> &str {
@@ -109,6 +158,9 @@ impl<U: Read + Write> App for SenderAppV1<U> {
     fn broadcast_addr(&self) -> SocketAddr {
         self.broadcast_addr
     }
+    fn multicast_addrs(&self) -> Vec<SocketAddrV6> {
+        self.multicast_addrs.clone()
+    }
     fn receiver_addr(&self) -> Option<SocketAddr> {
         self.receiver_addr
     }
@@ -118,9 +170,30 @@ impl<U: Read + Write> App for SenderAppV1<U> {
     fn upgrade_stream(&self, stream: Self::Stream) -> anyhow::Result<Self::UpgradeStream> {
         (*self.upgrade_stream)(stream)
     }
+    fn secure(&self) -> bool {
+        self.secure
+    }
     fn create_progress_bar(&self, n: u64) -> Box<dyn ProgressBar> {
         (self.pb)(n)
     }
+    fn collision_policy(&self) -> CollisionPolicy {
+        self.collision
+    }
+    fn ask_collision(&self, path: &Path) -> CollisionPolicy {
+        crate::utils::ask_collision(path)
+    }
+    fn link_policy(&self) -> LinkPolicy {
+        self.links
+    }
+    fn features(&self) -> Features {
+        self.features
+    }
+    fn device_name(&self) -> String {
+        crate::tls::device_name()
+    }
+    fn data_streams(&self) -> u8 {
+        self.data_streams
+    }
     fn preprocess_connection(&self, stream: &mut Self::Stream) -> anyhow::Result<()> {
         let addr = stream.local_addr()?;
         stream
@@ -139,10 +212,17 @@ impl<U: Read + Write> App for SenderAppV1<U> {
             Receiver<(SocketAddr, V)>,
             JoinHandle<()>,
         ),
-    ) -> Option<SocketAddr>
+    ) -> anyhow::Result<SocketAddr>
     where
         V: Clone + std::fmt::Display + PartialEq + RD + Send + 'static,
     {
+        if !self.selection.is_interactive() {
+            let found = search(&self.selection, &rx);
+            stop();
+            let _ = handle.join();
+            return Ok(self.selection.choose(&found)?.addr());
+        }
+
         use std::sync::mpsc;
 
         let mut items: Vec<V> = Vec::new();
@@ -169,11 +249,7 @@ impl<U: Read + Write> App for SenderAppV1<U> {
             match rx.try_recv() {
                 Ok(data) => {
                     if !items.contains(&data.1) {
-                        println!(
-                            "[{}] {}",
-                            (items.len() + 1).to_string().blue(),
-                            data.1.to_string().green()
-                        );
+                        print_found(items.len() + 1, &data.1);
                         items.push(data.1);
                     }
                 }
@@ -190,18 +266,118 @@ impl<U: Read + Write> App for SenderAppV1<U> {
         let _ = t.join();
 
         if items.is_empty() {
-            println!("No receivers found.");
-            return None;
+            anyhow::bail!("No receivers found");
         }
 
         print!("-----------------\nSelect receiver index: ");
         let _ = std::io::stdout().flush();
 
         let mut input = String::new();
-        std::io::stdin().read_line(&mut input).ok()?;
+        std::io::stdin().read_line(&mut input)?;
+
+        let item = input
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|idx| items.get(idx.checked_sub(1)?))
+            .with_context(|| format!("Invalid receiver index: {:?}", input.trim()))?;
+        if !item.accepts_transfers() {
+            anyhow::bail!(
+                "{} is an HTTP server, open it in a browser instead.",
+                item.addr()
+            );
+        }
+        Ok(item.addr())
+    }
+}
+
+fn print_found<V: Display>(index: usize, item: &V) {
+    println!(
+        "[{}] {}",
+        index.to_string().blue(),
+        item.to_string().green()
+    );
+}
+
+/// Collect receivers until the selection's timeout, or until the first
+/// one that qualifies with `--to-first`.
+fn search<V>(selection: &Selection, rx: &Receiver<(SocketAddr, V)>) -> Vec<V>
+where
+    V: Display + PartialEq + RD,
+{
+    let timeout = selection.timeout();
+    println!(
+        "{}",
+        format!("Searching for receivers for {:?}...", timeout).blue()
+    );
+
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<V> = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        let Ok((_, data)) = rx.recv_timeout(left) else {
+            break;
+        };
+        if found.contains(&data) {
+            continue;
+        }
+        print_found(found.len() + 1, &data);
+        let done = selection.first && selection.qualifies(&data);
+        found.push(data);
+        if done {
+            break;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use fs_share_utils::broadcast::sender::encode_field;
+
+    use super::*;
+
+    #[test]
+    fn advertised_addrs_are_resolved() {
+        let v4_src: SocketAddr = "192.168.1.9:7755".parse().unwrap();
+        let v6_src = SocketAddr::V6(SocketAddrV6::new("fe80::9".parse().unwrap(), 7755, 0, 3));
+
+        let addr = advertised_addr("0.0.0.0:4000", v4_src).unwrap();
+        assert_eq!(addr, "192.168.1.9:4000".parse().unwrap());
+        let addr = advertised_addr("192.168.1.5:4000", v4_src).unwrap();
+        assert_eq!(addr, "192.168.1.5:4000".parse().unwrap());
+
+        // The receiver's zone is replaced with the interface of the packet.
+        for field in ["[fe80::5%7]:4000", "[fe80::5%eth0]:4000", "[fe80::5]:4000"] {
+            let addr = advertised_addr(field, v6_src).unwrap();
+            assert_eq!(addr.to_string(), "[fe80::5%3]:4000");
+        }
+        let addr = advertised_addr("[::]:4000", v6_src).unwrap();
+        assert_eq!(addr.to_string(), "[fe80::9%3]:4000");
+        let addr = advertised_addr("[fd00::5]:4000", v6_src).unwrap();
+        assert_eq!(addr.to_string(), "[fd00::5]:4000");
+
+        assert!(advertised_addr("[fe80::5%eth0]", v6_src).is_none());
+        assert!(advertised_addr("not an address", v4_src).is_none());
+    }
+
+    #[test]
+    fn announcements_must_be_utf8() {
+        let payload = |fields: &[&[u8]]| {
+            let mut payload = Vec::new();
+            for field in fields {
+                encode_field(&mut payload, field);
+            }
+            payload
+        };
+        let src: SocketAddr = "192.168.1.9:7755".parse().unwrap();
 
-        let idx: usize = input.trim().parse().ok()?;
+        let ok = payload(&[b"alice", b"linux", b"x86_64", b"0.0.0.0:4000"]);
+        let data = ReceiverData::try_from((src, PayloadReader::new(&ok))).unwrap();
+        assert_eq!(data.name(), "alice");
 
-        items.get(idx - 1).map(|v| v.addr())
+        let bad = payload(&[b"al\xffce", b"linux", b"x86_64", b"0.0.0.0:4000"]);
+        assert!(ReceiverData::try_from((src, PayloadReader::new(&bad))).is_err());
+        let short = payload(&[b"alice", b"linux", b"x86_64"]);
+        assert!(ReceiverData::try_from((src, PayloadReader::new(&short))).is_err());
     }
 }
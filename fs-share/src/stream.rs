@@ -0,0 +1,114 @@
+//! Stream types produced by the CLI's upgrade step.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::Arc,
+};
+
+use fs_share_utils::zerocopy::PlainSocket;
+
+use crate::{
+    pake::{self, PakeStream},
+    tls::{TlsClientStream, TlsConfig, TlsServerStream},
+    utils::{
+        PAKE_HEADER, TLS_HEADER, receiver_exchange_header, receiver_upgrade_stream,
+        sender_exchange_header, sender_upgrade_stream,
+    },
+};
+
+/// How a session is secured after the version handshake.
+#[derive(Clone)]
+pub enum Security {
+    /// No encryption
+    Plain,
+    /// Encrypt with a key derived from this pairing code
+    Pake(String),
+    /// TLS with pinned peer fingerprints
+    Tls(Arc<TlsConfig>),
+}
+
+impl Security {
+    pub fn sender_upgrade(&self, stream: TcpStream) -> anyhow::Result<UpgradedStream> {
+        match self {
+            Self::Plain => Ok(UpgradedStream::Plain(sender_upgrade_stream(stream)?)),
+            Self::Pake(code) => {
+                let stream = sender_exchange_header(stream, PAKE_HEADER)?;
+                Ok(UpgradedStream::Pake(Box::new(pake::sender_handshake(
+                    stream, code,
+                )?)))
+            }
+            Self::Tls(config) => {
+                let stream = sender_exchange_header(stream, TLS_HEADER)?;
+                Ok(UpgradedStream::TlsClient(Box::new(
+                    config.sender_handshake(stream)?,
+                )))
+            }
+        }
+    }
+
+    pub fn receiver_upgrade(&self, stream: TcpStream) -> anyhow::Result<UpgradedStream> {
+        match self {
+            Self::Plain => Ok(UpgradedStream::Plain(receiver_upgrade_stream(stream)?)),
+            Self::Pake(code) => {
+                let stream = receiver_exchange_header(stream, PAKE_HEADER)?;
+                Ok(UpgradedStream::Pake(Box::new(pake::receiver_handshake(
+                    stream, code,
+                )?)))
+            }
+            Self::Tls(config) => {
+                let stream = receiver_exchange_header(stream, TLS_HEADER)?;
+                Ok(UpgradedStream::TlsServer(Box::new(
+                    config.receiver_handshake(stream)?,
+                )))
+            }
+        }
+    }
+}
+
+/// Upgraded connection, plaintext or encrypted.
+pub enum UpgradedStream {
+    Plain(TcpStream),
+    Pake(Box<PakeStream<TcpStream>>),
+    TlsClient(Box<TlsClientStream>),
+    TlsServer(Box<TlsServerStream>),
+}
+
+impl Read for UpgradedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(s) => s.read(buf),
+            Self::Pake(s) => s.read(buf),
+            Self::TlsClient(s) => s.read(buf),
+            Self::TlsServer(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for UpgradedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(s) => s.write(buf),
+            Self::Pake(s) => s.write(buf),
+            Self::TlsClient(s) => s.write(buf),
+            Self::TlsServer(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(s) => s.flush(),
+            Self::Pake(s) => s.flush(),
+            Self::TlsClient(s) => s.flush(),
+            Self::TlsServer(s) => s.flush(),
+        }
+    }
+}
+
+impl PlainSocket for UpgradedStream {
+    fn plain_socket(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Plain(s) => Some(s),
+            _ => None,
+        }
+    }
+}
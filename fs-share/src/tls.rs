@@ -0,0 +1,545 @@
+//! # TLS with Trust-On-First-Use
+//!
+//! TLS 1.3 stream where both peers present a self-signed certificate.
+//!
+//! Each device generates its certificate once and keeps it in the config
+//! directory. The SHA-256 fingerprint of a peer's certificate is pinned in
+//! `known_peers` together with the peer's IP address (the one connected to,
+//! or connected from), which the peer can't pick the way it picks the
+//! device name it sends after the handshake. The name is only shown.
+//!
+//! - A pinned address with a different fingerprint is refused.
+//! - A pinned fingerprint at a new address is the same device, moved; the
+//!   address is pinned too.
+//! - Anything else is a first contact: the fingerprint is shown and the
+//!   user asked to trust it before it is pinned.
+//!
+//! ## Files
+//!
+//! ```text
+//! <config_dir>/identity.der   certificate (DER)
+//! <config_dir>/identity.key   private key (PKCS#8 DER)
+//! <config_dir>/known_peers    "<fingerprint> <address> <name>" per line
+//! ```
+
+use std::{
+    fs::OpenOptions,
+    io::{Read, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{
+        CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+};
+use sha2::{Digest, Sha256};
+
+const CERT_FILE: &str = "identity.der";
+const KEY_FILE: &str = "identity.key";
+const KNOWN_PEERS_FILE: &str = "known_peers";
+
+/// Certificates are self-signed, the name is never checked.
+const SERVER_NAME: &str = "fs-share";
+
+/// Sender side TLS stream
+pub type TlsClientStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Receiver side TLS stream
+pub type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Default config directory (`$XDG_CONFIG_HOME/fs-share`, `~/.config/fs-share`
+/// or `%APPDATA%\fs-share`).
+pub fn default_config_dir() -> anyhow::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return Ok(PathBuf::from(dir).join("fs-share"));
+    }
+    if let Some(dir) = std::env::var_os("APPDATA") {
+        return Ok(PathBuf::from(dir).join("fs-share"));
+    }
+    let home = std::env::var_os("HOME").context("Could not determine the config directory")?;
+    Ok(PathBuf::from(home).join(".config").join("fs-share"))
+}
+
+/// Name this device uses towards its peers (`user@host`).
+pub fn device_name() -> String {
+    let user = ["USER", "USERNAME"]
+        .iter()
+        .find_map(|&key| std::env::var(key).ok())
+        .unwrap_or("unknown".into());
+    let host = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .or_else(|| {
+            ["HOSTNAME", "COMPUTERNAME"]
+                .iter()
+                .find_map(|&key| std::env::var(key).ok())
+        })
+        .unwrap_or("unknown".into());
+    format!("{}@{}", user, host)
+}
+
+/// SHA-256 fingerprint formatted as colon separated hex.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Asks whether to trust a peer seen for the first time.
+type Confirm = Box<dyn Fn() -> bool + Send + Sync>;
+
+/// Local identity and trust store.
+pub struct TlsConfig {
+    name: String,
+    cert: CertificateDer<'static>,
+    key: Vec<u8>,
+    known_peers: PathBuf,
+    confirm: Confirm,
+}
+
+impl TlsConfig {
+    /// Load the identity from `dir`, generating it on first use.
+    pub fn load(dir: &Path, name: String) -> anyhow::Result<Self> {
+        let cert_path = dir.join(CERT_FILE);
+        let key_path = dir.join(KEY_FILE);
+        if !cert_path.exists() || !key_path.exists() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Faild to create directoy: {}", dir.display()))?;
+            let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()])
+                .context("Failed to generate certificate")?;
+            write_private(&key_path, &certified.key_pair.serialize_der())?;
+            std::fs::write(&cert_path, certified.cert.der())
+                .with_context(|| format!("Failed to write {}", cert_path.display()))?;
+        }
+        let cert = std::fs::read(&cert_path)
+            .with_context(|| format!("Failed to read {}", cert_path.display()))?;
+        let key = std::fs::read(&key_path)
+            .with_context(|| format!("Failed to read {}", key_path.display()))?;
+        Ok(Self {
+            name,
+            cert: CertificateDer::from(cert),
+            key,
+            known_peers: dir.join(KNOWN_PEERS_FILE),
+            confirm: Box::new(ask_trust),
+        })
+    }
+
+    /// Fingerprint of the local certificate
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert)
+    }
+
+    fn key(&self) -> PrivateKeyDer<'static> {
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()))
+    }
+
+    /// Run the sender (TLS client) side of the handshake.
+    pub fn sender_handshake(&self, stream: TcpStream) -> anyhow::Result<TlsClientStream> {
+        let addr = stream.peer_addr()?.ip();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyPeer::new(&provider)))
+            .with_client_auth_cert(vec![self.cert.clone()], self.key())
+            .context("Invalid local certificate")?;
+        let conn = ClientConnection::new(Arc::new(config), ServerName::try_from(SERVER_NAME)?)
+            .context("Failed to create TLS connection")?;
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        let cert = peer_certificate(stream.conn.peer_certificates())?;
+        self.verify_peer(&mut stream, addr, &cert)?;
+        Ok(stream)
+    }
+
+    /// Run the receiver (TLS server) side of the handshake.
+    pub fn receiver_handshake(&self, stream: TcpStream) -> anyhow::Result<TlsServerStream> {
+        let addr = stream.peer_addr()?.ip();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(Arc::new(AnyPeer::new(&provider)))
+            .with_single_cert(vec![self.cert.clone()], self.key())
+            .context("Invalid local certificate")?;
+        let conn =
+            ServerConnection::new(Arc::new(config)).context("Failed to create TLS connection")?;
+        let mut stream = StreamOwned::new(conn, stream);
+        while stream.conn.is_handshaking() {
+            stream
+                .conn
+                .complete_io(&mut stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        let cert = peer_certificate(stream.conn.peer_certificates())?;
+        self.verify_peer(&mut stream, addr, &cert)?;
+        Ok(stream)
+    }
+
+    /// Exchange device names and check the peer at `addr` against
+    /// `known_peers`.
+    fn verify_peer<S: Read + Write>(
+        &self,
+        stream: &mut S,
+        addr: IpAddr,
+        cert: &[u8],
+    ) -> anyhow::Result<()> {
+        let name = self.name.as_bytes();
+        stream.write_all(&(name.len() as u16).to_be_bytes())?;
+        stream.write_all(name)?;
+        stream.flush()?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut peer_name = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut peer_name)?;
+        let peer_name = String::from_utf8(peer_name).context("Invalid peer name")?;
+        // It is printed and stored in `known_peers`, one line per peer.
+        if peer_name.chars().any(char::is_control) {
+            anyhow::bail!(
+                "Invalid peer name {:?}: contains control characters",
+                peer_name
+            );
+        }
+
+        self.pin(&peer_name, addr, &fingerprint(cert))
+    }
+
+    /// Compare `fp` with the fingerprint pinned for `addr`, pinning it if
+    /// the device is known or the user trusts it. `peer` is the name the
+    /// peer gave, for display.
+    fn pin(&self, peer: &str, addr: IpAddr, fp: &str) -> anyhow::Result<()> {
+        let known = std::fs::read_to_string(&self.known_peers).unwrap_or_default();
+        let pins: Vec<(&str, IpAddr, &str)> = known
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split(' ');
+                let fp = fields.next().filter(|fp| is_fingerprint(fp))?;
+                let addr = fields.next()?.parse().ok()?;
+                Some((fp, addr, fields.next().unwrap_or_default()))
+            })
+            .collect();
+
+        if let Some((pinned, _, name)) = pins.iter().find(|(_, a, _)| *a == addr) {
+            if pinned == &fp {
+                return Ok(());
+            }
+            anyhow::bail!(
+                "Fingerprint of the peer at {} ('{}') has changed!\n  pinned:   {}\n  received: {}\n\
+                 If the peer really changed its identity, remove its line from {}",
+                addr,
+                name,
+                pinned,
+                fp,
+                self.known_peers.display()
+            );
+        }
+
+        if let Some((_, old, _)) = pins.iter().find(|(pinned, _, _)| *pinned == fp) {
+            println!("'{}' at {} is known from {}", peer, addr, old);
+        } else {
+            println!("First connection to '{}' at {}", peer, addr);
+            println!("Peer fingerprint: {}", fp);
+            if !(self.confirm)() {
+                anyhow::bail!("Peer at {} is not trusted", addr);
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.known_peers)
+            .with_context(|| format!("Failed to open {}", self.known_peers.display()))?;
+        writeln!(file, "{} {} {}", fp, addr, peer)?;
+        println!("Pinned in {}", self.known_peers.display());
+        Ok(())
+    }
+}
+
+/// Whether `s` is formatted like [`fingerprint`] output.
+fn is_fingerprint(s: &str) -> bool {
+    s.len() == 32 * 3 - 1
+        && s.split(':')
+            .all(|b| b.len() == 2 && b.bytes().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Ask on stdin whether to trust a new peer; no if stdin can't be read.
+fn ask_trust() -> bool {
+    let answer = crate::utils::read_line(
+        "Compare it with the fingerprint shown on the peer. Trust it? [y/N] ",
+    );
+    matches!(
+        answer.as_deref().map(str::to_lowercase).as_deref(),
+        Ok("y" | "yes")
+    )
+}
+
+fn peer_certificate(certs: Option<&[CertificateDer<'_>]>) -> anyhow::Result<Vec<u8>> {
+    certs
+        .and_then(|c| c.first())
+        .map(|c| c.to_vec())
+        .context("Peer did not present a certificate")
+}
+
+/// Write a file only readable by the current user.
+fn write_private(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)
+        .and_then(|mut f| f.write_all(data))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Accepts any certificate, but still checks that the peer owns its key.
+///
+/// Trust is decided afterwards by [`TlsConfig::pin`].
+#[derive(Debug)]
+struct AnyPeer {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AnyPeer {
+    fn new(provider: &CryptoProvider) -> Self {
+        Self {
+            algorithms: provider.signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for AnyPeer {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for AnyPeer {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fs-share-tls-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Connects `sender` to `receiver` and sends one message through.
+    fn connect(sender: TlsConfig, receiver: TlsConfig) -> (anyhow::Result<()>, anyhow::Result<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || -> anyhow::Result<()> {
+            let (stream, _) = listener.accept()?;
+            let mut stream = receiver.receiver_handshake(stream)?;
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf)?;
+            anyhow::ensure!(&buf == b"hello");
+            Ok(())
+        });
+        let sent = (|| -> anyhow::Result<()> {
+            let mut stream = sender.sender_handshake(TcpStream::connect(addr)?)?;
+            stream.write_all(b"hello")?;
+            stream.flush()?;
+            Ok(())
+        })();
+        (sent, handle.join().unwrap())
+    }
+
+    #[test]
+    fn identity_is_generated_once() {
+        let dir = temp_dir("identity");
+        let a = TlsConfig::load(&dir, "a".into()).unwrap();
+        let b = TlsConfig::load(&dir, "a".into()).unwrap();
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Identity in `dir` that trusts new peers if `trust`.
+    fn load(dir: &Path, name: &str, trust: bool) -> TlsConfig {
+        let mut config = TlsConfig::load(dir, name.into()).unwrap();
+        config.confirm = Box::new(move || trust);
+        config
+    }
+
+    #[test]
+    fn first_contact_pins_and_changed_fingerprint_is_refused() {
+        let alice = temp_dir("alice");
+        let bob = temp_dir("bob");
+        let load = |dir: &Path, name: &str| load(dir, name, true);
+
+        let (sent, received) = connect(load(&alice, "alice"), load(&bob, "bob"));
+        sent.unwrap();
+        received.unwrap();
+        let known = std::fs::read_to_string(alice.join(KNOWN_PEERS_FILE)).unwrap();
+        assert_eq!(
+            known,
+            format!("{} 127.0.0.1 bob\n", load(&bob, "bob").fingerprint())
+        );
+
+        // A new name is no new peer: the address is pinned.
+        let (sent, received) = connect(load(&alice, "alice"), load(&bob, "mallory"));
+        sent.unwrap();
+        received.unwrap();
+
+        // Same peers again: pinned fingerprints match.
+        let (sent, received) = connect(load(&alice, "alice"), load(&bob, "bob"));
+        sent.unwrap();
+        received.unwrap();
+
+        // Bob regenerates his identity.
+        std::fs::remove_file(bob.join(CERT_FILE)).unwrap();
+        let (sent, _) = connect(load(&alice, "alice"), load(&bob, "bob"));
+        assert!(format!("{:#}", sent.unwrap_err()).contains("has changed"));
+
+        let _ = std::fs::remove_dir_all(&alice);
+        let _ = std::fs::remove_dir_all(&bob);
+    }
+
+    #[test]
+    fn untrusted_first_contact_is_refused() {
+        let alice = temp_dir("untrusting");
+        let bob = temp_dir("untrusted");
+
+        let (sent, _) = connect(load(&alice, "alice", false), load(&bob, "bob", true));
+        assert!(format!("{:#}", sent.unwrap_err()).contains("not trusted"));
+        assert!(!alice.join(KNOWN_PEERS_FILE).exists());
+
+        let _ = std::fs::remove_dir_all(&alice);
+        let _ = std::fs::remove_dir_all(&bob);
+    }
+
+    #[test]
+    fn peer_names_cannot_forge_pins() {
+        let alice = temp_dir("forged");
+        let bob = temp_dir("forger");
+        let bob_fp = load(&bob, "bob", true).fingerprint();
+
+        // A name that would add a second line to `known_peers`
+        let forged = format!("x\n{} 192.0.2.20 laptop", bob_fp);
+        let (_, received) = connect(load(&bob, &forged, true), load(&alice, "alice", true));
+        assert!(format!("{:#}", received.unwrap_err()).contains("control characters"));
+        assert!(!alice.join(KNOWN_PEERS_FILE).exists());
+
+        // Lines are read as fingerprint, address and name only.
+        let config = load(&alice, "alice", false);
+        std::fs::write(
+            alice.join(KNOWN_PEERS_FILE),
+            format!(
+                "x {} 192.0.2.20 laptop\n{} 192.0.2.7 bob extra\n",
+                bob_fp, bob_fp
+            ),
+        )
+        .unwrap();
+        let addr: IpAddr = "192.0.2.20".parse().unwrap();
+        // Not pinned by the broken line: eve is a first contact.
+        let error = config.pin("eve", addr, &fingerprint(b"eve")).unwrap_err();
+        assert!(error.to_string().contains("not trusted"), "{}", error);
+        config
+            .pin("bob", "192.0.2.7".parse().unwrap(), &bob_fp)
+            .unwrap();
+
+        let _ = std::fs::remove_dir_all(&alice);
+        let _ = std::fs::remove_dir_all(&bob);
+    }
+
+    #[test]
+    fn known_fingerprint_is_trusted_at_a_new_address() {
+        let alice = temp_dir("moving");
+        let config = load(&alice, "alice", false);
+        let fp = fingerprint(b"bob");
+        std::fs::write(
+            alice.join(KNOWN_PEERS_FILE),
+            format!("{} 192.0.2.7 bob\n", fp),
+        )
+        .unwrap();
+
+        let addr: IpAddr = "192.0.2.9".parse().unwrap();
+        config.pin("bob", addr, &fp).unwrap();
+        assert!(config.pin("eve", addr, &fingerprint(b"eve")).is_err());
+        let known = std::fs::read_to_string(alice.join(KNOWN_PEERS_FILE)).unwrap();
+        assert!(known.ends_with(&format!("{} 192.0.2.9 bob\n", fp)));
+
+        let _ = std::fs::remove_dir_all(&alice);
+    }
+}
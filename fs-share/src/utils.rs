@@ -1,42 +1,221 @@
 use std::{
+    collections::HashSet,
     fmt::Debug,
     io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, UdpSocket},
     str::FromStr,
     time::Duration,
 };
 
 use anyhow::Context;
+use fs_share_utils::{
+    broadcast::{MULTICAST_V6, mdns::MDNS_ADDR},
+    collision::CollisionPolicy,
+    manifest::{Decision, Entry, EntryKind, Manifest, human_size},
+    summary::{Direction, Summary},
+};
 use socket2::{Domain, Socket, Type};
 
-pub fn select_ip() -> Option<IpAddr> {
-    select_ip_impl()
+/// Ask which local IP to listen on; port 0, and the interface as scope
+/// id for link-local IPv6 addresses.
+pub fn select_addr() -> Option<SocketAddr> {
+    select_addr_impl()
 }
 
 #[cfg(all(unix, not(target_os = "android")))]
-pub fn select_ip_impl() -> Option<IpAddr> {
-    use fs_share_utils::ip::IterIpAddr;
+pub fn select_addr_impl() -> Option<SocketAddr> {
+    use fs_share_utils::ip::{IterIpAddr, interface_index};
     use std::io::Write;
 
     let mut stdout = std::io::stdout();
     let ips = IterIpAddr::new().unwrap().collect::<Vec<_>>();
 
     writeln!(&mut stdout, "IP Address List").unwrap();
-    for (i, (j, k)) in ips.iter().enumerate() {
-        writeln!(&mut stdout, "{}: {} {}", i + 1, j, k).unwrap();
+    for (i, interface) in ips.iter().enumerate() {
+        writeln!(&mut stdout, "{}: {}", i + 1, interface).unwrap();
     }
     write!(&mut stdout, "Select Ip Addr: ").unwrap();
     stdout.flush().unwrap();
     let index: usize = get_user_input();
     println!("----------------");
-    ips.get(index - 1).map(|(_, ip)| *ip)
+    ips.get(index - 1).map(|interface| match &interface.addr {
+        IpAddr::V6(ip) if ip.is_unicast_link_local() => {
+            let scope_id = interface_index(&interface.name).unwrap_or(0);
+            SocketAddr::V6(SocketAddrV6::new(*ip, 0, 0, scope_id))
+        }
+        ip => SocketAddr::new(*ip, 0),
+    })
 }
 
 #[cfg(any(not(unix), target_os = "android"))]
-pub fn select_ip_impl() -> Option<IpAddr> {
+pub fn select_addr_impl() -> Option<SocketAddr> {
     None
 }
 
+/// IPv6 discovery group on every interface with a link-local address.
+#[cfg(all(unix, not(target_os = "android")))]
+pub fn multicast_addrs(port: u16) -> Vec<SocketAddrV6> {
+    use fs_share_utils::ip::{IterIpAddr, interface_index};
+
+    let Ok(ips) = IterIpAddr::new() else {
+        return Vec::new();
+    };
+    let mut indices: Vec<u32> = ips
+        .filter(|i| i.up && i.multicast)
+        .filter(|i| matches!(i.addr, IpAddr::V6(ip) if ip.is_unicast_link_local()))
+        .filter_map(|i| interface_index(&i.name))
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+        .into_iter()
+        .map(|index| SocketAddrV6::new(MULTICAST_V6, port, 0, index))
+        .collect()
+}
+
+/// IPv6 discovery group on the default interface.
+#[cfg(any(not(unix), target_os = "android"))]
+pub fn multicast_addrs(port: u16) -> Vec<SocketAddrV6> {
+    vec![SocketAddrV6::new(MULTICAST_V6, port, 0, 0)]
+}
+
+/// Where a receiver listening on `listener_addr` announces itself: the
+/// IPv4 broadcast address, or the IPv6 multicast group on the listener's
+/// interface.
+pub fn broadcast_target(listener_addr: SocketAddr, port: u16) -> SocketAddr {
+    match listener_addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), port),
+        SocketAddr::V6(addr) => {
+            SocketAddr::V6(SocketAddrV6::new(MULTICAST_V6, port, 0, addr.scope_id()))
+        }
+    }
+}
+
+/// Print `prompt` and read one trimmed line from stdin.
+///
+/// Fails once stdin is closed.
+pub fn read_line(prompt: &str) -> anyhow::Result<String> {
+    let mut stdout = std::io::stdout();
+    write!(&mut stdout, "{}", prompt)?;
+    stdout.flush()?;
+    let mut input = String::new();
+    let read = std::io::stdin()
+        .read_line(&mut input)
+        .context("Failed to read from stdin")?;
+    if read == 0 {
+        anyhow::bail!("stdin is closed");
+    }
+    Ok(input.trim().to_owned())
+}
+
+/// Ask what to do with a received file that already exists.
+///
+/// Falls back to skipping the file if stdin can't be read.
+pub fn ask_collision(path: &std::path::Path) -> CollisionPolicy {
+    let prompt = format!(
+        "{} already exists. [s]kip, [o]verwrite or [r]ename? ",
+        path.display()
+    );
+    loop {
+        match read_line(&prompt).as_deref() {
+            Ok("s" | "S" | "skip") => return CollisionPolicy::Skip,
+            Ok("o" | "O" | "overwrite") => return CollisionPolicy::Overwrite,
+            Ok("r" | "R" | "rename") => return CollisionPolicy::Rename,
+            Ok(_) => continue,
+            Err(_) => return CollisionPolicy::Skip,
+        }
+    }
+}
+
+/// Most files listed before asking about incoming files
+const MAX_LISTED_FILES: usize = 20;
+
+/// Decide on the files a peer wants to send.
+///
+/// Transfers above `max_size` are rejected; with `yes` the rest are
+/// accepted, otherwise the user is asked. Rejects if stdin can't be read.
+pub fn approve_incoming(manifest: &Manifest, yes: bool, max_size: Option<u64>) -> Decision {
+    let from = match manifest.from.is_empty() {
+        true => "Peer",
+        false => manifest.from.as_str(),
+    };
+    let count = manifest.files().count();
+    let total = manifest.total_size();
+    if let Some(max_size) = max_size
+        && total > max_size
+    {
+        println!(
+            "Rejecting {} files ({}) from {}: more than --max-size {}",
+            count,
+            human_size(total),
+            from,
+            human_size(max_size)
+        );
+        return Decision::RejectAll;
+    }
+    if yes {
+        return Decision::AcceptAll;
+    }
+
+    println!(
+        "{} wants to send {} files ({}):",
+        from,
+        count,
+        human_size(total)
+    );
+    // Directories come with what's inside them, unless that's all there is.
+    let listed: Vec<&Entry> = match manifest
+        .entries
+        .iter()
+        .all(|e| e.kind == EntryKind::Directory)
+    {
+        true => manifest.entries.iter().collect(),
+        false => manifest
+            .entries
+            .iter()
+            .filter(|e| e.kind != EntryKind::Directory)
+            .collect(),
+    };
+    for entry in listed.iter().take(MAX_LISTED_FILES) {
+        println!("  {}", describe(entry));
+    }
+    if listed.len() > MAX_LISTED_FILES {
+        println!("  ... and {} more", listed.len() - MAX_LISTED_FILES);
+    }
+    loop {
+        match read_line("[a]ccept all, [r]eject all or [p]ick files? ").as_deref() {
+            Ok("a" | "A" | "accept") => return Decision::AcceptAll,
+            Ok("p" | "P" | "pick") => break,
+            Ok("r" | "R" | "reject") | Err(_) => return Decision::RejectAll,
+            Ok(_) => continue,
+        }
+    }
+    let mut picked = HashSet::new();
+    for entry in listed {
+        let prompt = format!("Receive {}? [y/n] ", describe(entry));
+        loop {
+            match read_line(&prompt).as_deref() {
+                Ok("y" | "Y" | "yes") => {
+                    picked.insert(entry.path.clone());
+                    break;
+                }
+                Ok("n" | "N" | "no") | Err(_) => break,
+                Ok(_) => continue,
+            }
+        }
+    }
+    Decision::Pick(picked)
+}
+
+/// `a.txt (1.0 KB)`, `d/link (link)` or `d/` for an entry of a manifest
+fn describe(entry: &Entry) -> String {
+    match entry.kind {
+        EntryKind::File => format!("{} ({})", entry.path, human_size(entry.size)),
+        EntryKind::Link => format!("{} (link)", entry.path),
+        EntryKind::Directory => format!("{}/", entry.path),
+    }
+}
+
 fn get_user_input<T: FromStr>() -> T
 where
     <T as FromStr>::Err: Debug,
@@ -129,17 +308,47 @@ pub fn create_tcp_listener(
     Ok((listener_addr, Dummy { inner: listener }))
 }
 
-pub fn receiver_upgrade_stream(mut stream: TcpStream) -> anyhow::Result<TcpStream> {
+/// UDP socket on the mDNS port, shared with the system responder (if any).
+pub fn create_mdns_socket() -> anyhow::Result<UdpSocket> {
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), MDNS_ADDR.port());
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).context("Failed to create socket")?;
+    socket
+        .set_reuse_address(true)
+        .context("Failed to set SO_REUSEADDR")?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Failed to bind UDP socket on {}", addr))?;
+    Ok(socket.into())
+}
+
+/// Header sent by the sender for a plaintext session
+pub const PLAIN_HEADER: &str = "v1.fs-share";
+
+/// Header sent by the sender for a pairing code (SPAKE2) session
+pub const PAKE_HEADER: &str = "v1.fs-spake";
+
+/// Header sent by the sender for a TLS session
+pub const TLS_HEADER: &str = "v1.fs-tls13";
+
+pub fn receiver_upgrade_stream(stream: TcpStream) -> anyhow::Result<TcpStream> {
+    receiver_exchange_header(stream, PLAIN_HEADER)
+}
+
+pub fn sender_upgrade_stream(stream: TcpStream) -> anyhow::Result<TcpStream> {
+    sender_exchange_header(stream, PLAIN_HEADER)
+}
+
+/// Wait for `header` from the sender and acknowledge it.
+pub fn receiver_exchange_header(mut stream: TcpStream, header: &str) -> anyhow::Result<TcpStream> {
     let addr = stream.local_addr()?;
     stream
         .set_read_timeout(Some(Duration::from_millis(300)))
         .with_context(|| format!("Faild to set read timeout on {}", addr))?;
-    if !match_bytes("v1.fs-share", &mut stream)
-        .context("Failed to read protocol header from sender")?
-    {
+    if !match_bytes(header, &mut stream).context("Failed to read protocol header from sender")? {
         anyhow::bail!(
-            "Protocol mismatch from peer {}: expected 'v1.fs-share' header",
-            stream.peer_addr().unwrap_or(addr)
+            "Protocol mismatch from peer {}: expected '{}' header",
+            stream.peer_addr().unwrap_or(addr),
+            header
         );
     }
     stream
@@ -151,10 +360,11 @@ pub fn receiver_upgrade_stream(mut stream: TcpStream) -> anyhow::Result<TcpStrea
     Ok(stream)
 }
 
-pub fn sender_upgrade_stream(mut stream: TcpStream) -> anyhow::Result<TcpStream> {
+/// Send `header` to the receiver and wait for the acknowledgement.
+pub fn sender_exchange_header(mut stream: TcpStream, header: &str) -> anyhow::Result<TcpStream> {
     let addr = stream.local_addr()?;
     stream
-        .write_all(b"v1.fs-share")
+        .write_all(header.as_bytes())
         .context("Failed to send protocol header")?;
     stream.flush().context("Failed to flush stream")?;
     if !match_bytes(":accept:", &mut stream)
@@ -170,6 +380,34 @@ pub fn sender_upgrade_stream(mut stream: TcpStream) -> anyhow::Result<TcpStream>
     Ok(stream)
 }
 
+/// Print every transferred file with its size and BLAKE3 hash.
+pub fn print_summary(summary: &Summary) {
+    if summary.files.is_empty() && summary.skipped.is_empty() {
+        return;
+    }
+    println!("----------------\nTransfer summary (BLAKE3):");
+    for file in &summary.files {
+        let direction = match file.direction {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        };
+        println!(
+            "{:<8} {:>12} {} {}",
+            direction,
+            file.size,
+            file.hash_hex(),
+            file.path.display()
+        );
+    }
+    for file in &summary.skipped {
+        let direction = match file.direction {
+            Direction::Sent => "not sent",
+            Direction::Received => "refused",
+        };
+        println!("{:<8} {}: {}", direction, file.path.display(), file.reason);
+    }
+}
+
 fn match_bytes<B: AsRef<[u8]>, R: Read>(bytes: B, mut reader: R) -> anyhow::Result<bool> {
     let expected = bytes.as_ref();
 